@@ -0,0 +1,502 @@
+//! Cross-party atomic swaps between two HTLCs sharing one payment hash.
+//!
+//! The initiator knows the preimage and funds HTLC A, redeemable by the participant. The
+//! participant funds HTLC B, redeemable by the initiator, with a shorter refund lock. Redeeming B
+//! reveals the preimage on chain, which the participant then uses to redeem A before A's refund
+//! lock runs out. Both HTLCs use the `_with_fee` leaves so every step is a single transaction.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use bitcoin::{Address, Amount, Network, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::ChainBackend;
+use crate::htlc::contract::{RedeemConfig, RefundConfig, HTLC};
+use crate::htlc::hashlock::{PaymentHash, Preimage};
+
+/// blocks the participant gets between the preimage being revealed on B and A becoming
+/// refundable by the initiator
+pub(crate) const MIN_LOCK_GAP: i64 = 12;
+
+/// where one side of the swap gets paid and refunded
+#[derive(Debug, Clone)]
+pub(crate) struct SwapParty {
+    /// receives the other side's HTLC
+    pub redeem_address: Address,
+    /// receives this side's own HTLC back after its refund lock
+    pub refund_address: Address,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum SwapState {
+    Created,
+    /// the initiator's HTLC is funded, confirmed at `height` or, while unconfirmed, after it
+    AFunded {
+        height: u64,
+    },
+    /// the participant's HTLC is funded with a safe refund lock
+    BVerified,
+    /// the initiator redeemed B, revealing the preimage
+    BRedeemed {
+        txid: Txid,
+    },
+    /// the participant learnt the preimage from B's redeem
+    PreimageExtracted,
+    ARedeemed {
+        txid: Txid,
+    },
+}
+
+impl fmt::Display for SwapState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapState::Created => write!(f, "created"),
+            SwapState::AFunded { height } => write!(f, "A funded at height {}", height),
+            SwapState::BVerified => write!(f, "B verified"),
+            SwapState::BRedeemed { txid } => write!(f, "B redeemed by {}", txid),
+            SwapState::PreimageExtracted => write!(f, "preimage extracted"),
+            SwapState::ARedeemed { txid } => write!(f, "A redeemed by {}", txid),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AtomicSwap {
+    /// funded by the initiator, redeemed by the participant
+    pub htlc_a: HTLC,
+    /// funded by the participant, redeemed by the initiator
+    pub htlc_b: HTLC,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount_a: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount_b: Amount,
+    pub state: SwapState,
+}
+
+impl AtomicSwap {
+    /// set up both HTLCs. `initiator_lock` guards A and must exceed `participant_lock` (guarding
+    /// B) by at least `MIN_LOCK_GAP` blocks
+    pub(crate) fn new(
+        payment_hash: PaymentHash,
+        initiator: SwapParty,
+        participant: SwapParty,
+        amounts: (Amount, Amount),
+        initiator_lock: i64,
+        participant_lock: i64,
+    ) -> Result<Self> {
+        if participant_lock <= 0 {
+            return Err(anyhow!("refund locks need to be positive"));
+        }
+        if initiator_lock - participant_lock < MIN_LOCK_GAP {
+            return Err(anyhow!(
+                "initiator lock {} must exceed participant lock {} by at least {} blocks",
+                initiator_lock,
+                participant_lock,
+                MIN_LOCK_GAP
+            ));
+        }
+
+        // B learns the preimage from A's redeem, which only works for 32 byte preimages
+        let mut redeem_config = RedeemConfig::new(payment_hash, None)?;
+        redeem_config.set_size_guard(true);
+        let htlc = |redeem_address: Address, refund_address: Address, refund_lock| HTLC {
+            htlc_funded_utxo: None,
+            redeem_address: Some(redeem_address),
+            redeem_config: Some(redeem_config.clone()),
+            refund_config: Some(RefundConfig {
+                refund_address,
+                refund_lock,
+            }),
+            nums_tweak: None,
+            extra_leaves: Vec::new(),
+        };
+        Ok(AtomicSwap {
+            htlc_a: htlc(
+                participant.redeem_address,
+                initiator.refund_address,
+                initiator_lock,
+            ),
+            htlc_b: htlc(
+                initiator.redeem_address,
+                participant.refund_address,
+                participant_lock,
+            ),
+            amount_a: amounts.0,
+            amount_b: amounts.1,
+            state: SwapState::Created,
+        })
+    }
+
+    pub(crate) fn from_file(path: &PathBuf) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub(crate) fn to_file(&self, path: &PathBuf) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// the address the initiator pays
+    pub(crate) fn address_a(&self, network: Network) -> Result<Address> {
+        self.htlc_a.address_with_fee(network)
+    }
+
+    /// the address the participant pays
+    pub(crate) fn address_b(&self, network: Network) -> Result<Address> {
+        self.htlc_b.address_with_fee(network)
+    }
+
+    /// record the initiator's funding transaction
+    pub(crate) fn fund_a(
+        &mut self,
+        chain: &dyn ChainBackend,
+        funding_tx: &Transaction,
+    ) -> Result<OutPoint> {
+        self.expect_state(&SwapState::Created)?;
+        // A's refund lock counts from its confirmation, an unconfirmed A confirms in the next
+        // block at the earliest. Overestimating it would make the lock gap check of B unsafe
+        let height = match chain.get_confirmation_height(&funding_tx.txid())? {
+            Some(height) => height,
+            None => chain.get_block_height()? + 1,
+        };
+        let outpoint = record_funding(&mut self.htlc_a, funding_tx, self.amount_a)?;
+        self.state = SwapState::AFunded { height };
+        Ok(outpoint)
+    }
+
+    /// check the participant's funding transaction before revealing anything.
+    /// B has to be confirmed, and its refund has to unlock at least `MIN_LOCK_GAP` blocks before
+    /// A's
+    pub(crate) fn verify_b(
+        &mut self,
+        chain: &dyn ChainBackend,
+        funding_tx: &Transaction,
+    ) -> Result<OutPoint> {
+        let SwapState::AFunded { height: a_height } = self.state else {
+            return Err(anyhow!("cannot verify B in state {}", self.state));
+        };
+        // an unconfirmed B could confirm arbitrarily late and refund after A
+        let b_height = chain
+            .get_confirmation_height(&funding_tx.txid())?
+            .ok_or(anyhow!(
+                "B's funding transaction {} is not confirmed",
+                funding_tx.txid()
+            ))?;
+
+        let b_refund_height = b_height as i64 + self.htlc_b_lock()?;
+        let a_refund_height = a_height as i64 + self.htlc_a_lock()?;
+        if a_refund_height - b_refund_height < MIN_LOCK_GAP {
+            return Err(anyhow!(
+                "B refunds at height {} which leaves less than {} blocks before A refunds at {}",
+                b_refund_height,
+                MIN_LOCK_GAP,
+                a_refund_height
+            ));
+        }
+
+        let outpoint = record_funding(&mut self.htlc_b, funding_tx, self.amount_b)?;
+        self.state = SwapState::BVerified;
+        Ok(outpoint)
+    }
+
+    /// the initiator claims B, publishing the preimage
+    pub(crate) fn redeem_b(
+        &mut self,
+        chain: &dyn ChainBackend,
+        preimage: Preimage,
+        fee: Amount,
+    ) -> Result<Txid> {
+        self.expect_state(&SwapState::BVerified)?;
+        set_preimage(&mut self.htlc_b, preimage)?;
+        let txid = chain.broadcast(&self.htlc_b.create_redeem_tx_with_fee(fee)?)?;
+        self.state = SwapState::BRedeemed { txid };
+        Ok(txid)
+    }
+
+    /// the participant looks up B's redeem and takes the preimage from its witness
+    pub(crate) fn extract_preimage(&mut self, chain: &dyn ChainBackend) -> Result<Preimage> {
+        if !matches!(
+            self.state,
+            SwapState::BVerified | SwapState::BRedeemed { .. }
+        ) {
+            return Err(anyhow!(
+                "cannot extract the preimage in state {}",
+                self.state
+            ));
+        }
+        let b_outpoint = self.htlc_b.htlc_funded_utxo.as_ref().unwrap().htlc_outpoint;
+        let spend = chain
+            .find_spend(&b_outpoint)?
+            .ok_or(anyhow!("B at {} is not spent yet", b_outpoint))?;
+        let preimage = self.htlc_b.extract_preimage(&spend)?.ok_or(anyhow!(
+            "B was spent by {} without revealing the preimage",
+            spend.txid()
+        ))?;
+
+        set_preimage(&mut self.htlc_a, preimage)?;
+        self.state = SwapState::PreimageExtracted;
+        Ok(preimage)
+    }
+
+    /// the participant claims A with the extracted preimage
+    pub(crate) fn redeem_a(&mut self, chain: &dyn ChainBackend, fee: Amount) -> Result<Txid> {
+        self.expect_state(&SwapState::PreimageExtracted)?;
+        let txid = chain.broadcast(&self.htlc_a.create_redeem_tx_with_fee(fee)?)?;
+        self.state = SwapState::ARedeemed { txid };
+        Ok(txid)
+    }
+
+    fn expect_state(&self, expected: &SwapState) -> Result<()> {
+        if &self.state != expected {
+            return Err(anyhow!(
+                "expected swap state {}, found {}",
+                expected,
+                self.state
+            ));
+        }
+        Ok(())
+    }
+
+    fn htlc_a_lock(&self) -> Result<i64> {
+        refund_lock(&self.htlc_a)
+    }
+
+    fn htlc_b_lock(&self) -> Result<i64> {
+        refund_lock(&self.htlc_b)
+    }
+}
+
+fn refund_lock(htlc: &HTLC) -> Result<i64> {
+    Ok(htlc
+        .refund_config
+        .as_ref()
+        .ok_or(anyhow!("the HTLC has no refund config"))?
+        .refund_lock)
+}
+
+fn set_preimage(htlc: &mut HTLC, preimage: Preimage) -> Result<()> {
+    let redeem_config = htlc
+        .redeem_config
+        .as_mut()
+        .ok_or(anyhow!("the HTLC has no redeem config"))?;
+    Ok(redeem_config.set_preimage(preimage)?)
+}
+
+/// record `funding_tx` as funding `htlc` with at least `amount`
+fn record_funding(htlc: &mut HTLC, funding_tx: &Transaction, amount: Amount) -> Result<OutPoint> {
+    let outpoint = htlc.record_funding(funding_tx)?;
+    let funded = htlc.htlc_funded_utxo.as_ref().unwrap().amount;
+    if funded < amount {
+        htlc.htlc_funded_utxo = None;
+        return Err(anyhow!("HTLC funded with {}, expected {}", funded, amount));
+    }
+    Ok(outpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        address, new_chain, pay_to, pay_to_mempool, FEE, HTLC_AMOUNT, PAYMENT_HASH, PREIMAGE,
+    };
+
+    const INITIATOR_LOCK: i64 = 48;
+    const PARTICIPANT_LOCK: i64 = 24;
+
+    fn swap() -> AtomicSwap {
+        AtomicSwap::new(
+            PAYMENT_HASH.parse().unwrap(),
+            SwapParty {
+                redeem_address: address(2),
+                refund_address: address(3),
+            },
+            SwapParty {
+                redeem_address: address(4),
+                refund_address: address(5),
+            },
+            (HTLC_AMOUNT, HTLC_AMOUNT),
+            INITIATOR_LOCK,
+            PARTICIPANT_LOCK,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn swap_completes() {
+        let chain = new_chain();
+        let mut swap = swap();
+
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.fund_a(&chain, &funding_a).unwrap();
+        let funding_b = pay_to(
+            &chain,
+            swap.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.verify_b(&chain, &funding_b).unwrap();
+
+        swap.redeem_b(&chain, PREIMAGE.parse().unwrap(), FEE).unwrap();
+        chain.mine_blocks(1);
+        assert_eq!(swap.extract_preimage(&chain).unwrap().to_string(), PREIMAGE);
+        let txid = swap.redeem_a(&chain, FEE).unwrap();
+
+        let output = chain.get_utxo(&OutPoint::new(txid, 0)).unwrap();
+        assert_eq!(output.script_pubkey, address(4).script_pubkey());
+        assert_eq!(swap.state, SwapState::ARedeemed { txid });
+    }
+
+    #[test]
+    fn swap_resumes_from_its_file() {
+        let chain = new_chain();
+        let mut swap = swap();
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.fund_a(&chain, &funding_a).unwrap();
+
+        let path = std::env::temp_dir().join(format!("cat-htlc-swap-{}.json", std::process::id()));
+        swap.to_file(&path).unwrap();
+        let mut loaded = AtomicSwap::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.state, swap.state);
+        assert_eq!(
+            loaded.address_b(Network::Regtest).unwrap(),
+            swap.address_b(Network::Regtest).unwrap()
+        );
+
+        let funding_b = pay_to(
+            &chain,
+            loaded.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        loaded.verify_b(&chain, &funding_b).unwrap();
+        loaded
+            .redeem_b(&chain, PREIMAGE.parse().unwrap(), FEE)
+            .unwrap();
+    }
+
+    #[test]
+    fn lock_gap_too_small_is_rejected() {
+        let party = || SwapParty {
+            redeem_address: address(2),
+            refund_address: address(3),
+        };
+        let err = AtomicSwap::new(
+            PAYMENT_HASH.parse().unwrap(),
+            party(),
+            party(),
+            (HTLC_AMOUNT, HTLC_AMOUNT),
+            PARTICIPANT_LOCK + MIN_LOCK_GAP - 1,
+            PARTICIPANT_LOCK,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("at least"), "{}", err);
+    }
+
+    #[test]
+    fn late_funding_of_b_is_rejected() {
+        let chain = new_chain();
+        let mut swap = swap();
+
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.fund_a(&chain, &funding_a).unwrap();
+        chain.mine_blocks((INITIATOR_LOCK - PARTICIPANT_LOCK - MIN_LOCK_GAP) as u64);
+        let funding_b = pay_to(
+            &chain,
+            swap.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        let err = swap.verify_b(&chain, &funding_b).unwrap_err();
+        assert!(err.to_string().contains("less than"), "{}", err);
+        assert!(swap.htlc_b.htlc_funded_utxo.is_none());
+    }
+
+    #[test]
+    fn unconfirmed_b_is_rejected() {
+        let chain = new_chain();
+        let mut swap = swap();
+
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.fund_a(&chain, &funding_a).unwrap();
+        let funding_b = pay_to_mempool(
+            &chain,
+            swap.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        let err = swap.verify_b(&chain, &funding_b).unwrap_err();
+        assert!(err.to_string().contains("not confirmed"), "{}", err);
+        assert!(swap.htlc_b.htlc_funded_utxo.is_none());
+
+        chain.mine_blocks(1);
+        swap.verify_b(&chain, &funding_b).unwrap();
+        assert_eq!(swap.state, SwapState::BVerified);
+    }
+
+    #[test]
+    fn underfunded_b_is_rejected() {
+        let chain = new_chain();
+        let mut swap = swap();
+
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        swap.fund_a(&chain, &funding_a).unwrap();
+        let funding_b = pay_to(
+            &chain,
+            swap.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT - FEE,
+        );
+        assert!(swap.verify_b(&chain, &funding_b).is_err());
+        assert_eq!(swap.state, SwapState::AFunded { height: 102 });
+    }
+
+    #[test]
+    fn a_recorded_late_keeps_its_confirmation_height() {
+        let chain = new_chain();
+        let mut swap = swap();
+
+        let funding_a = pay_to(
+            &chain,
+            swap.address_a(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        let confirmed_at = chain.get_confirmation_height(&funding_a.txid()).unwrap();
+        // the initiator only reports A once B could no longer be funded safely
+        chain.mine_blocks((INITIATOR_LOCK - PARTICIPANT_LOCK - MIN_LOCK_GAP) as u64);
+        swap.fund_a(&chain, &funding_a).unwrap();
+        assert_eq!(
+            Some(swap.state.clone()),
+            confirmed_at.map(|height| SwapState::AFunded { height })
+        );
+
+        let funding_b = pay_to(
+            &chain,
+            swap.address_b(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        let err = swap.verify_b(&chain, &funding_b).unwrap_err();
+        assert!(err.to_string().contains("less than"), "{}", err);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+use bitcoin::{Amount, FeeRate, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::jsonrpc::serde_json::{json, Value};
+use bitcoincore_rpc::{Client, RawTx, RpcApi};
+use log::debug;
+
+use crate::chain::ChainBackend;
+
+/// how many blocks back from the tip `find_spend` searches when the outpoint is not in the mempool
+const SPEND_SCAN_DEPTH: u64 = 1000;
+
+/// fee rate used when bitcoind has no estimate yet (e.g. a fresh regtest chain)
+const FALLBACK_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+#[derive(Debug)]
+pub(crate) enum BitcoindError {
+    /// bitcoind doesn't have the transaction in its mempool, and without `-txindex` it can't look
+    /// up confirmed ones
+    TxindexRequired(Txid),
+    /// the outpoint is spent, but not in the mempool or the blocks `find_spend` searches
+    SpendNotFound { outpoint: OutPoint, depth: u64 },
+}
+
+impl fmt::Display for BitcoindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitcoindError::TxindexRequired(txid) => write!(
+                f,
+                "transaction {} is not in the mempool, restart bitcoind with -txindex to look up confirmed ones",
+                txid
+            ),
+            BitcoindError::SpendNotFound { outpoint, depth } => write!(
+                f,
+                "{} is spent, but not in the mempool or the last {} blocks",
+                outpoint, depth
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BitcoindError {}
+
+/// RPC_INVALID_ADDRESS_OR_KEY: no such mempool or blockchain transaction
+fn is_not_found(e: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        e,
+        bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)) if e.code == -5
+    )
+}
+
+/// what a lookup of `txid` that bitcoind doesn't know means: the transaction doesn't exist when
+/// bitcoind indexes every transaction, otherwise it may just be confirmed
+fn unknown_tx<T>(client: &Client, txid: &Txid) -> Result<Option<T>> {
+    let indexes: HashMap<String, Value> = client.call("getindexinfo", &[json!("txindex")])?;
+    if indexes.contains_key("txindex") {
+        Ok(None)
+    } else {
+        Err(BitcoindError::TxindexRequired(*txid).into())
+    }
+}
+
+impl ChainBackend for Client {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let txid = self.call("sendrawtransaction", &[json!(tx.raw_hex())])?;
+        Ok(txid)
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        match self.get_raw_transaction(txid, None) {
+            Ok(tx) => Ok(Some(tx)),
+            Err(e) if is_not_found(&e) => unknown_tx(self, txid),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_block_height(&self) -> Result<u64> {
+        Ok(self.get_block_count()?)
+    }
+
+    fn get_confirmation_height(&self, txid: &Txid) -> Result<Option<u64>> {
+        let blockhash = match self.get_raw_transaction_info(txid, None) {
+            Ok(info) => info.blockhash,
+            Err(e) if is_not_found(&e) => return unknown_tx(self, txid),
+            Err(e) => return Err(e.into()),
+        };
+        match blockhash {
+            Some(blockhash) => Ok(Some(self.get_block_header_info(&blockhash)?.height as u64)),
+            None => Ok(None),
+        }
+    }
+
+    fn find_spend(&self, outpoint: &OutPoint) -> Result<Option<Transaction>> {
+        // bitcoind has no spend index, so search the mempool and then the most recent blocks
+        let spends = |tx: &Transaction| tx.input.iter().any(|i| i.previous_output == *outpoint);
+
+        for txid in self.get_raw_mempool()? {
+            if let Some(tx) = self.get_tx(&txid)?.filter(|tx| spends(tx)) {
+                return Ok(Some(tx));
+            }
+        }
+
+        // still in the utxo set (or created in the mempool) and not spent by the mempool
+        if self
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .is_some()
+        {
+            return Ok(None);
+        }
+
+        let tip = self.get_block_count()?;
+        for height in (tip.saturating_sub(SPEND_SCAN_DEPTH)..=tip).rev() {
+            let block = self.get_block(&self.get_block_hash(height)?)?;
+            if let Some(tx) = block.txdata.into_iter().find(|tx| spends(tx)) {
+                debug!("found spend of {} in block {}", outpoint, height);
+                return Ok(Some(tx));
+            }
+        }
+        if tip > SPEND_SCAN_DEPTH {
+            return Err(BitcoindError::SpendNotFound {
+                outpoint: *outpoint,
+                depth: SPEND_SCAN_DEPTH,
+            }
+            .into());
+        }
+        // the whole chain was searched, the outpoint never existed
+        Ok(None)
+    }
+
+    fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate> {
+        let estimate = self.estimate_smart_fee(target_blocks, None)?;
+        // estimatesmartfee reports BTC/kvB, round up to sat/kwu like esplora so we never underpay
+        let fee_rate = estimate
+            .fee_rate
+            .map(|per_kvb: Amount| FeeRate::from_sat_per_kwu(per_kvb.to_sat().div_ceil(4)))
+            .unwrap_or(FALLBACK_FEE_RATE);
+        Ok(fee_rate)
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::{FeeRate, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::chain::ChainBackend;
+
+/// Esplora-style REST API (blockstream.info, mempool.space or a self hosted electrs)
+pub(crate) struct EsploraBackend {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+#[derive(Deserialize)]
+struct TxStatus {
+    confirmed: bool,
+    block_height: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct OutSpend {
+    spent: bool,
+    txid: Option<Txid>,
+}
+
+impl EsploraBackend {
+    pub(crate) fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<Option<String>> {
+        match self.agent.get(&format!("{}{}", self.base_url, path)).call() {
+            Ok(response) => Ok(Some(response.into_string()?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(code, response)) => Err(anyhow!(
+                "esplora GET {} failed with {}: {}",
+                path,
+                code,
+                response.into_string().unwrap_or_default()
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_required(&self, path: &str) -> Result<String> {
+        self.get(path)?
+            .ok_or(anyhow!("esplora GET {} returned not found", path))
+    }
+}
+
+impl ChainBackend for EsploraBackend {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let url = format!("{}/tx", self.base_url);
+        match self.agent.post(&url).send_string(&serialize_hex(tx)) {
+            Ok(response) => Ok(Txid::from_str(response.into_string()?.trim())?),
+            Err(ureq::Error::Status(code, response)) => Err(anyhow!(
+                "esplora rejected transaction ({}): {}",
+                code,
+                response.into_string().unwrap_or_default()
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        match self.get(&format!("/tx/{}/hex", txid))? {
+            Some(raw) => Ok(Some(deserialize(&hex::decode(raw.trim())?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_height(&self) -> Result<u64> {
+        Ok(self.get_required("/blocks/tip/height")?.trim().parse()?)
+    }
+
+    fn get_confirmation_height(&self, txid: &Txid) -> Result<Option<u64>> {
+        let status: TxStatus = match self.get(&format!("/tx/{}/status", txid))? {
+            Some(status) => serde_json::from_str(&status)?,
+            None => return Ok(None),
+        };
+        Ok(status.block_height.filter(|_| status.confirmed))
+    }
+
+    fn find_spend(&self, outpoint: &OutPoint) -> Result<Option<Transaction>> {
+        let outspend: OutSpend = serde_json::from_str(
+            &self.get_required(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))?,
+        )?;
+        match (outspend.spent, outspend.txid) {
+            (true, Some(txid)) => self.get_tx(&txid),
+            _ => Ok(None),
+        }
+    }
+
+    fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate> {
+        // map of confirmation target to sat/vB
+        let estimates: HashMap<String, f64> =
+            serde_json::from_str(&self.get_required("/fee-estimates")?)?;
+        let sat_per_vb = estimates
+            .iter()
+            .filter_map(|(target, rate)| Some((target.parse::<u16>().ok()?, *rate)))
+            .filter(|(target, _)| *target <= target_blocks.max(1))
+            .max_by_key(|(target, _)| *target)
+            .map(|(_, rate)| rate)
+            .ok_or(anyhow!("esplora has no fee estimate for {} blocks", target_blocks))?;
+        // sat/vB to sat/kwu, rounding up so we never underpay
+        Ok(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64))
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::{anyhow, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Amount, FeeRate, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::chain::ChainBackend;
+use crate::interpreter;
+
+/// In-memory chain for tests.
+///
+/// Tracks the UTXO set and block height, enforces BIP68 relative and absolute lock times against
+/// the next block, and validates every taproot input with the in-crate OP_CAT interpreter.
+/// Broadcast transactions sit in the mempool until `mine_blocks` confirms them.
+pub(crate) struct MockChain {
+    state: Mutex<MockState>,
+}
+
+struct MockState {
+    height: u64,
+    fee_rate: FeeRate,
+    transactions: HashMap<Txid, Transaction>,
+    /// the height every confirmed transaction confirmed at
+    confirmations: HashMap<Txid, u64>,
+    /// unspent outputs and the height they confirmed at, `None` while in the mempool
+    utxos: HashMap<OutPoint, (TxOut, Option<u64>)>,
+    spends: HashMap<OutPoint, Txid>,
+    mempool: Vec<Txid>,
+}
+
+impl MockChain {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                height: 0,
+                fee_rate: FeeRate::from_sat_per_vb_unchecked(1),
+                transactions: HashMap::new(),
+                confirmations: HashMap::new(),
+                utxos: HashMap::new(),
+                spends: HashMap::new(),
+                mempool: Vec::new(),
+            }),
+        }
+    }
+
+    /// confirm the mempool in the next block and advance the tip by `blocks`
+    pub(crate) fn mine_blocks(&self, blocks: u64) {
+        let mut state = self.state.lock().unwrap();
+        if blocks == 0 {
+            return;
+        }
+        let confirmation_height = state.height + 1;
+        for txid in std::mem::take(&mut state.mempool) {
+            state.confirmations.insert(txid, confirmation_height);
+            for vout in 0..state.transactions[&txid].output.len() as u32 {
+                if let Some((_, height)) = state.utxos.get_mut(&OutPoint::new(txid, vout)) {
+                    *height = Some(confirmation_height);
+                }
+            }
+        }
+        state.height += blocks;
+    }
+
+    pub(crate) fn set_fee_rate(&self, fee_rate: FeeRate) {
+        self.state.lock().unwrap().fee_rate = fee_rate;
+    }
+
+    /// mint a confirmed output paying `amount` to `script_pubkey`, like a coinbase that matured
+    pub(crate) fn fund(&self, script_pubkey: ScriptBuf, amount: Amount) -> OutPoint {
+        let mut state = self.state.lock().unwrap();
+        // the height in the lock time keeps every funding transaction unique
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::from_consensus(state.transactions.len() as u32),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: amount,
+                script_pubkey,
+            }],
+        };
+        let txid = tx.txid();
+        let outpoint = OutPoint::new(txid, 0);
+        let height = state.height;
+        state
+            .utxos
+            .insert(outpoint, (tx.output[0].clone(), Some(height)));
+        state.confirmations.insert(txid, height);
+        state.transactions.insert(txid, tx);
+        outpoint
+    }
+
+    /// the unspent output at `outpoint`, if any
+    pub(crate) fn get_utxo(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        let state = self.state.lock().unwrap();
+        state.utxos.get(outpoint).map(|(txout, _)| txout.clone())
+    }
+
+    /// run every consensus check `broadcast` does, without accepting the transaction
+    pub(crate) fn test_accept(&self, tx: &Transaction) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        state.check(tx).map(|_| ())
+    }
+}
+
+impl MockState {
+    /// validate `tx` against the current UTXO set and return the prevouts it spends
+    fn check(&self, tx: &Transaction) -> Result<Vec<TxOut>> {
+        // the block that would include the transaction
+        let next_height = self.height + 1;
+        let mut prevouts = Vec::with_capacity(tx.input.len());
+        for input in tx.input.iter() {
+            let (prevout, confirmed_at) = match self.utxos.get(&input.previous_output) {
+                Some(utxo) => utxo,
+                None => match self.spends.get(&input.previous_output) {
+                    Some(spender) => {
+                        return Err(anyhow!(
+                            "{} is already spent by {}",
+                            input.previous_output,
+                            spender
+                        ))
+                    }
+                    None => return Err(anyhow!("{} does not exist", input.previous_output)),
+                },
+            };
+
+            // BIP68 relative lock, measured from the block the prevout confirmed in
+            if let (true, Some(blocks)) = (tx.version.0 >= 2, input.sequence.to_relative_lock_time())
+            {
+                let blocks = match blocks {
+                    bitcoin::relative::LockTime::Blocks(height) => height.value() as u64,
+                    bitcoin::relative::LockTime::Time(_) => {
+                        return Err(anyhow!("time based relative locks are not supported"))
+                    }
+                };
+                let mature = match confirmed_at {
+                    Some(height) => height + blocks <= next_height,
+                    None => blocks == 0,
+                };
+                if !mature {
+                    return Err(anyhow!(
+                        "non-BIP68-final: {} needs {} confirmations",
+                        input.previous_output,
+                        blocks
+                    ));
+                }
+            }
+            prevouts.push(prevout.clone());
+        }
+
+        // consensus requires the lock time to be below the height of the block including the tx
+        let final_lock_time = match tx.lock_time {
+            LockTime::Blocks(height) => height.to_consensus_u32() as u64 <= self.height,
+            LockTime::Seconds(_) => false,
+        };
+        if tx.is_lock_time_enabled() && !final_lock_time {
+            return Err(anyhow!("non-final: lock time {} not reached", tx.lock_time));
+        }
+
+        let input_value: Amount = prevouts.iter().map(|p| p.value).sum();
+        let output_value: Amount = tx.output.iter().map(|o| o.value).sum();
+        if output_value > input_value {
+            return Err(anyhow!(
+                "outputs ({}) exceed inputs ({})",
+                output_value,
+                input_value
+            ));
+        }
+
+        for index in 0..tx.input.len() {
+            interpreter::verify_input(tx, index, &prevouts)
+                .map_err(|e| anyhow!("input {} failed script validation: {}", index, e))?;
+        }
+        Ok(prevouts)
+    }
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChainBackend for MockChain {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let mut state = self.state.lock().unwrap();
+        state.check(tx)?;
+        let txid = tx.txid();
+        for input in tx.input.iter() {
+            state.utxos.remove(&input.previous_output);
+            state.spends.insert(input.previous_output, txid);
+        }
+        for (vout, output) in tx.output.iter().enumerate() {
+            state
+                .utxos
+                .insert(OutPoint::new(txid, vout as u32), (output.clone(), None));
+        }
+        state.transactions.insert(txid, tx.clone());
+        state.mempool.push(txid);
+        Ok(txid)
+    }
+
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        Ok(self.state.lock().unwrap().transactions.get(txid).cloned())
+    }
+
+    fn get_block_height(&self) -> Result<u64> {
+        Ok(self.state.lock().unwrap().height)
+    }
+
+    fn get_confirmation_height(&self, txid: &Txid) -> Result<Option<u64>> {
+        Ok(self.state.lock().unwrap().confirmations.get(txid).copied())
+    }
+
+    fn find_spend(&self, outpoint: &OutPoint) -> Result<Option<Transaction>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .spends
+            .get(outpoint)
+            .and_then(|txid| state.transactions.get(txid))
+            .cloned())
+    }
+
+    fn estimate_fee_rate(&self, _target_blocks: u16) -> Result<FeeRate> {
+        Ok(self.state.lock().unwrap().fee_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use crate::testing::{address, deposit, htlc, new_chain, FEE, HTLC_AMOUNT, PREIMAGE, REFUND_LOCK};
+
+    #[test]
+    fn redeem_with_fee_pays_the_redeem_address() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        let txid = chain.broadcast(&redeem_tx).unwrap();
+        chain.mine_blocks(1);
+
+        let output = chain.get_utxo(&OutPoint::new(txid, 0)).unwrap();
+        assert_eq!(output.script_pubkey, address(2).script_pubkey());
+        assert_eq!(output.value, HTLC_AMOUNT - FEE);
+    }
+
+    #[test]
+    fn refund_with_fee_only_after_the_lock() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let refund_tx = htlc.create_refund_tx_with_fee(FEE).unwrap();
+        let err = chain.broadcast(&refund_tx).unwrap_err();
+        assert!(err.to_string().contains("non-BIP68-final"), "{}", err);
+
+        chain.mine_blocks(REFUND_LOCK as u64 - 2);
+        assert!(chain.test_accept(&refund_tx).is_err());
+        chain.mine_blocks(1);
+        let txid = chain.broadcast(&refund_tx).unwrap();
+
+        let output = chain.get_utxo(&OutPoint::new(txid, 0)).unwrap();
+        assert_eq!(output.script_pubkey, address(3).script_pubkey());
+        assert_eq!(output.value, HTLC_AMOUNT - FEE);
+    }
+
+    #[test]
+    fn redeem_with_wrong_preimage_is_rejected() {
+        let chain = new_chain();
+        let mut htlc = htlc(&"00".repeat(32));
+        deposit(&chain, &mut htlc);
+
+        let redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        let err = chain.broadcast(&redeem_tx).unwrap_err();
+        assert!(err.to_string().contains("OP_EQUALVERIFY failed"), "{}", err);
+    }
+
+    #[test]
+    fn covenant_rejects_a_redirected_output() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let mut redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        redeem_tx.output[0].script_pubkey = address(4).script_pubkey();
+        let err = chain.broadcast(&redeem_tx).unwrap_err();
+        assert!(err.to_string().contains("signature verification failed"), "{}", err);
+    }
+
+    #[test]
+    fn plain_refund_only_after_the_lock() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        let script_pubkey = htlc.address(Network::Regtest).unwrap().script_pubkey();
+        let outpoint = chain.fund(script_pubkey, HTLC_AMOUNT);
+        htlc.set_funded_htlc(outpoint, HTLC_AMOUNT);
+
+        let refund_tx = htlc.create_refund_tx().unwrap();
+        assert!(chain.test_accept(&refund_tx).is_err());
+        chain.mine_blocks(REFUND_LOCK as u64);
+        chain.broadcast(&refund_tx).unwrap();
+    }
+
+    #[test]
+    fn fee_rate_is_the_one_set() {
+        let chain = new_chain();
+        let fee_rate = FeeRate::from_sat_per_vb(25).unwrap();
+        chain.set_fee_rate(fee_rate);
+        assert_eq!(chain.estimate_fee_rate(6).unwrap(), fee_rate);
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use bitcoin::{FeeRate, OutPoint, Transaction, Txid};
+
+use crate::settings::Settings;
+use crate::wallet::{Wallet, WalletError};
+
+pub(crate) mod bitcoind;
+pub(crate) mod esplora;
+#[cfg(test)]
+pub(crate) mod mock;
+
+/// The chain queries contract logic needs, independent of the node we talk to.
+pub(crate) trait ChainBackend {
+    /// broadcast a fully signed transaction and return its txid
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+
+    /// look up a transaction by txid, `None` if the backend does not know it
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>>;
+
+    /// height of the current chain tip
+    fn get_block_height(&self) -> Result<u64>;
+
+    /// height of the block confirming `txid`, `None` while unconfirmed or unknown
+    fn get_confirmation_height(&self, txid: &Txid) -> Result<Option<u64>>;
+
+    /// find the transaction spending `outpoint`, `None` while it is unspent
+    fn find_spend(&self, outpoint: &OutPoint) -> Result<Option<Transaction>>;
+
+    /// fee rate expected to confirm within `target_blocks`
+    fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate>;
+}
+
+/// build the backend configured in `settings`: esplora when `esplora_url` is set, bitcoind otherwise
+pub(crate) fn backend_from_settings(
+    settings: &Settings,
+) -> Result<Box<dyn ChainBackend>, WalletError> {
+    match &settings.esplora_url {
+        Some(url) => Ok(Box::new(esplora::EsploraBackend::new(url))),
+        None => Ok(Box::new(Wallet::create_rpc_client(settings, None)?)),
+    }
+}
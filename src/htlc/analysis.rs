@@ -0,0 +1,273 @@
+//! Sizes of every leaf of an HTLC, to budget fees and compare covenant designs.
+//!
+//! The numbers come from real spends of a copy of the contract, ground and with their witnesses
+//! built like the contract builds them, so they can't drift from the scripts. Signatures the
+//! crate doesn't produce, the fee input's and those of the extra leaves, are stood in for by
+//! 64 byte placeholders. With SINGLE|ANYONECANPAY leaves the spend also has a P2TR fee input and
+//! change output. Preimages are 32 bytes under the size guard; without it the redeem leaf takes
+//! any preimage up to the 520 byte element limit, and its figures are those of the largest.
+
+use anyhow::{Result, anyhow};
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash;
+use bitcoin::taproot::Signature;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use serde::Serialize;
+
+use crate::htlc::contract::{HTLC, HtlcFunded, add_fee_to_txn};
+use crate::htlc::descriptor::{FeeMode, HtlcDescriptor};
+use crate::htlc::hashlock::Preimage;
+use crate::htlc::leaves::ExtraLeaf;
+use crate::interpreter::MAX_SCRIPT_ELEMENT_SIZE;
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LeafAnalysis {
+    pub leaf: &'static str,
+    pub fee: FeeMode,
+    pub script_size: usize,
+    pub control_block_size: usize,
+    /// witness elements before the script and the control block
+    pub stack_items: usize,
+    /// the largest of those elements
+    pub max_element_size: usize,
+    /// the size of each preimage the figures assume, 0 for leaves without one
+    pub preimage_size: usize,
+    /// the serialized witness of the leaf's input, in weight units
+    pub witness_weight: usize,
+    /// the whole spending transaction
+    pub spend_vbytes: usize,
+}
+
+impl LeafAnalysis {
+    fn new(leaf: &'static str, fee: FeeMode, tx: &Transaction) -> Self {
+        let witness = &tx.input[0].witness;
+        let stack: Vec<&[u8]> = witness.iter().collect();
+        let (elements, script_and_control_block) = stack.split_at(stack.len() - 2);
+        LeafAnalysis {
+            leaf,
+            fee,
+            script_size: script_and_control_block[0].len(),
+            control_block_size: script_and_control_block[1].len(),
+            stack_items: elements.len(),
+            max_element_size: elements
+                .iter()
+                .map(|element| element.len())
+                .max()
+                .unwrap_or(0),
+            preimage_size: 0,
+            witness_weight: witness.size(),
+            spend_vbytes: tx.vsize(),
+        }
+    }
+
+    /// a redeem built with `count` 32 byte preimages, grown to preimages of `size` bytes
+    fn with_preimages(mut self, count: usize, size: usize) -> Self {
+        let push_size = |len: usize| bitcoin::VarInt(len as u64).size() + len;
+        let extra = count * (push_size(size) - push_size(32));
+        self.max_element_size = self.max_element_size.max(size);
+        self.preimage_size = size;
+        self.witness_weight += extra;
+        self.spend_vbytes = (self.spend_vbytes * 4 + extra).div_ceil(4);
+        self
+    }
+}
+
+/// every leaf of `htlc` in both trees, spending `amount` unless the HTLC is funded
+pub(crate) fn analyze(htlc: &HTLC, amount: Amount, fee: Amount) -> Result<Vec<LeafAnalysis>> {
+    let mut analyses = Vec::new();
+    for fee_mode in [FeeMode::Input, FeeMode::Stack] {
+        let mut copy = HtlcDescriptor::from_htlc(htlc, fee_mode)?.to_htlc();
+        copy.htlc_funded_utxo = Some(HtlcFunded {
+            htlc_outpoint: htlc
+                .htlc_funded_utxo
+                .as_ref()
+                .map(|funded| funded.htlc_outpoint)
+                .unwrap_or(OutPoint::new(Txid::all_zeros(), 0)),
+            amount: htlc
+                .htlc_funded_utxo
+                .as_ref()
+                .map(|funded| funded.amount)
+                .unwrap_or(amount),
+        });
+        // only the size of the preimages matters
+        let redeem_config = copy
+            .redeem_config
+            .as_ref()
+            .ok_or(anyhow!("the HTLC has no redeem leaf"))?;
+        let hashlocks = redeem_config.hashlocks().len();
+        let preimage_size = if redeem_config.size_guard() {
+            32
+        } else {
+            MAX_SCRIPT_ELEMENT_SIZE
+        };
+        let preimages = vec![Preimage::from_byte_array([0; 32]); hashlocks];
+
+        let (redeem, refund) = match fee_mode {
+            FeeMode::Input => {
+                let mut redeem = copy.create_redeem_tx_single_anyonecanpay(&preimages)?;
+                let mut refund = copy.create_refund_tx()?;
+                add_fee_input(&mut redeem, &copy, fee)?;
+                add_fee_input(&mut refund, &copy, fee)?;
+                (redeem, refund)
+            }
+            FeeMode::Stack => (
+                copy.create_redeem_tx_with_preimages(fee, &preimages)?,
+                copy.create_refund_tx_with_fee(fee)?,
+            ),
+        };
+        analyses.push(
+            LeafAnalysis::new("redeem", fee_mode, &redeem).with_preimages(hashlocks, preimage_size),
+        );
+        analyses.push(LeafAnalysis::new("refund", fee_mode, &refund));
+
+        for leaf in copy.extra_leaves.iter() {
+            let tx = extra_leaf_spend(&copy, leaf, fee_mode, fee)?;
+            let name = match leaf {
+                ExtraLeaf::Arbiter { .. } => "arbiter",
+                ExtraLeaf::Recovery { .. } => "recovery",
+            };
+            analyses.push(LeafAnalysis::new(name, fee_mode, &tx));
+        }
+    }
+    Ok(analyses)
+}
+
+fn placeholder_signature() -> Signature {
+    Signature::from_slice(&[1; 64]).expect("64 bytes are a signature")
+}
+
+/// the fee input and change output, shaped like the HTLC's own output
+fn add_fee_input(tx: &mut Transaction, htlc: &HTLC, fee: Amount) -> Result<()> {
+    // the network doesn't change the script pubkey
+    let address = htlc.address(Network::Regtest)?;
+    add_fee_to_txn(tx, OutPoint::null(), fee * 2, fee, address)?;
+    let fee_input = tx.input.last_mut().expect("the fee input was just added");
+    fee_input.witness = Witness::from_slice(&[placeholder_signature().to_vec()]);
+    Ok(())
+}
+
+fn extra_leaf_spend(
+    htlc: &HTLC,
+    leaf: &ExtraLeaf,
+    fee_mode: FeeMode,
+    fee: Amount,
+) -> Result<Transaction> {
+    let funded = htlc.htlc_funded_utxo.as_ref().expect("the copy is funded");
+    let spend_info = match fee_mode {
+        FeeMode::Input => htlc.taproot_spend_info()?,
+        FeeMode::Stack => htlc.taproot_spend_info_with_fee()?,
+    };
+    let (address, signatures, sequence): (&Address, Vec<_>, _) = match leaf {
+        ExtraLeaf::Arbiter { .. } => (
+            htlc.redeem_address
+                .as_ref()
+                .expect("descriptors have a redeem address"),
+            vec![
+                Some(placeholder_signature()),
+                Some(placeholder_signature()),
+                None,
+            ],
+            Sequence::MAX,
+        ),
+        ExtraLeaf::Recovery { lock, .. } => (
+            &htlc
+                .refund_config
+                .as_ref()
+                .expect("descriptors have a refund address")
+                .refund_address,
+            vec![Some(placeholder_signature())],
+            Sequence::from_height(*lock),
+        ),
+    };
+    Ok(Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: funded.htlc_outpoint,
+            sequence,
+            witness: leaf.witness(&spend_info, &signatures)?,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: funded.amount - fee,
+            script_pubkey: address.script_pubkey(),
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FEE, HTLC_AMOUNT, PREIMAGE, REFUND_LOCK, htlc, keypair};
+
+    #[test]
+    fn both_trees_are_analyzed() {
+        let analyses = analyze(&htlc(PREIMAGE), HTLC_AMOUNT, FEE).unwrap();
+        let leaves: Vec<_> = analyses.iter().map(|a| (a.leaf, a.fee)).collect();
+        assert_eq!(
+            leaves,
+            vec![
+                ("redeem", FeeMode::Input),
+                ("refund", FeeMode::Input),
+                ("redeem", FeeMode::Stack),
+                ("refund", FeeMode::Stack),
+            ]
+        );
+        for analysis in analyses.iter() {
+            // two leaves, one sibling hash
+            assert_eq!(analysis.control_block_size, 65);
+            assert!(analysis.witness_weight > analysis.script_size);
+            assert!(analysis.spend_vbytes * 4 > analysis.witness_weight);
+        }
+        // the fee input and change output weigh more than a fee on the stack
+        assert!(analyses[0].spend_vbytes > analyses[2].spend_vbytes);
+    }
+
+    #[test]
+    fn extra_leaves_sit_deeper() {
+        let mut htlc = htlc(PREIMAGE);
+        let keys = [5, 6, 7].map(|secret| keypair(secret).x_only_public_key().0);
+        htlc.extra_leaves = vec![
+            ExtraLeaf::Arbiter { keys },
+            ExtraLeaf::Recovery {
+                cold_key: keypair(8).x_only_public_key().0,
+                lock: REFUND_LOCK as u16 * 2,
+            },
+        ];
+        let analyses = analyze(&htlc, HTLC_AMOUNT, FEE).unwrap();
+        assert_eq!(analyses.len(), 8);
+        let redeem = &analyses[0];
+        let recovery = &analyses[3];
+        assert_eq!(recovery.leaf, "recovery");
+        assert!(recovery.control_block_size > redeem.control_block_size);
+        let arbiter = &analyses[2];
+        assert_eq!(arbiter.leaf, "arbiter");
+        // two signatures and an empty element for the missing one
+        assert_eq!(arbiter.stack_items, 3);
+        assert_eq!(arbiter.max_element_size, 64);
+    }
+
+    #[test]
+    fn unguarded_preimages_are_sized_at_the_element_limit() {
+        let mut htlc = htlc(PREIMAGE);
+        let unguarded = analyze(&htlc, HTLC_AMOUNT, FEE).unwrap();
+        htlc.redeem_config.as_mut().unwrap().set_size_guard(true);
+        let guarded = analyze(&htlc, HTLC_AMOUNT, FEE).unwrap();
+
+        for (unguarded, guarded) in unguarded.iter().zip(guarded.iter()) {
+            assert_eq!(unguarded.leaf, guarded.leaf);
+            if unguarded.leaf != "redeem" {
+                assert_eq!(unguarded.preimage_size, 0);
+                continue;
+            }
+            assert_eq!(guarded.preimage_size, 32);
+            assert_eq!(unguarded.preimage_size, MAX_SCRIPT_ELEMENT_SIZE);
+            assert_eq!(unguarded.max_element_size, MAX_SCRIPT_ELEMENT_SIZE);
+            assert!(unguarded.witness_weight > guarded.witness_weight + 480);
+            assert!(unguarded.spend_vbytes > guarded.spend_vbytes + 100);
+        }
+    }
+}
@@ -1,48 +1,227 @@
 use anyhow::{anyhow, Result};
 use bitcoin::absolute::LockTime;
-use bitcoin::consensus::Encodable;
-use bitcoin::hashes::{sha256, Hash};
 use bitcoin::hex::{Case, DisplayHex};
-use bitcoin::key::{Secp256k1, Keypair};
-use bitcoin::secp256k1::{ThirtyTwoByteHash, rand, Message};
-use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo, Signature};
+use bitcoin::secp256k1::Message;
+use bitcoin::taproot::{LeafVersion, TaprootSpendInfo};
 use bitcoin::transaction::Version;
 use bitcoin::consensus::encode::serialize;
 use bitcoin::{
-    amount, Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapLeafHash, TapSighashType, Transaction, TxIn, TxOut, Witness, XOnlyPublicKey
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapLeafHash, TapSighashType, Transaction, TxIn, TxOut, Witness
 };
 use bitcoin::sighash::{SighashCache, Prevouts};
 use bitcoincore_rpc::jsonrpc::serde_json;
-use log::{debug, info};
-use secp256kfun::marker::{EvenY, NonZero, Public};
-use secp256kfun::{Point, G};
+use log::debug;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use std::path::PathBuf;
+use crate::nums::{self, NumsTweak};
 use crate::htlc::scripts::{
     htlc_redeem_script, htlc_refund_script, htlc_redeem_script_with_fee,htlc_refund_script_with_fee
 };
+use crate::htlc::leaves::{self, ExtraLeaf};
+use crate::htlc::hashlock::{HashLock, HashlockError, PaymentHash, Preimage};
 use crate::htlc::signature_building;
 use crate::htlc::signature_building::{get_sigmsg_components, TxCommitmentSpec};
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) struct HTLC {
     pub htlc_funded_utxo: Option<HtlcFunded>,
+    #[serde(with = "serde_option_address")]
     pub redeem_address: Option<Address>,
     pub redeem_config: Option<RedeemConfig>,
     pub refund_config: Option<RefundConfig>,
+    /// the `r` of an H + rG internal key, H alone when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nums_tweak: Option<NumsTweak>,
+    /// leaves besides redeem and refund
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_leaves: Vec<ExtraLeaf>,
 }
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RefundConfig {
+    #[serde(with = "serde_address")]
     pub refund_address: Address,
     pub refund_lock: i64,
 }
-#[derive(Debug)]
+/// one payment hash of the redeem leaf and, once known, its preimage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hashlock {
+    pub payment_hash: PaymentHash,
+    pub preimage: Option<Preimage>,
+}
+
+/// the hash locks of the redeem leaf, all of which have to be unlocked to redeem
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedRedeemConfig")]
 pub struct RedeemConfig {
-    pub payment_hash: String,
-    pub preimage: Option<String>, // Changed to Option<String>
+    #[serde(default)]
+    hash_lock: HashLock,
+    hashlocks: Vec<Hashlock>,
+    /// the redeem leaf checks `OP_SIZE 32 OP_EQUALVERIFY` before hashing each preimage
+    #[serde(default)]
+    size_guard: bool,
+}
+
+/// also reads the single `payment_hash` and `preimage` of contracts written before multi
+/// hash lock support
+#[derive(Deserialize)]
+struct UncheckedRedeemConfig {
+    #[serde(default)]
+    hash_lock: HashLock,
+    #[serde(default)]
+    hashlocks: Vec<Hashlock>,
+    payment_hash: Option<PaymentHash>,
+    preimage: Option<Preimage>,
+    #[serde(default)]
+    size_guard: bool,
+}
+
+impl TryFrom<UncheckedRedeemConfig> for RedeemConfig {
+    type Error = HashlockError;
+
+    fn try_from(config: UncheckedRedeemConfig) -> Result<Self, Self::Error> {
+        let mut hashlocks = config.hashlocks;
+        if let Some(payment_hash) = config.payment_hash {
+            hashlocks.push(Hashlock {
+                payment_hash,
+                preimage: config.preimage,
+            });
+        }
+        let mut redeem_config = RedeemConfig::with_hash_locks(
+            config.hash_lock,
+            hashlocks.iter().map(|h| h.payment_hash.clone()).collect(),
+        )?;
+        for preimage in hashlocks.into_iter().filter_map(|h| h.preimage) {
+            redeem_config.set_preimage(preimage)?;
+        }
+        redeem_config.set_size_guard(config.size_guard);
+        Ok(redeem_config)
+    }
+}
+
+impl RedeemConfig {
+    /// a SHA256 hash lock, fails if `preimage` does not unlock `payment_hash`
+    pub(crate) fn new(
+        payment_hash: PaymentHash,
+        preimage: Option<Preimage>,
+    ) -> Result<Self, HashlockError> {
+        RedeemConfig::with_hash_lock(HashLock::Sha256, payment_hash, preimage)
+    }
+
+    /// fails if `payment_hash` is not a `hash_lock` digest or `preimage` does not unlock it
+    pub(crate) fn with_hash_lock(
+        hash_lock: HashLock,
+        payment_hash: PaymentHash,
+        preimage: Option<Preimage>,
+    ) -> Result<Self, HashlockError> {
+        let mut redeem_config = RedeemConfig::with_hash_locks(hash_lock, vec![payment_hash])?;
+        if let Some(preimage) = preimage {
+            redeem_config.set_preimage(preimage)?;
+        }
+        Ok(redeem_config)
+    }
+
+    /// a redeem leaf requiring a preimage for each of `payment_hashes`, which have to be
+    /// distinct `hash_lock` digests
+    pub(crate) fn with_hash_locks(
+        hash_lock: HashLock,
+        payment_hashes: Vec<PaymentHash>,
+    ) -> Result<Self, HashlockError> {
+        if payment_hashes.is_empty() {
+            return Err(HashlockError::NoPaymentHash);
+        }
+        for (i, payment_hash) in payment_hashes.iter().enumerate() {
+            hash_lock.check_digest(payment_hash)?;
+            if payment_hashes[..i].contains(payment_hash) {
+                return Err(HashlockError::DuplicatePaymentHash(payment_hash.clone()));
+            }
+        }
+        Ok(RedeemConfig {
+            hash_lock,
+            hashlocks: payment_hashes
+                .into_iter()
+                .map(|payment_hash| Hashlock {
+                    payment_hash,
+                    preimage: None,
+                })
+                .collect(),
+            size_guard: false,
+        })
+    }
+
+    /// skips the preimage check, for building spends that have to fail
+    #[cfg(test)]
+    pub(crate) fn new_unchecked(payment_hash: PaymentHash, preimage: Option<Preimage>) -> Self {
+        RedeemConfig {
+            hash_lock: HashLock::Sha256,
+            hashlocks: vec![Hashlock {
+                payment_hash,
+                preimage,
+            }],
+            size_guard: false,
+        }
+    }
+
+    pub(crate) fn hash_lock(&self) -> HashLock {
+        self.hash_lock
+    }
+
+    pub(crate) fn size_guard(&self) -> bool {
+        self.size_guard
+    }
+
+    /// reject preimages that are not 32 bytes in the redeem leaf, for chains that enforce it.
+    /// without it a redeem may reveal a preimage `extract_preimages` cannot return
+    pub(crate) fn set_size_guard(&mut self, size_guard: bool) {
+        self.size_guard = size_guard;
+    }
+
+    /// the hash locks in the order the redeem leaf checks them
+    pub(crate) fn hashlocks(&self) -> &[Hashlock] {
+        &self.hashlocks
+    }
+
+    /// the first payment hash, the only one of a single hash lock contract
+    #[cfg(test)]
+    pub(crate) fn payment_hash(&self) -> &PaymentHash {
+        &self.hashlocks[0].payment_hash
+    }
+
+    /// the payment hash `preimage` unlocks
+    pub(crate) fn check(&self, preimage: &Preimage) -> Result<&PaymentHash, HashlockError> {
+        self.hashlocks
+            .iter()
+            .map(|hashlock| &hashlock.payment_hash)
+            .find(|payment_hash| preimage.check_with(self.hash_lock, payment_hash).is_ok())
+            .ok_or(HashlockError::PreimageMismatch)
+    }
+
+    /// the preimage of the first payment hash
+    #[cfg(test)]
+    pub(crate) fn preimage(&self) -> Option<&Preimage> {
+        self.hashlocks[0].preimage.as_ref()
+    }
+
+    /// every preimage, in the order the redeem leaf checks them, `None` unless all are known
+    pub(crate) fn preimages(&self) -> Option<Vec<Preimage>> {
+        self.hashlocks.iter().map(|hashlock| hashlock.preimage).collect()
+    }
+
+    /// record `preimage` for the payment hash it unlocks
+    pub(crate) fn set_preimage(&mut self, preimage: Preimage) -> Result<(), HashlockError> {
+        let payment_hash = self.check(&preimage)?.clone();
+        let hashlock = self
+            .hashlocks
+            .iter_mut()
+            .find(|hashlock| hashlock.payment_hash == payment_hash)
+            .expect("check found the payment hash");
+        hashlock.preimage = Some(preimage);
+        Ok(())
+    }
 }
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HtlcFunded {
     pub htlc_outpoint: OutPoint,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
     pub amount: Amount,
 }
 // Network constant for Regtest
@@ -51,51 +230,77 @@ const NETWORK:Network = Network::Signet;
 
 
 impl HTLC {
+    #[allow(dead_code)]
     pub(crate) fn default() -> Self {
         Self {
             htlc_funded_utxo: None,
             redeem_address: None,
             redeem_config: None,
             refund_config: None,
+            nums_tweak: None,
+            extra_leaves: Vec::new(),
         }
     }
     pub(crate) fn set_funded_htlc(&mut self, outpoint: OutPoint, amount: Amount) {
         self.htlc_funded_utxo = Some(HtlcFunded {
             htlc_outpoint: outpoint,
-            amount: amount,
+            amount,
         });
     }
 
+    #[allow(dead_code)]
     pub(crate) fn set_redeem_address(&mut self, address: Address) {
         self.redeem_address = Some(address);
     }
 
+    pub(crate) fn from_file(path: &PathBuf) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub(crate) fn to_file(&self, path: &PathBuf) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// find the output of `tx` paying this HTLC (either variant) and record it as the funding utxo
+    pub(crate) fn record_funding(&mut self, tx: &Transaction) -> Result<OutPoint> {
+        let script_pubkeys = [
+            self.address(NETWORK)?.script_pubkey(),
+            self.address_with_fee(NETWORK)?.script_pubkey(),
+        ];
+        let (vout, output) = tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| script_pubkeys.contains(&output.script_pubkey))
+            .ok_or(anyhow!("transaction {} does not pay the HTLC", tx.txid()))?;
+        let outpoint = OutPoint::new(tx.txid(), vout as u32);
+        self.set_funded_htlc(outpoint, output.value);
+        Ok(outpoint)
+    }
+
     pub fn taproot_spend_info(&self) -> Result<TaprootSpendInfo> {
-        let hash = sha256::Hash::hash(G.to_bytes_uncompressed().as_slice());
-        let point: Point<EvenY, Public, NonZero> = Point::from_xonly_bytes(hash.into_32())
-            .ok_or(anyhow!("G_X hash should be a valid x-only point"))?;
-        let nums_key = XOnlyPublicKey::from_slice(point.to_xonly_bytes().as_slice())?;
-        let secp = Secp256k1::new();
-        let payment_hash = self.redeem_config.as_ref().unwrap().payment_hash.as_str();
-        Ok(TaprootBuilder::new()
-            .add_leaf(1, htlc_redeem_script(self.redeem_address.as_ref().unwrap(), payment_hash))?
-            .add_leaf(1, htlc_refund_script(&self.refund_config.as_ref().unwrap().refund_address, &self.refund_config.as_ref().unwrap().refund_lock))?
-            .finalize(&secp, nums_key)
-            .expect("finalizing taproot spend info with a NUMS point should always work"))
+        let nums_key = nums::internal_key(self.nums_tweak.as_ref());
+        let redeem_config = self.redeem_config.as_ref().unwrap();
+        leaves::spend_info(
+            htlc_redeem_script(self.redeem_address.as_ref().unwrap(), redeem_config),
+            htlc_refund_script(&self.refund_config.as_ref().unwrap().refund_address, &self.refund_config.as_ref().unwrap().refund_lock),
+            &self.extra_leaves,
+            nums_key,
+        )
     }
 
     pub fn taproot_spend_info_with_fee(&self)-> Result<TaprootSpendInfo> {
-        let hash = sha256::Hash::hash(G.to_bytes_uncompressed().as_slice());
-        let point: Point<EvenY, Public, NonZero> = Point::from_xonly_bytes(hash.into_32())
-            .ok_or(anyhow!("G_X hash should be a valid x-only point"))?;
-        let nums_key = XOnlyPublicKey::from_slice(point.to_xonly_bytes().as_slice())?;
-        let secp = Secp256k1::new();
-        let payment_hash = self.redeem_config.as_ref().unwrap().payment_hash.as_str();
-        Ok(TaprootBuilder::new()
-            .add_leaf(1, htlc_redeem_script_with_fee(self.redeem_address.as_ref().unwrap(), payment_hash))?
-            .add_leaf(1, htlc_refund_script_with_fee(&self.refund_config.as_ref().unwrap().refund_address, &self.refund_config.as_ref().unwrap().refund_lock))? // to be changed to refund with fee
-            .finalize(&secp, nums_key)
-            .expect("finalizing taproot spend info with a NUMS point should always work"))
+        let nums_key = nums::internal_key(self.nums_tweak.as_ref());
+        let redeem_config = self.redeem_config.as_ref().unwrap();
+        leaves::spend_info(
+            htlc_redeem_script_with_fee(self.redeem_address.as_ref().unwrap(), redeem_config),
+            htlc_refund_script_with_fee(&self.refund_config.as_ref().unwrap().refund_address, &self.refund_config.as_ref().unwrap().refund_lock),
+            &self.extra_leaves,
+            nums_key,
+        )
     }
     pub(crate) fn address_with_fee(&self, network: Network) -> Result<Address> {
         let spend_info = self.taproot_spend_info_with_fee()?;
@@ -108,6 +313,13 @@ impl HTLC {
     }
 
     pub(crate) fn create_redeem_tx(&self) -> Result<Transaction> {
+        let preimages = self.redeem_config.as_ref().and_then(|config| config.preimages())
+            .ok_or(anyhow!("Preimage is required"))?;
+        self.create_redeem_tx_single_anyonecanpay(&preimages)
+    }
+
+    /// the redeem whose fee an extra input pays, with `preimages`, which aren't checked
+    pub(crate) fn create_redeem_tx_single_anyonecanpay(&self, preimages: &[Preimage]) -> Result<Transaction> {
         // Validate required fields
         if self.htlc_funded_utxo.is_none() || self.redeem_address.is_none() || self.redeem_config.is_none() {
             return Err(anyhow!("Missing required fields for redeem transaction"));
@@ -122,7 +334,7 @@ impl HTLC {
         let spend_info = self.taproot_spend_info()?;
 
         // Create redeem script and leaf hash
-        let redeem_script = htlc_redeem_script(redeem_address, &redeem_config.payment_hash);
+        let redeem_script = htlc_redeem_script(redeem_address, redeem_config);
         let leaf_hash = TapLeafHash::from_script(&redeem_script, LeafVersion::TapScript);
 
         // Define the previous HTLC output (to be spent)
@@ -160,20 +372,15 @@ impl HTLC {
         };
         let contract_components = signature_building::grind_transaction(
             htlc_tx,
-            signature_building::GrindField::LockTime,
-            &[htlc_txout.clone()],
+            // the script pins the lock time to zero, grind the sequence instead
+            signature_building::GrindField::Sequence,
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
-            // TapSighashType::SinglePlusAnyoneCanPay,
-            
+            TapSighashType::SinglePlusAnyoneCanPay,
         )?;
         let signature_components = &contract_components.signature_components; // Borrow before move
         let mut grinded_txn = contract_components.transaction; // Move after borrow
 
-        let preimage = redeem_config.preimage.as_ref()
-            .ok_or(anyhow!("Preimage is required"))?;
-        
-        let preimage_hex = hex::decode(preimage).unwrap();
-
         // Build and set the witness
         let witness = self.build_witness_single_anyonecanpay(
             &grinded_txn,
@@ -184,17 +391,18 @@ impl HTLC {
             &spend_info,
             &tx_commitment_spec,
             signature_components, // Pass borrowed signature_components
-            Some(&preimage_hex),
+            preimages,
         )?;
         grinded_txn.input[0].witness = witness;
 
         // Serialize and print the raw transaction for debugging
         let raw_tx_hex = hex::encode(serialize(&grinded_txn));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
 
         Ok(grinded_txn)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn build_witness_single_anyonecanpay(
         &self,
         grinded_txn: &Transaction,
@@ -204,8 +412,8 @@ impl HTLC {
         redeem_script: &ScriptBuf,
         spend_info: &TaprootSpendInfo,
         tx_commitment_spec: &TxCommitmentSpec,
-        signature_components: &Vec<Vec<u8>>,
-        preimage: Option<&Vec<u8>> // Updated to take SignatureComponents directly
+        signature_components: &[Vec<u8>],
+        preimages: &[Preimage],
     ) -> Result<Witness> {
         // Compute witness components
         let witness_components = get_sigmsg_components(
@@ -239,8 +447,8 @@ impl HTLC {
         htlc_witness_components.push(witness_components[5].clone());
 
 
-        // Push witness components - switch 
-        for component in witness_components.iter() {
+        // the script rebuilds the rest of the sigmsg itself
+        for component in htlc_witness_components.iter() {
             debug!(
                 "pushing component <0x{}> into the witness",
                 component.to_hex_string(Case::Lower)
@@ -256,11 +464,16 @@ impl HTLC {
         let mangled_signature: [u8; 63] = computed_signature[0..63].try_into().unwrap();
         witness.push(mangled_signature);
         witness.push([computed_signature[63]]);
-        witness.push([computed_signature[63] + 1]);
-        
+        // the script appends this to the signature, so it carries the sighash byte as well
+        witness.push([
+            computed_signature[63] + 1,
+            TapSighashType::SinglePlusAnyoneCanPay as u8,
+        ]);
+
         //pushing preimage 
-        if preimage != None {
-            witness.push(preimage.unwrap());
+        // the first hash lock is checked first, so its preimage goes on top
+        for preimage in preimages.iter().rev() {
+            witness.push(preimage.to_byte_array());
         }
 
        
@@ -328,10 +541,11 @@ impl HTLC {
         };
         let contract_components = signature_building::grind_transaction(
             htlc_tx,
-            signature_building::GrindField::LockTime,
-            &[htlc_txout.clone()],
+            // the CSV check needs the relative lock, so grind it upwards from the refund lock
+            signature_building::GrindField::RelativeHeight,
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
-            // TapSighashType::SinglePlusAnyoneCanPay,
+            TapSighashType::SinglePlusAnyoneCanPay,
         )?;
 
         let signature_components = &contract_components.signature_components; // Borrow before move
@@ -347,18 +561,92 @@ impl HTLC {
             &spend_info,
             &tx_commitment_spec,
             signature_components, // Pass borrowed signature_components
-            None,
+            &[],
         )?;
         grinded_txn.input[0].witness = witness;
 
         // Serialize and print the raw transaction for debugging
         let raw_tx_hex = hex::encode(serialize(&grinded_txn));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
 
         Ok(grinded_txn)    
     }
+    /// the preimage `tx` reveals by redeeming this HTLC, `None` if it doesn't redeem it
+    pub(crate) fn extract_preimage(&self, tx: &Transaction) -> Result<Option<Preimage>> {
+        Ok(self.extract_preimages(tx)?.map(|preimages| preimages[0]))
+    }
+
+    /// the preimages `tx` reveals by redeeming this HTLC, in the order of the hash locks
+    pub(crate) fn extract_preimages(&self, tx: &Transaction) -> Result<Option<Vec<Preimage>>> {
+        let htlc_funded = self
+            .htlc_funded_utxo
+            .as_ref()
+            .ok_or(anyhow!("the HTLC is not funded"))?;
+        let redeem_address = self
+            .redeem_address
+            .as_ref()
+            .ok_or(anyhow!("the HTLC has no redeem address"))?;
+        let redeem_config = self
+            .redeem_config
+            .as_ref()
+            .ok_or(anyhow!("the HTLC has no redeem config"))?;
+
+        let Some(input) = tx
+            .input
+            .iter()
+            .find(|input| input.previous_output == htlc_funded.htlc_outpoint)
+        else {
+            return Ok(None);
+        };
+        let redeem_scripts = [
+            htlc_redeem_script(redeem_address, redeem_config),
+            htlc_redeem_script_with_fee(redeem_address, redeem_config),
+        ];
+        match input.witness.tapscript() {
+            Some(script) if redeem_scripts.iter().any(|s| s.as_script() == script) => {}
+            _ => return Ok(None),
+        }
+
+        let preimages = redeem_config
+            .hashlocks()
+            .iter()
+            .map(|hashlock| {
+                let element = input
+                    .witness
+                    .iter()
+                    .find(|element| {
+                        redeem_config.hash_lock().hash(element) == hashlock.payment_hash
+                    })
+                    .ok_or(anyhow!(
+                        "redeem of {} carries no preimage for {}",
+                        htlc_funded.htlc_outpoint,
+                        hashlock.payment_hash
+                    ))?;
+                Preimage::from_slice(element).map_err(|_| {
+                    anyhow!(
+                        "redeem of {} reveals a {} byte preimage for {}, only 32 byte preimages can be extracted",
+                        htlc_funded.htlc_outpoint,
+                        element.len(),
+                        hashlock.payment_hash
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(preimages))
+    }
+
     // doesnt need a extra input the user can set fee in the stack
     pub(crate) fn create_redeem_tx_with_fee(&self,fee_amount:Amount)->Result<Transaction> {
+        let preimages = self
+            .redeem_config
+            .as_ref()
+            .and_then(|redeem_config| redeem_config.preimages())
+            .ok_or(anyhow!("Preimage is required"))?;
+        self.create_redeem_tx_with_preimages(fee_amount, &preimages)
+    }
+
+    /// the redeem with fee, revealing `preimages` whether or not they match the payment hashes
+    pub(crate) fn create_redeem_tx_with_preimages(&self,fee_amount:Amount,preimages:&[Preimage])->Result<Transaction> {
           // Validate required fields 
           if self.htlc_funded_utxo.is_none() || self.redeem_address.is_none() || self.redeem_config.is_none() {
             return Err(anyhow!("Missing required fields for redeem transaction"));
@@ -373,7 +661,7 @@ impl HTLC {
         let spend_info = self.taproot_spend_info_with_fee()?;
 
         // Create redeem script and leaf hash
-        let redeem_script = htlc_redeem_script_with_fee(redeem_address, &redeem_config.payment_hash);
+        let redeem_script = htlc_redeem_script_with_fee(redeem_address, redeem_config);
         let leaf_hash = TapLeafHash::from_script(&redeem_script, LeafVersion::TapScript);
 
         // Define the previous HTLC output (to be spent)
@@ -412,9 +700,9 @@ impl HTLC {
         let contract_components = signature_building::grind_transaction(
             htlc_tx,
             signature_building::GrindField::LockTime,
-            &[htlc_txout.clone()],
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
-            // TapSighashType::Default,
+            TapSighashType::Default,
         )?;
         let signature_components = &contract_components.signature_components; // Borrow before move
         let mut grinded_txn = contract_components.transaction; // Move after borrow
@@ -422,17 +710,12 @@ impl HTLC {
         let message = compute_taproot_sighash(
             &grinded_txn,
             0,
-            &[htlc_txout.clone()],
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
             TapSighashType::Default,
         )?;
 
-        println!("Message: {}", message);
-
-        let preimage = redeem_config.preimage.as_ref()
-            .ok_or(anyhow!("Preimage is required"))?;
-        
-        let preimage_hex = hex::decode(preimage).unwrap();
+        debug!("Message: {}", message);
 
         // Build and set the witness
         let witness = self.build_witness_all(
@@ -444,13 +727,13 @@ impl HTLC {
             &spend_info,
             &tx_commitment_spec,
             signature_components, // Pass borrowed signature_components
-            Some(&preimage_hex),
+            preimages,
         )?;
         grinded_txn.input[0].witness = witness;
 
         // Serialize and print the raw transaction for debugging
         let raw_tx_hex = hex::encode(serialize(&grinded_txn));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
 
         Ok(grinded_txn)
     }
@@ -473,7 +756,7 @@ impl HTLC {
         let leaf_hash = TapLeafHash::from_script(&refund_script, LeafVersion::TapScript);
 
         // Define the previous HTLC output (to be spent)
-        let htlc_address = self.address(NETWORK)?; // Assuming Bitcoin network
+        let htlc_address = self.address_with_fee(NETWORK)?; // Assuming Bitcoin network
         let htlc_txout = TxOut {
             script_pubkey: htlc_address.script_pubkey(),
             value: htlc_funded.amount,
@@ -506,30 +789,31 @@ impl HTLC {
             ..Default::default()
         };
         let raw_tx_hex = hex::encode(serialize(&htlc_tx));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
         
         let contract_components = signature_building::grind_transaction(
             htlc_tx,
             signature_building::GrindField::LockTime,
-            &[htlc_txout.clone()],
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
+            TapSighashType::Default,
         )?;
 
         let signature_components = &contract_components.signature_components; // Borrow before move
         let mut grinded_txn = contract_components.transaction; // Move after borrow
         let raw_tx_hex = hex::encode(serialize(&grinded_txn));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
 
 
         let message = compute_taproot_sighash(
             &grinded_txn,
             0,
-            &[htlc_txout.clone()],
+            std::slice::from_ref(&htlc_txout),
             leaf_hash,
             TapSighashType::Default,
         )?;
 
-        println!("Message: {}", message);
+        debug!("Message: {}", message);
 
         // Build and set the witness
         let witness = self.build_witness_all(
@@ -541,18 +825,19 @@ impl HTLC {
             &spend_info,
             &tx_commitment_spec,
             signature_components, // Pass borrowed signature_components
-            None,
+            &[],
         )?;
         htlc_txin.witness = witness;
         grinded_txn.input.first_mut().unwrap().witness = htlc_txin.witness.clone();
 
         // Serialize and print the raw transaction for debugging
         let raw_tx_hex = hex::encode(serialize(&grinded_txn));
-        println!("Raw transaction hex: {}", raw_tx_hex);
+        debug!("Raw transaction hex: {}", raw_tx_hex);
    
         Ok(grinded_txn)    
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn build_witness_all(
         &self,
         grinded_txn: &Transaction,
@@ -562,8 +847,8 @@ impl HTLC {
         redeem_script: &ScriptBuf,
         spend_info: &TaprootSpendInfo,
         tx_commitment_spec: &TxCommitmentSpec,
-        signature_components: &Vec<Vec<u8>>,
-        preimage: Option<&Vec<u8>> // Updated to take SignatureComponents directly
+        signature_components: &[Vec<u8>],
+        preimages: &[Preimage],
     ) -> Result<Witness> {
         // Compute witness components
         let witness_components = get_sigmsg_components(
@@ -602,8 +887,9 @@ impl HTLC {
         witness.push([computed_signature[63] + 1]);
         
         //pushing preimage 
-        if preimage != None {
-            witness.push(preimage.unwrap());
+        // the first hash lock is checked first, so its preimage goes on top
+        for preimage in preimages.iter().rev() {
+            witness.push(preimage.to_byte_array());
         }
 
         // Push redeem script and control block
@@ -659,3 +945,37 @@ pub(crate) fn compute_taproot_sighash(
         ).expect("Failed to compute signature");
     Message::from_digest_slice(&sighash[..])
 }
+
+/// addresses are stored unchecked, the network is enforced where they are used
+pub(crate) mod serde_address {
+    use bitcoin::address::NetworkUnchecked;
+    use bitcoin::Address;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(address: &Address, s: S) -> Result<S::Ok, S::Error> {
+        address.serialize(s)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Address, D::Error> {
+        Ok(Address::<NetworkUnchecked>::deserialize(d)?.assume_checked())
+    }
+}
+
+mod serde_option_address {
+    use bitcoin::address::NetworkUnchecked;
+    use bitcoin::Address;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        address: &Option<Address>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        address.serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Address>, D::Error> {
+        Ok(Option::<Address<NetworkUnchecked>>::deserialize(d)?.map(|a| a.assume_checked()))
+    }
+}
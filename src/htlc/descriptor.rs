@@ -0,0 +1,448 @@
+//! A textual encoding of an HTLC, in the style of output script descriptors.
+//!
+//! `cathtlc(hash=<hex>,redeem=<address>,refund=<address>,older=<blocks>,fee=stack)#<checksum>`
+//! determines the taproot tree and so the address. A multi hash lock repeats `hash=`, a hash
+//! function other than SHA256 adds `hash_lock=<name>` and the OP_SIZE guard adds `size_guard`.
+//! `nums=<hex>` is the `r` of an H + rG internal key, without it the internal key is H. Extra
+//! leaves follow as `arbiter=<key>:<key>:<key>` and `recovery=<key>:<blocks>`.
+//! `fee=stack` is the tree of `taproot_spend_info_with_fee`, where the spend pays its fee from the
+//! HTLC amount, `fee=input` the one of `taproot_spend_info`, where an extra input pays it. The
+//! checksum is the one of BIP380, it is checked when present and always printed.
+
+use std::fmt;
+
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::{Address, Network};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::htlc::contract::{RedeemConfig, RefundConfig, HTLC};
+use crate::htlc::hashlock::{HashLock, HashlockError, PaymentHash};
+use crate::htlc::leaves::ExtraLeaf;
+use crate::nums::NumsTweak;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Debug)]
+pub(crate) enum DescriptorError {
+    /// not of the form `cathtlc(...)`
+    Syntax(String),
+    UnknownKey(String),
+    MissingKey(&'static str),
+    /// a key other than `hash`, `arbiter` and `recovery` given twice
+    DuplicateKey(String),
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+    InvalidChecksum {
+        expected: String,
+        actual: String,
+    },
+    Hashlock(HashlockError),
+    Address(bitcoin::address::Error),
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::Syntax(reason) => write!(f, "malformed descriptor: {}", reason),
+            DescriptorError::UnknownKey(key) => write!(f, "unknown descriptor key {}", key),
+            DescriptorError::MissingKey(key) => write!(f, "the descriptor has no {}", key),
+            DescriptorError::DuplicateKey(key) => {
+                write!(f, "the descriptor has more than one {}", key)
+            }
+            DescriptorError::InvalidValue { key, value } => {
+                write!(f, "invalid {} in the descriptor: {}", key, value)
+            }
+            DescriptorError::InvalidChecksum { expected, actual } => {
+                write!(
+                    f,
+                    "descriptor checksum is {}, expected {}",
+                    actual, expected
+                )
+            }
+            DescriptorError::Hashlock(e) => write!(f, "{}", e),
+            DescriptorError::Address(e) => write!(f, "invalid address in the descriptor: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DescriptorError::Hashlock(e) => Some(e),
+            DescriptorError::Address(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HashlockError> for DescriptorError {
+    fn from(e: HashlockError) -> Self {
+        DescriptorError::Hashlock(e)
+    }
+}
+
+impl From<bitcoin::address::Error> for DescriptorError {
+    fn from(e: bitcoin::address::Error) -> Self {
+        DescriptorError::Address(e)
+    }
+}
+
+/// who pays the fee of a spend, which picks between the two trees of an HTLC. The default is
+/// the tree `deposit` funds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FeeMode {
+    /// SIGHASH_DEFAULT leaves, the output pays the fee chosen on the witness stack
+    Stack,
+    /// SIGHASH_SINGLE|ANYONECANPAY leaves, an extra input pays the fee
+    #[default]
+    Input,
+}
+
+impl fmt::Display for FeeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeMode::Stack => write!(f, "stack"),
+            FeeMode::Input => write!(f, "input"),
+        }
+    }
+}
+
+/// everything the address of an HTLC commits to, and nothing secret
+#[derive(Debug)]
+pub(crate) struct HtlcDescriptor {
+    pub redeem_address: Address,
+    pub redeem_config: RedeemConfig,
+    pub refund_config: RefundConfig,
+    pub fee: FeeMode,
+    pub nums_tweak: Option<NumsTweak>,
+    pub extra_leaves: Vec<ExtraLeaf>,
+}
+
+impl HtlcDescriptor {
+    pub(crate) fn from_htlc(htlc: &HTLC, fee: FeeMode) -> Result<Self, DescriptorError> {
+        let redeem_address = htlc
+            .redeem_address
+            .clone()
+            .ok_or(DescriptorError::MissingKey("redeem"))?;
+        let redeem_config = htlc
+            .redeem_config
+            .as_ref()
+            .ok_or(DescriptorError::MissingKey("hash"))?;
+        let refund_config = htlc
+            .refund_config
+            .as_ref()
+            .ok_or(DescriptorError::MissingKey("refund"))?;
+        // `older` is a BIP68 block count, which the parser reads back as a u16
+        if u16::try_from(refund_config.refund_lock).is_err() {
+            return Err(DescriptorError::InvalidValue {
+                key: "older".to_string(),
+                value: refund_config.refund_lock.to_string(),
+            });
+        }
+        // the preimages are not part of the contract, leave them out
+        let mut public_config = RedeemConfig::with_hash_locks(
+            redeem_config.hash_lock(),
+            redeem_config
+                .hashlocks()
+                .iter()
+                .map(|hashlock| hashlock.payment_hash.clone())
+                .collect(),
+        )?;
+        public_config.set_size_guard(redeem_config.size_guard());
+        Ok(HtlcDescriptor {
+            redeem_address,
+            redeem_config: public_config,
+            refund_config: RefundConfig {
+                refund_address: refund_config.refund_address.clone(),
+                refund_lock: refund_config.refund_lock,
+            },
+            fee,
+            nums_tweak: htlc.nums_tweak,
+            extra_leaves: htlc.extra_leaves.clone(),
+        })
+    }
+
+    /// parse a descriptor whose addresses belong to `network`
+    pub(crate) fn parse(descriptor: &str, network: Network) -> Result<Self, DescriptorError> {
+        let body = match descriptor.split_once('#') {
+            Some((body, actual)) => {
+                let expected = checksum(body)?;
+                if actual != expected {
+                    return Err(DescriptorError::InvalidChecksum {
+                        expected,
+                        actual: actual.to_string(),
+                    });
+                }
+                body
+            }
+            None => descriptor,
+        };
+        let arguments = body
+            .strip_prefix("cathtlc(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or(DescriptorError::Syntax("expected cathtlc(...)".to_string()))?;
+
+        let mut payment_hashes = Vec::new();
+        let mut hash_lock = HashLock::default();
+        let mut size_guard = false;
+        let (mut redeem, mut refund, mut older, mut fee) = (None, None, None, None);
+        let mut nums_tweak = None;
+        let mut extra_leaves = Vec::new();
+        let mut seen = Vec::new();
+        for argument in arguments.split(',') {
+            let (key, value) = argument.split_once('=').unwrap_or((argument, ""));
+            // a repeated key would silently override the one before it
+            if !matches!(key, "hash" | "arbiter" | "recovery") {
+                if seen.contains(&key) {
+                    return Err(DescriptorError::DuplicateKey(key.to_string()));
+                }
+                seen.push(key);
+            }
+            let invalid = || DescriptorError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            match key {
+                "hash" => payment_hashes.push(value.parse::<PaymentHash>()?),
+                "hash_lock" => {
+                    hash_lock = HashLock::from_str(value, false).map_err(|_| invalid())?
+                }
+                "size_guard" if value.is_empty() => size_guard = true,
+                "redeem" => redeem = Some(parse_address(value, network)?),
+                "refund" => refund = Some(parse_address(value, network)?),
+                "older" => older = Some(value.parse::<u16>().map_err(|_| invalid())?),
+                "fee" => fee = Some(FeeMode::from_str(value, false).map_err(|_| invalid())?),
+                "nums" => nums_tweak = Some(value.parse::<NumsTweak>().map_err(|_| invalid())?),
+                "arbiter" | "recovery" => {
+                    extra_leaves.push(argument.parse::<ExtraLeaf>().map_err(|_| invalid())?)
+                }
+                _ => return Err(DescriptorError::UnknownKey(argument.to_string())),
+            }
+        }
+
+        let mut redeem_config = RedeemConfig::with_hash_locks(hash_lock, payment_hashes)?;
+        redeem_config.set_size_guard(size_guard);
+        Ok(HtlcDescriptor {
+            redeem_address: redeem.ok_or(DescriptorError::MissingKey("redeem"))?,
+            redeem_config,
+            refund_config: RefundConfig {
+                refund_address: refund.ok_or(DescriptorError::MissingKey("refund"))?,
+                refund_lock: older.ok_or(DescriptorError::MissingKey("older"))? as i64,
+            },
+            fee: fee.ok_or(DescriptorError::MissingKey("fee"))?,
+            nums_tweak,
+            extra_leaves,
+        })
+    }
+
+    /// an unfunded HTLC with this tree
+    pub(crate) fn to_htlc(&self) -> HTLC {
+        HTLC {
+            htlc_funded_utxo: None,
+            redeem_address: Some(self.redeem_address.clone()),
+            redeem_config: Some(self.redeem_config.clone()),
+            refund_config: Some(RefundConfig {
+                refund_address: self.refund_config.refund_address.clone(),
+                refund_lock: self.refund_config.refund_lock,
+            }),
+            nums_tweak: self.nums_tweak,
+            extra_leaves: self.extra_leaves.clone(),
+        }
+    }
+
+    pub(crate) fn taproot_spend_info(&self) -> anyhow::Result<TaprootSpendInfo> {
+        match self.fee {
+            FeeMode::Stack => self.to_htlc().taproot_spend_info_with_fee(),
+            FeeMode::Input => self.to_htlc().taproot_spend_info(),
+        }
+    }
+
+    pub(crate) fn address(&self, network: Network) -> anyhow::Result<Address> {
+        Ok(Address::p2tr_tweaked(
+            self.taproot_spend_info()?.output_key(),
+            network,
+        ))
+    }
+
+    fn body(&self) -> String {
+        let mut arguments: Vec<String> = self
+            .redeem_config
+            .hashlocks()
+            .iter()
+            .map(|hashlock| format!("hash={}", hashlock.payment_hash))
+            .collect();
+        if self.redeem_config.hash_lock() != HashLock::default() {
+            arguments.push(format!("hash_lock={}", self.redeem_config.hash_lock()));
+        }
+        if self.redeem_config.size_guard() {
+            arguments.push("size_guard".to_string());
+        }
+        arguments.push(format!("redeem={}", self.redeem_address));
+        arguments.push(format!("refund={}", self.refund_config.refund_address));
+        arguments.push(format!("older={}", self.refund_config.refund_lock));
+        arguments.push(format!("fee={}", self.fee));
+        if let Some(nums_tweak) = self.nums_tweak {
+            arguments.push(format!("nums={}", nums_tweak));
+        }
+        arguments.extend(self.extra_leaves.iter().map(|leaf| leaf.to_string()));
+        format!("cathtlc({})", arguments.join(","))
+    }
+}
+
+impl fmt::Display for HtlcDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self.body();
+        let checksum = checksum(&body).map_err(|_| fmt::Error)?;
+        write!(f, "{}#{}", body, checksum)
+    }
+}
+
+fn parse_address(value: &str, network: Network) -> Result<Address, DescriptorError> {
+    let address: Address<NetworkUnchecked> =
+        value.parse().map_err(|_| DescriptorError::InvalidValue {
+            key: "address".to_string(),
+            value: value.to_string(),
+        })?;
+    Ok(address.require_network(network)?)
+}
+
+fn poly_mod(mut c: u64, value: u64) -> u64 {
+    let c0 = c >> 35;
+    c = ((c & 0x7ffffffff) << 5) ^ value;
+    for (bit, generator) in [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// the BIP380 descriptor checksum of `body`
+fn checksum(body: &str) -> Result<String, DescriptorError> {
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in body.chars() {
+        let position = INPUT_CHARSET
+            .find(ch)
+            .ok_or(DescriptorError::Syntax(format!(
+                "invalid character {:?}",
+                ch
+            )))? as u64;
+        c = poly_mod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = poly_mod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = poly_mod(c, class);
+    }
+    for _ in 0..8 {
+        c = poly_mod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{address, htlc, PREIMAGE};
+
+    #[test]
+    fn checksum_matches_bip380() {
+        assert_eq!(checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+    }
+
+    #[test]
+    fn descriptor_round_trips_to_the_same_address() {
+        for fee in [FeeMode::Stack, FeeMode::Input] {
+            let htlc = htlc(PREIMAGE);
+            let descriptor = HtlcDescriptor::from_htlc(&htlc, fee).unwrap().to_string();
+            let parsed = HtlcDescriptor::parse(&descriptor, Network::Regtest).unwrap();
+            assert_eq!(parsed.to_string(), descriptor);
+            let expected = match fee {
+                FeeMode::Stack => htlc.address_with_fee(Network::Regtest).unwrap(),
+                FeeMode::Input => htlc.address(Network::Regtest).unwrap(),
+            };
+            assert_eq!(parsed.address(Network::Regtest).unwrap(), expected);
+            assert!(!descriptor.contains(PREIMAGE));
+        }
+    }
+
+    #[test]
+    fn tampered_descriptors_are_rejected() {
+        let descriptor = HtlcDescriptor::from_htlc(&htlc(PREIMAGE), FeeMode::Stack)
+            .unwrap()
+            .to_string();
+        let tampered = descriptor.replace("older=20", "older=21");
+        assert!(matches!(
+            HtlcDescriptor::parse(&tampered, Network::Regtest),
+            Err(DescriptorError::InvalidChecksum { .. })
+        ));
+        // without the checksum it parses, to another contract
+        let (body, _) = tampered.split_once('#').unwrap();
+        let parsed = HtlcDescriptor::parse(body, Network::Regtest).unwrap();
+        assert_eq!(parsed.refund_config.refund_lock, 21);
+        assert!(HtlcDescriptor::parse(&descriptor, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn repeated_keys_are_rejected() {
+        let mut htlc = htlc(PREIMAGE);
+        htlc.redeem_config.as_mut().unwrap().set_size_guard(true);
+        let descriptor = HtlcDescriptor::from_htlc(&htlc, FeeMode::Stack)
+            .unwrap()
+            .to_string();
+        let (body, _) = descriptor.split_once('#').unwrap();
+        for argument in ["older=21", "fee=input", "size_guard", &format!("redeem={}", address(4))] {
+            let repeated = format!("{},{})", body.strip_suffix(')').unwrap(), argument);
+            let key = argument.split('=').next().unwrap();
+            assert!(
+                matches!(
+                    HtlcDescriptor::parse(&repeated, Network::Regtest),
+                    Err(DescriptorError::DuplicateKey(k)) if k == key
+                ),
+                "{}",
+                repeated
+            );
+        }
+        // a second hash is a multi hash lock
+        let second_hash = format!("{},hash={})", body.strip_suffix(')').unwrap(), "11".repeat(32));
+        assert!(HtlcDescriptor::parse(&second_hash, Network::Regtest).is_ok());
+    }
+
+    #[test]
+    fn refund_locks_beyond_bip68_are_not_printed() {
+        for refund_lock in [-1, u16::MAX as i64 + 1] {
+            let mut htlc = htlc(PREIMAGE);
+            htlc.refund_config.as_mut().unwrap().refund_lock = refund_lock;
+            assert!(matches!(
+                HtlcDescriptor::from_htlc(&htlc, FeeMode::Stack),
+                Err(DescriptorError::InvalidValue { key, .. }) if key == "older"
+            ));
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Payment hashes, preimages and the hash function locking the redeem leaf, validated when
+//! they are constructed.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::opcodes::all::{OP_HASH160, OP_HASH256, OP_RIPEMD160, OP_SHA256};
+use bitcoin::opcodes::Opcode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub(crate) enum HashlockError {
+    InvalidHex(hex::FromHexError),
+    /// a preimage or payment hash of none of the `expected` lengths
+    InvalidLength {
+        expected: Vec<usize>,
+        actual: usize,
+    },
+    /// the preimage does not hash to the payment hash
+    PreimageMismatch,
+    NoPaymentHash,
+    /// a preimage would unlock two hash locks at once
+    DuplicatePaymentHash(PaymentHash),
+}
+
+impl fmt::Display for HashlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashlockError::InvalidHex(e) => write!(f, "invalid hex: {}", e),
+            HashlockError::InvalidLength { expected, actual } => {
+                let expected: Vec<String> = expected.iter().map(usize::to_string).collect();
+                write!(f, "expected {} bytes, got {}", expected.join(" or "), actual)
+            }
+            HashlockError::PreimageMismatch => {
+                write!(f, "the preimage does not hash to the payment hash")
+            }
+            HashlockError::NoPaymentHash => write!(f, "the redeem leaf needs a payment hash"),
+            HashlockError::DuplicatePaymentHash(payment_hash) => {
+                write!(f, "payment hash {} is used twice", payment_hash)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HashlockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HashlockError::InvalidHex(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<hex::FromHexError> for HashlockError {
+    fn from(e: hex::FromHexError) -> Self {
+        HashlockError::InvalidHex(e)
+    }
+}
+
+/// the hash function the redeem leaf applies to the preimage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HashLock {
+    /// the lightning payment hash
+    #[default]
+    Sha256,
+    /// RIPEMD160(SHA256(x)), used by most pre-taproot atomic swap protocols
+    Hash160,
+    /// SHA256(SHA256(x))
+    Hash256,
+    Ripemd160,
+}
+
+impl HashLock {
+    pub(crate) fn opcode(self) -> Opcode {
+        match self {
+            HashLock::Sha256 => OP_SHA256,
+            HashLock::Hash160 => OP_HASH160,
+            HashLock::Hash256 => OP_HASH256,
+            HashLock::Ripemd160 => OP_RIPEMD160,
+        }
+    }
+
+    pub(crate) fn digest_len(self) -> usize {
+        match self {
+            HashLock::Sha256 | HashLock::Hash256 => 32,
+            HashLock::Hash160 | HashLock::Ripemd160 => 20,
+        }
+    }
+
+    /// the payment hash of `preimage`, as the opcode computes it
+    pub(crate) fn hash(self, preimage: &[u8]) -> PaymentHash {
+        PaymentHash(match self {
+            HashLock::Sha256 => sha256::Hash::hash(preimage).to_byte_array().to_vec(),
+            HashLock::Hash160 => hash160::Hash::hash(preimage).to_byte_array().to_vec(),
+            HashLock::Hash256 => sha256d::Hash::hash(preimage).to_byte_array().to_vec(),
+            HashLock::Ripemd160 => ripemd160::Hash::hash(preimage).to_byte_array().to_vec(),
+        })
+    }
+
+    /// fails unless `payment_hash` has the length of this function's digests
+    pub(crate) fn check_digest(self, payment_hash: &PaymentHash) -> Result<(), HashlockError> {
+        if payment_hash.0.len() != self.digest_len() {
+            return Err(HashlockError::InvalidLength {
+                expected: vec![self.digest_len()],
+                actual: payment_hash.0.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HashLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashLock::Sha256 => "sha256",
+            HashLock::Hash160 => "hash160",
+            HashLock::Hash256 => "hash256",
+            HashLock::Ripemd160 => "ripemd160",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// the digest the redeem leaf locks the funds to, 32 bytes or 20 for the RIPEMD160 based locks
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct PaymentHash(Vec<u8>);
+
+/// the 32 byte secret unlocking the redeem leaf
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Preimage([u8; 32]);
+
+impl PaymentHash {
+    pub(crate) fn from_byte_array(bytes: [u8; 32]) -> Self {
+        PaymentHash(bytes.to_vec())
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for PaymentHash {
+    type Err = HashlockError;
+
+    /// accepts the digest length of any hash lock, `HashLock::check_digest` matches it to one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)?;
+        if bytes.len() != 20 && bytes.len() != 32 {
+            return Err(HashlockError::InvalidLength {
+                expected: vec![20, 32],
+                actual: bytes.len(),
+            });
+        }
+        Ok(PaymentHash(bytes))
+    }
+}
+
+impl Preimage {
+    pub(crate) fn from_byte_array(bytes: [u8; 32]) -> Self {
+        Preimage(bytes)
+    }
+
+    /// a preimage read from a witness element
+    pub(crate) fn from_slice(bytes: &[u8]) -> Result<Self, HashlockError> {
+        let bytes = bytes.try_into().map_err(|_| HashlockError::InvalidLength {
+            expected: vec![32],
+            actual: bytes.len(),
+        })?;
+        Ok(Preimage(bytes))
+    }
+
+    pub(crate) fn to_byte_array(self) -> [u8; 32] {
+        self.0
+    }
+
+    /// the SHA256 payment hash
+    pub(crate) fn payment_hash(&self) -> PaymentHash {
+        HashLock::Sha256.hash(&self.0)
+    }
+
+    /// fails unless this preimage unlocks the SHA256 `payment_hash`
+    #[cfg(test)]
+    pub(crate) fn check(&self, payment_hash: &PaymentHash) -> Result<(), HashlockError> {
+        self.check_with(HashLock::Sha256, payment_hash)
+    }
+
+    /// fails unless this preimage unlocks `payment_hash` under `hash_lock`
+    pub(crate) fn check_with(
+        &self,
+        hash_lock: HashLock,
+        payment_hash: &PaymentHash,
+    ) -> Result<(), HashlockError> {
+        if &hash_lock.hash(&self.0) != payment_hash {
+            return Err(HashlockError::PreimageMismatch);
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Preimage {
+    type Err = HashlockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Preimage::from_slice(&hex::decode(s)?)
+    }
+}
+
+macro_rules! impl_hex_string {
+    ($type:ident) => {
+        impl TryFrom<String> for $type {
+            type Error = HashlockError;
+
+            fn try_from(s: String) -> Result<Self, Self::Error> {
+                s.parse()
+            }
+        }
+
+        impl From<$type> for String {
+            fn from(value: $type) -> Self {
+                value.to_string()
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", hex::encode(&self.0))
+            }
+        }
+
+        impl fmt::Debug for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($type), self)
+            }
+        }
+    };
+}
+
+impl_hex_string!(PaymentHash);
+impl_hex_string!(Preimage);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainBackend;
+    use crate::htlc::contract::RedeemConfig;
+    use crate::testing::{deposit, htlc, new_chain, FEE, PAYMENT_HASH, PREIMAGE};
+
+    #[test]
+    fn preimage_matches_its_hash() {
+        let preimage = Preimage::from_str(PREIMAGE).unwrap();
+        let payment_hash = PaymentHash::from_str(PAYMENT_HASH).unwrap();
+        assert_eq!(preimage.payment_hash(), payment_hash);
+        assert!(preimage.check(&payment_hash).is_ok());
+        assert!(matches!(
+            Preimage::from_byte_array([0; 32]).check(&payment_hash),
+            Err(HashlockError::PreimageMismatch)
+        ));
+    }
+
+    #[test]
+    fn hash_locks_match_the_script_opcodes() {
+        let preimage = Preimage::from_str(PREIMAGE).unwrap();
+        for hash_lock in [
+            HashLock::Sha256,
+            HashLock::Hash160,
+            HashLock::Hash256,
+            HashLock::Ripemd160,
+        ] {
+            let payment_hash = hash_lock.hash(&preimage.to_byte_array());
+            assert_eq!(payment_hash.as_bytes().len(), hash_lock.digest_len());
+            assert!(hash_lock.check_digest(&payment_hash).is_ok());
+            assert!(preimage.check_with(hash_lock, &payment_hash).is_ok());
+        }
+        // HASH160 is RIPEMD160 over the SHA256 payment hash
+        let hash160 = HashLock::Hash160.hash(&preimage.to_byte_array());
+        assert_eq!(
+            HashLock::Ripemd160.hash(PaymentHash::from_str(PAYMENT_HASH).unwrap().as_bytes()),
+            hash160
+        );
+        assert!(matches!(
+            HashLock::Sha256.check_digest(&hash160),
+            Err(HashlockError::InvalidLength { actual: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_hex_is_rejected() {
+        assert!(matches!(
+            PaymentHash::from_str("zz"),
+            Err(HashlockError::InvalidHex(_))
+        ));
+        let err = PaymentHash::from_str(&PAYMENT_HASH[..62]).unwrap_err();
+        assert!(matches!(err, HashlockError::InvalidLength { actual: 31, .. }));
+        assert_eq!(err.to_string(), "expected 20 or 32 bytes, got 31");
+        let err = Preimage::from_str(&PREIMAGE[..62]).unwrap_err();
+        assert!(matches!(err, HashlockError::InvalidLength { actual: 31, .. }));
+        assert_eq!(err.to_string(), "expected 32 bytes, got 31");
+    }
+
+    #[test]
+    fn payment_hashes_match_the_selected_hash_lock() {
+        let sha256 = PaymentHash::from_str(PAYMENT_HASH).unwrap();
+        let hash160 = HashLock::Hash160.hash(&Preimage::from_str(PREIMAGE).unwrap().to_byte_array());
+        assert!(RedeemConfig::with_hash_locks(HashLock::Sha256, vec![sha256.clone()]).is_ok());
+        for (hash_lock, payment_hash) in [
+            (HashLock::Sha256, hash160.clone()),
+            (HashLock::Hash256, hash160),
+            (HashLock::Hash160, sha256.clone()),
+            (HashLock::Ripemd160, sha256),
+        ] {
+            let err = RedeemConfig::with_hash_locks(hash_lock, vec![payment_hash]).unwrap_err();
+            assert!(
+                matches!(&err, HashlockError::InvalidLength { expected, .. }
+                    if expected == &[hash_lock.digest_len()]),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn single_hash_contracts_still_load() {
+        use crate::htlc::contract::RedeemConfig;
+        use bitcoincore_rpc::jsonrpc::serde_json;
+
+        let json = format!(
+            r#"{{"payment_hash":"{}","preimage":"{}"}}"#,
+            PAYMENT_HASH, PREIMAGE
+        );
+        let redeem_config: RedeemConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(redeem_config.hashlocks().len(), 1);
+        assert_eq!(redeem_config.preimage().unwrap().to_string(), PREIMAGE);
+
+        let mismatch = json.replace(PREIMAGE, &"00".repeat(32));
+        assert!(serde_json::from_str::<RedeemConfig>(&mismatch).is_err());
+    }
+
+    #[test]
+    fn hash160_redeem_reveals_the_preimage() {
+        let chain = new_chain();
+        let preimage: Preimage = PREIMAGE.parse().unwrap();
+        let payment_hash = HashLock::Hash160.hash(&preimage.to_byte_array());
+        let mut htlc = htlc(PREIMAGE);
+        htlc.redeem_config = Some(
+            RedeemConfig::with_hash_lock(HashLock::Hash160, payment_hash, Some(preimage)).unwrap(),
+        );
+        deposit(&chain, &mut htlc);
+
+        let redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        chain.broadcast(&redeem_tx).unwrap();
+        assert_eq!(htlc.extract_preimage(&redeem_tx).unwrap(), Some(preimage));
+    }
+
+    #[test]
+    fn size_guard_rejects_a_short_preimage() {
+        let short_preimage = [7u8; 16];
+        let payment_hash = HashLock::Sha256.hash(&short_preimage);
+        let redeem_with = |size_guard: bool| {
+            let chain = new_chain();
+            let mut redeem_config =
+                RedeemConfig::new_unchecked(payment_hash.clone(), Some(PREIMAGE.parse().unwrap()));
+            redeem_config.set_size_guard(size_guard);
+            let mut htlc = htlc(PREIMAGE);
+            htlc.redeem_config = Some(redeem_config);
+            deposit(&chain, &mut htlc);
+
+            // the covenant signs the transaction, not the witness, so the preimage can be swapped
+            let mut redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+            let mut witness = redeem_tx.input[0].witness.to_vec();
+            let preimage_index = witness.len() - 3;
+            witness[preimage_index] = short_preimage.to_vec();
+            redeem_tx.input[0].witness = witness.into();
+            chain.broadcast(&redeem_tx)?;
+            htlc.extract_preimage(&redeem_tx)
+        };
+
+        // without the guard the redeem goes through but reveals a preimage that can't be extracted
+        let err = redeem_with(false).unwrap_err();
+        assert!(err.to_string().contains("16 byte preimage"), "{}", err);
+        let err = redeem_with(true).unwrap_err();
+        assert!(err.to_string().contains("OP_EQUALVERIFY failed"), "{}", err);
+    }
+
+    #[test]
+    fn multi_hashlock_redeem_needs_every_preimage() {
+        let chain = new_chain();
+        let preimages: Vec<Preimage> = (1..=3)
+            .map(|i| Preimage::from_byte_array([i; 32]))
+            .collect();
+        let mut redeem_config = RedeemConfig::with_hash_locks(
+            HashLock::Sha256,
+            preimages.iter().map(|p| p.payment_hash()).collect(),
+        )
+        .unwrap();
+        let mut htlc = htlc(PREIMAGE);
+        for preimage in [preimages[2], preimages[1]] {
+            redeem_config.set_preimage(preimage).unwrap();
+        }
+        htlc.redeem_config = Some(redeem_config.clone());
+        deposit(&chain, &mut htlc);
+        assert!(htlc.create_redeem_tx_with_fee(FEE).is_err());
+
+        // preimages can arrive in any order, the witness follows the hash locks
+        redeem_config.set_preimage(preimages[0]).unwrap();
+        htlc.redeem_config = Some(redeem_config);
+        let redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        chain.broadcast(&redeem_tx).unwrap();
+        assert_eq!(htlc.extract_preimages(&redeem_tx).unwrap(), Some(preimages));
+    }
+}
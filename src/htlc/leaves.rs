@@ -0,0 +1,355 @@
+//! Leaves a contract can carry besides redeem and refund: a 2-of-3 multisig for an arbiter to
+//! settle disputes, and a recovery to a cold key long after the refund. Neither is a covenant,
+//! they are plain signature checks for when the covenant paths can't be used.
+//!
+//! The tree is the Huffman tree of how often each leaf is expected to be spent. That minimizes
+//! the expected control block, so the redeem keeps the shortest witness and the leaves that are
+//! hardly ever used go deepest. With only redeem and refund it is the tree of two leaves at
+//! depth 1 the contracts always had.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bitcoin::key::Secp256k1;
+use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGADD, OP_CSV, OP_DROP, OP_NUMEQUAL};
+use bitcoin::script::Builder;
+use bitcoin::taproot::{LeafVersion, Signature, TaprootSpendInfo};
+use bitcoin::{ScriptBuf, Witness, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+
+/// how often each leaf is expected to be spent, in percent
+pub(crate) const REDEEM_WEIGHT: u32 = 60;
+pub(crate) const REFUND_WEIGHT: u32 = 30;
+pub(crate) const ARBITER_WEIGHT: u32 = 8;
+pub(crate) const RECOVERY_WEIGHT: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "leaf", rename_all = "snake_case")]
+pub(crate) enum ExtraLeaf {
+    /// any two of the funder, the redeemer and the arbiter
+    Arbiter { keys: [XOnlyPublicKey; 3] },
+    /// the cold key alone, `lock` blocks after funding
+    Recovery { cold_key: XOnlyPublicKey, lock: u16 },
+}
+
+impl ExtraLeaf {
+    pub(crate) fn script(&self) -> ScriptBuf {
+        match self {
+            ExtraLeaf::Arbiter { keys } => Builder::new()
+                .push_x_only_key(&keys[0])
+                .push_opcode(OP_CHECKSIG)
+                .push_x_only_key(&keys[1])
+                .push_opcode(OP_CHECKSIGADD)
+                .push_x_only_key(&keys[2])
+                .push_opcode(OP_CHECKSIGADD)
+                .push_int(2)
+                .push_opcode(OP_NUMEQUAL)
+                .into_script(),
+            ExtraLeaf::Recovery { cold_key, lock } => Builder::new()
+                .push_int(*lock as i64)
+                .push_opcode(OP_CSV)
+                .push_opcode(OP_DROP)
+                .push_x_only_key(cold_key)
+                .push_opcode(OP_CHECKSIG)
+                .into_script(),
+        }
+    }
+
+    pub(crate) fn weight(&self) -> u32 {
+        match self {
+            ExtraLeaf::Arbiter { .. } => ARBITER_WEIGHT,
+            ExtraLeaf::Recovery { .. } => RECOVERY_WEIGHT,
+        }
+    }
+
+    /// fails for a recovery leaf unlocking no later than the refund, the cold key could take the
+    /// coins before the funder gets them back
+    pub(crate) fn check_refund_lock(&self, refund_lock: i64) -> Result<()> {
+        match self {
+            ExtraLeaf::Recovery { lock, .. } if *lock as i64 <= refund_lock => Err(anyhow!(
+                "the recovery leaf unlocks after {} blocks, it has to wait longer than the refund's {}",
+                lock,
+                refund_lock
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// the script path witness, with one signature per key of the leaf in order, `None` for the
+    /// key that doesn't sign
+    pub(crate) fn witness(
+        &self,
+        spend_info: &TaprootSpendInfo,
+        signatures: &[Option<Signature>],
+    ) -> Result<Witness> {
+        let script = self.script();
+        let control_block = spend_info
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .ok_or(anyhow!("the leaf is not in the tree"))?;
+        let mut witness = Witness::new();
+        match self {
+            ExtraLeaf::Arbiter { .. } => {
+                if signatures.len() != 3 || signatures.iter().flatten().count() < 2 {
+                    return Err(anyhow!("the arbiter leaf needs 2 of its 3 signatures"));
+                }
+                // the first key checks the top of the stack
+                for signature in signatures.iter().rev() {
+                    match signature {
+                        Some(signature) => witness.push(signature.to_vec()),
+                        None => witness.push([]),
+                    }
+                }
+            }
+            ExtraLeaf::Recovery { .. } => match signatures {
+                [Some(signature)] => witness.push(signature.to_vec()),
+                _ => return Err(anyhow!("the recovery leaf needs the cold key's signature")),
+            },
+        }
+        witness.push(script);
+        witness.push(control_block.serialize());
+        Ok(witness)
+    }
+}
+
+/// `arbiter=<key>:<key>:<key>` and `recovery=<key>:<blocks>` in descriptors
+impl fmt::Display for ExtraLeaf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtraLeaf::Arbiter { keys } => {
+                write!(f, "arbiter={}:{}:{}", keys[0], keys[1], keys[2])
+            }
+            ExtraLeaf::Recovery { cold_key, lock } => write!(f, "recovery={}:{}", cold_key, lock),
+        }
+    }
+}
+
+impl FromStr for ExtraLeaf {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s
+            .split_once('=')
+            .ok_or(anyhow!("expected arbiter=... or recovery=..."))?;
+        let parts: Vec<&str> = value.split(':').collect();
+        match (kind, parts.as_slice()) {
+            ("arbiter", [first, second, third]) => Ok(ExtraLeaf::Arbiter {
+                keys: [first.parse()?, second.parse()?, third.parse()?],
+            }),
+            ("recovery", [cold_key, lock]) => Ok(ExtraLeaf::Recovery {
+                cold_key: cold_key.parse()?,
+                lock: lock.parse()?,
+            }),
+            _ => Err(anyhow!("invalid leaf {}", s)),
+        }
+    }
+}
+
+/// the tree of `redeem`, `refund` and `extra_leaves` under `internal_key`
+pub(crate) fn spend_info(
+    redeem: ScriptBuf,
+    refund: ScriptBuf,
+    extra_leaves: &[ExtraLeaf],
+    internal_key: XOnlyPublicKey,
+) -> Result<TaprootSpendInfo> {
+    let leaves = [(REDEEM_WEIGHT, redeem), (REFUND_WEIGHT, refund)]
+        .into_iter()
+        .chain(
+            extra_leaves
+                .iter()
+                .map(|leaf| (leaf.weight(), leaf.script())),
+        );
+    Ok(TaprootSpendInfo::with_huffman_tree(
+        &Secp256k1::verification_only(),
+        internal_key,
+        leaves,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::contract::{compute_taproot_sighash, HTLC};
+    use crate::htlc::scripts::{htlc_redeem_script_with_fee, htlc_refund_script_with_fee};
+    use crate::interpreter::{verify_input, ScriptError};
+    use crate::testing::{
+        address, deposit, htlc, keypair, new_chain, FEE, HTLC_AMOUNT, PREIMAGE, REFUND_LOCK,
+    };
+    use bitcoin::absolute::LockTime;
+    use bitcoin::taproot::{TapLeafHash, TaprootBuilder};
+    use bitcoin::transaction::Version;
+    use bitcoin::{Network, Sequence, TapSighashType, Transaction, TxIn, TxOut};
+
+    const RECOVERY_LOCK: u16 = 1000;
+
+    fn key(secret: u8) -> XOnlyPublicKey {
+        keypair(secret).x_only_public_key().0
+    }
+
+    fn with_extra_leaves() -> HTLC {
+        let mut htlc = htlc(PREIMAGE);
+        htlc.extra_leaves = vec![
+            ExtraLeaf::Arbiter {
+                keys: [key(5), key(6), key(7)],
+            },
+            ExtraLeaf::Recovery {
+                cold_key: key(8),
+                lock: RECOVERY_LOCK,
+            },
+        ];
+        htlc
+    }
+
+    /// a spend of the funded `htlc` by `leaf`, signed by the `signers` of its keys
+    fn leaf_spend(
+        htlc: &HTLC,
+        leaf: &ExtraLeaf,
+        sequence: u16,
+        signers: &[Option<u8>],
+    ) -> Transaction {
+        let funded = htlc.htlc_funded_utxo.as_ref().unwrap();
+        let spend_info = htlc.taproot_spend_info_with_fee().unwrap();
+        let mut tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: funded.htlc_outpoint,
+                sequence: Sequence::from_height(sequence),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: funded.amount - FEE,
+                script_pubkey: address(4).script_pubkey(),
+            }],
+        };
+        let prevouts = [TxOut {
+            value: funded.amount,
+            script_pubkey: htlc
+                .address_with_fee(Network::Regtest)
+                .unwrap()
+                .script_pubkey(),
+        }];
+        let leaf_hash = TapLeafHash::from_script(&leaf.script(), LeafVersion::TapScript);
+        let message =
+            compute_taproot_sighash(&tx, 0, &prevouts, leaf_hash, TapSighashType::Default).unwrap();
+        let secp = Secp256k1::new();
+        let signatures: Vec<Option<Signature>> = signers
+            .iter()
+            .map(|signer| {
+                signer.map(|secret| Signature {
+                    sig: secp.sign_schnorr_no_aux_rand(&message, &keypair(secret)),
+                    hash_ty: TapSighashType::Default,
+                })
+            })
+            .collect();
+        tx.input[0].witness = leaf.witness(&spend_info, &signatures).unwrap();
+        tx
+    }
+
+    #[test]
+    fn two_leaf_tree_is_unchanged() {
+        let htlc = htlc(PREIMAGE);
+        let spend_info = htlc.taproot_spend_info_with_fee().unwrap();
+        let leaves: Vec<ScriptBuf> = spend_info
+            .script_map()
+            .keys()
+            .map(|(script, _)| script.clone())
+            .collect();
+        let two_leaves = TaprootBuilder::new()
+            .add_leaf(1, leaves[0].clone())
+            .unwrap()
+            .add_leaf(1, leaves[1].clone())
+            .unwrap()
+            .finalize(&Secp256k1::new(), spend_info.internal_key())
+            .unwrap();
+        assert_eq!(spend_info.output_key(), two_leaves.output_key());
+    }
+
+    #[test]
+    fn redeem_keeps_the_shortest_control_block() {
+        let htlc = with_extra_leaves();
+        let spend_info = htlc.taproot_spend_info_with_fee().unwrap();
+        let depth = |script: ScriptBuf| {
+            spend_info
+                .control_block(&(script, LeafVersion::TapScript))
+                .unwrap()
+                .merkle_branch
+                .len()
+        };
+        let redeem = htlc.redeem_config.as_ref().unwrap();
+        let refund = htlc.refund_config.as_ref().unwrap();
+        let redeem_script =
+            htlc_redeem_script_with_fee(htlc.redeem_address.as_ref().unwrap(), redeem);
+        let refund_script =
+            htlc_refund_script_with_fee(&refund.refund_address, &refund.refund_lock);
+        assert_eq!(depth(redeem_script), 1);
+        assert_eq!(depth(refund_script), 2);
+        for leaf in htlc.extra_leaves.iter() {
+            assert_eq!(depth(leaf.script()), 3);
+        }
+    }
+
+    #[test]
+    fn arbiter_spends_with_two_of_three() {
+        let chain = new_chain();
+        let mut htlc = with_extra_leaves();
+        deposit(&chain, &mut htlc);
+        let arbiter = htlc.extra_leaves[0].clone();
+        let prevouts = [TxOut {
+            value: HTLC_AMOUNT,
+            script_pubkey: htlc
+                .address_with_fee(Network::Regtest)
+                .unwrap()
+                .script_pubkey(),
+        }];
+
+        let tx = leaf_spend(&htlc, &arbiter, 0, &[Some(5), None, Some(7)]);
+        assert_eq!(verify_input(&tx, 0, &prevouts), Ok(()));
+        chain.test_accept(&tx).unwrap();
+
+        let wrong_key = leaf_spend(&htlc, &arbiter, 0, &[Some(5), Some(9), None]);
+        assert_eq!(
+            verify_input(&wrong_key, 0, &prevouts),
+            Err(ScriptError::SignatureFailed)
+        );
+        let spend_info = htlc.taproot_spend_info_with_fee().unwrap();
+        assert!(arbiter.witness(&spend_info, &[None, None, None]).is_err());
+    }
+
+    #[test]
+    fn recovery_waits_for_its_lock() {
+        let chain = new_chain();
+        let mut htlc = with_extra_leaves();
+        deposit(&chain, &mut htlc);
+        let recovery = htlc.extra_leaves[1].clone();
+
+        let early = leaf_spend(&htlc, &recovery, RECOVERY_LOCK - 1, &[Some(8)]);
+        assert!(chain.test_accept(&early).is_err());
+        let recovered = leaf_spend(&htlc, &recovery, RECOVERY_LOCK, &[Some(8)]);
+        assert!(chain.test_accept(&recovered).is_err());
+        chain.mine_blocks(RECOVERY_LOCK as u64);
+        chain.test_accept(&recovered).unwrap();
+    }
+
+    #[test]
+    fn recovery_has_to_outlast_the_refund() {
+        let recovery = |lock| ExtraLeaf::Recovery {
+            cold_key: key(8),
+            lock,
+        };
+        let refund_lock = REFUND_LOCK as u16;
+        assert!(recovery(refund_lock - 1)
+            .check_refund_lock(REFUND_LOCK)
+            .is_err());
+        assert!(recovery(refund_lock)
+            .check_refund_lock(REFUND_LOCK)
+            .is_err());
+        recovery(refund_lock + 1)
+            .check_refund_lock(REFUND_LOCK)
+            .unwrap();
+        let arbiter = ExtraLeaf::Arbiter {
+            keys: [key(5), key(6), key(7)],
+        };
+        arbiter.check_refund_lock(REFUND_LOCK).unwrap();
+    }
+}
@@ -1,3 +1,10 @@
+pub(crate) mod analysis;
 pub(crate) mod contract;
+pub(crate) mod descriptor;
+pub(crate) mod hashlock;
+pub(crate) mod leaves;
+pub(crate) mod psbt;
 pub(crate) mod scripts;
 pub(crate) mod signature_building;
+pub(crate) mod theft;
+pub(crate) mod verify;
@@ -0,0 +1,514 @@
+//! BIP174/BIP371 PSBTs for HTLC spends.
+//!
+//! The covenant leaves carry no signature of their own, their witness is a list of sigmsg
+//! components the script rebuilds the sighash from. Standard PSBT fields cover the leaf script,
+//! control block and prevout. The CAT witness components travel in proprietary input fields, one
+//! per component keyed by its position, so a PSBT can pass through an external wallet (e.g. to
+//! add and sign a fee input) and come back here to be finalized.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::psbt::{Psbt, PsbtSighashType};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TaprootSpendInfo};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, OutPoint, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut,
+    Witness,
+};
+
+use crate::chain::ChainBackend;
+use crate::htlc::contract::HTLC;
+use crate::interpreter;
+
+/// proprietary key prefix of all our PSBT fields
+pub(crate) const PSBT_PROPRIETARY_PREFIX: &[u8] = b"cathtlc";
+/// subtype of a CAT witness component, the key data is its position as a big endian u16
+pub(crate) const PSBT_WITNESS_COMPONENT: u8 = 0x00;
+
+/// the leaf an HTLC spend takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpendPath {
+    Redeem,
+    Refund,
+}
+
+impl HTLC {
+    /// the spend along `path` as a PSBT.
+    /// with a `fee` the `_with_fee` leaves pay it out of the HTLC value and the PSBT is complete.
+    /// without one the plain leaves are used: they sign with SIGHASH_SINGLE|ANYONECANPAY, so the
+    /// fee has to come from an input (and change output) another wallet adds before finalizing
+    pub(crate) fn create_spend_psbt(&self, path: SpendPath, fee: Option<Amount>) -> Result<Psbt> {
+        let htlc_funded = self
+            .htlc_funded_utxo
+            .as_ref()
+            .ok_or(anyhow!("the HTLC is not funded"))?;
+
+        let (tx, spend_info, sighash_type) = match (path, fee) {
+            (SpendPath::Redeem, Some(fee)) => (
+                self.create_redeem_tx_with_fee(fee)?,
+                self.taproot_spend_info_with_fee()?,
+                TapSighashType::Default,
+            ),
+            (SpendPath::Refund, Some(fee)) => (
+                self.create_refund_tx_with_fee(fee)?,
+                self.taproot_spend_info_with_fee()?,
+                TapSighashType::Default,
+            ),
+            (SpendPath::Redeem, None) => (
+                self.create_redeem_tx()?,
+                self.taproot_spend_info()?,
+                TapSighashType::SinglePlusAnyoneCanPay,
+            ),
+            (SpendPath::Refund, None) => (
+                self.create_refund_tx()?,
+                self.taproot_spend_info()?,
+                TapSighashType::SinglePlusAnyoneCanPay,
+            ),
+        };
+
+        let prevout = TxOut {
+            value: htlc_funded.amount,
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+        spend_to_psbt(tx, 0, prevout, &spend_info, sighash_type)
+    }
+}
+
+/// move the script path witness of `input_index` into PSBT fields
+pub(crate) fn spend_to_psbt(
+    mut tx: Transaction,
+    input_index: usize,
+    prevout: TxOut,
+    spend_info: &TaprootSpendInfo,
+    sighash_type: TapSighashType,
+) -> Result<Psbt> {
+    let input = tx
+        .input
+        .get_mut(input_index)
+        .ok_or(anyhow!("transaction has no input {}", input_index))?;
+    let mut elements = std::mem::take(&mut input.witness).to_vec();
+    if elements.len() < 2 {
+        return Err(anyhow!("input {} is not a script path spend", input_index));
+    }
+    let control_block = ControlBlock::decode(&elements.pop().unwrap())?;
+    let script = ScriptBuf::from_bytes(elements.pop().unwrap());
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    let psbt_input = &mut psbt.inputs[input_index];
+    psbt_input.witness_utxo = Some(prevout);
+    psbt_input.sighash_type = Some(PsbtSighashType::from(sighash_type));
+    psbt_input.tap_internal_key = Some(spend_info.internal_key());
+    psbt_input.tap_merkle_root = spend_info.merkle_root();
+    psbt_input
+        .tap_scripts
+        .insert(control_block, (script, LeafVersion::TapScript));
+    for (position, component) in elements.into_iter().enumerate() {
+        psbt_input
+            .proprietary
+            .insert(witness_component_key(position)?, component);
+    }
+    Ok(psbt)
+}
+
+/// finalize every input and extract the transaction.
+///
+/// HTLC inputs get their witness from the proprietary components, key path inputs from
+/// `tap_key_sig`, anything else has to be finalized already (e.g. by the wallet that signed it).
+/// when the PSBT knows all prevouts the result is checked with the in-crate interpreter
+pub(crate) fn finalize_spend_psbt(mut psbt: Psbt) -> Result<Transaction> {
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        let components: BTreeMap<u16, Vec<u8>> = input
+            .proprietary
+            .iter()
+            .filter(|(key, _)| {
+                key.prefix == PSBT_PROPRIETARY_PREFIX && key.subtype == PSBT_WITNESS_COMPONENT
+            })
+            .map(|(key, value)| {
+                let position: [u8; 2] = key.key.as_slice().try_into().map_err(|_| {
+                    anyhow!("input {} has a malformed witness component key", index)
+                })?;
+                Ok((u16::from_be_bytes(position), value.clone()))
+            })
+            .collect::<Result<_>>()?;
+
+        if !components.is_empty() {
+            let mut leaves = input.tap_scripts.iter();
+            let (control_block, (script, _)) = match (leaves.next(), leaves.next()) {
+                (Some(leaf), None) => leaf,
+                _ => {
+                    return Err(anyhow!(
+                        "input {} needs exactly one tap leaf script to finalize",
+                        index
+                    ))
+                }
+            };
+            if components.keys().copied().ne(0..components.len() as u16) {
+                return Err(anyhow!("input {} is missing witness components", index));
+            }
+
+            let mut witness = Witness::new();
+            for component in components.values() {
+                witness.push(component);
+            }
+            witness.push(script.as_bytes());
+            witness.push(control_block.serialize());
+            input.final_script_witness = Some(witness);
+        } else if let Some(signature) = input.tap_key_sig {
+            input.final_script_witness = Some(Witness::from_slice(&[signature.to_vec()]));
+        } else if input.final_script_witness.is_none() && input.final_script_sig.is_none() {
+            return Err(anyhow!("input {} is not signed", index));
+        }
+
+        // BIP174: the finalizer keeps only the utxo and the final scripts
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        input.tap_key_sig = None;
+        input.tap_script_sigs.clear();
+        input.tap_scripts.clear();
+        input.tap_key_origins.clear();
+        input.tap_internal_key = None;
+        input.tap_merkle_root = None;
+        input
+            .proprietary
+            .retain(|key, _| key.prefix != PSBT_PROPRIETARY_PREFIX);
+    }
+
+    let prevouts: Option<Vec<TxOut>> = psbt
+        .inputs
+        .iter()
+        .map(|input| input.witness_utxo.clone())
+        .collect();
+    let tx = psbt.extract_tx_unchecked_fee_rate();
+    if let Some(prevouts) = prevouts {
+        // the interpreter only knows taproot, other inputs are the signing wallet's business
+        for index in (0..tx.input.len()).filter(|i| prevouts[*i].script_pubkey.is_p2tr()) {
+            interpreter::verify_input(&tx, index, &prevouts)
+                .map_err(|e| anyhow!("input {} failed script validation: {}", index, e))?;
+        }
+    }
+    Ok(tx)
+}
+
+/// an unsigned transaction paying `amount` to `script_pubkey` from `inputs`, with an optional
+/// change output. Lets any wallet that signs PSBTs fund the HTLC
+pub(crate) fn funding_psbt(
+    inputs: &[(OutPoint, TxOut)],
+    script_pubkey: ScriptBuf,
+    amount: Amount,
+    change: Option<TxOut>,
+) -> Result<Psbt> {
+    let tx = Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: inputs
+            .iter()
+            .map(|(outpoint, _)| TxIn {
+                previous_output: *outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: std::iter::once(TxOut {
+            value: amount,
+            script_pubkey,
+        })
+        .chain(change)
+        .collect(),
+    };
+    let input_value: Amount = inputs.iter().map(|(_, txout)| txout.value).sum();
+    let output_value: Amount = tx.output.iter().map(|txout| txout.value).sum();
+    if output_value > input_value {
+        return Err(anyhow!(
+            "outputs ({}) exceed inputs ({})",
+            output_value,
+            input_value
+        ));
+    }
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    for (psbt_input, (_, txout)) in psbt.inputs.iter_mut().zip(inputs) {
+        psbt_input.witness_utxo = Some(txout.clone());
+    }
+    Ok(psbt)
+}
+
+/// `funding_psbt` spending the wallet's own `inputs`, whose prevouts are looked up on `chain`.
+/// whatever the inputs hold beyond `amount` and `fee` goes to `change_address`
+pub(crate) fn funding_psbt_from_chain(
+    chain: &dyn ChainBackend,
+    inputs: &[OutPoint],
+    script_pubkey: ScriptBuf,
+    amount: Amount,
+    fee: Amount,
+    change_address: Option<&Address>,
+) -> Result<Psbt> {
+    if inputs.is_empty() {
+        return Err(anyhow!("a funding PSBT needs at least one input"));
+    }
+    let inputs = inputs
+        .iter()
+        .map(|outpoint| {
+            let txout = chain
+                .get_tx(&outpoint.txid)?
+                .ok_or(anyhow!("transaction {} not found", outpoint.txid))?
+                .output
+                .get(outpoint.vout as usize)
+                .cloned()
+                .ok_or(anyhow!("{} has no output {}", outpoint.txid, outpoint.vout))?;
+            Ok((*outpoint, txout))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let input_value: Amount = inputs.iter().map(|(_, txout)| txout.value).sum();
+    let change_value = input_value.checked_sub(amount + fee).ok_or(anyhow!(
+        "inputs hold {}, less than {} and a fee of {}",
+        input_value,
+        amount,
+        fee
+    ))?;
+    let change = match change_address {
+        _ if change_value == Amount::ZERO => None,
+        Some(address) => Some(TxOut {
+            value: change_value,
+            script_pubkey: address.script_pubkey(),
+        }),
+        None => {
+            return Err(anyhow!(
+                "inputs hold {} more than the amount and fee, give a change address",
+                change_value
+            ))
+        }
+    };
+    funding_psbt(&inputs, script_pubkey, amount, change)
+}
+
+fn witness_component_key(position: usize) -> Result<bitcoin::psbt::raw::ProprietaryKey> {
+    let position = u16::try_from(position).map_err(|_| anyhow!("too many witness components"))?;
+    Ok(bitcoin::psbt::raw::ProprietaryKey {
+        prefix: PSBT_PROPRIETARY_PREFIX.to_vec(),
+        subtype: PSBT_WITNESS_COMPONENT,
+        key: position.to_be_bytes().to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use bitcoin::hashes::Hash;
+    use bitcoin::key::{Secp256k1, TapTweak};
+    use bitcoin::secp256k1::Message;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::Network;
+
+    use crate::chain::ChainBackend;
+    use crate::htlc::contract::add_fee_to_txn;
+    use crate::testing::{
+        address, deposit, htlc, keypair, new_chain, sign_key_spend, FEE, HTLC_AMOUNT, PREIMAGE,
+        REFUND_LOCK,
+    };
+
+    /// round trip through the base64 encoding an external wallet would see
+    fn round_trip(psbt: &Psbt) -> Psbt {
+        Psbt::from_str(&psbt.to_string()).unwrap()
+    }
+
+    #[test]
+    fn redeem_with_fee_round_trips() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let psbt = htlc
+            .create_spend_psbt(SpendPath::Redeem, Some(FEE))
+            .unwrap();
+        assert!(psbt.inputs[0].final_script_witness.is_none());
+        assert_eq!(psbt.inputs[0].tap_scripts.len(), 1);
+
+        let tx = finalize_spend_psbt(round_trip(&psbt)).unwrap();
+        assert_eq!(tx, htlc.create_redeem_tx_with_fee(FEE).unwrap());
+        chain.broadcast(&tx).unwrap();
+    }
+
+    #[test]
+    fn refund_with_fee_round_trips() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+        chain.mine_blocks(REFUND_LOCK as u64);
+
+        let psbt = htlc
+            .create_spend_psbt(SpendPath::Refund, Some(FEE))
+            .unwrap();
+        let tx = finalize_spend_psbt(round_trip(&psbt)).unwrap();
+        chain.broadcast(&tx).unwrap();
+    }
+
+    #[test]
+    fn external_fee_input_is_finalized() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        let htlc_outpoint = chain.fund(
+            htlc.address(Network::Regtest).unwrap().script_pubkey(),
+            HTLC_AMOUNT,
+        );
+        htlc.set_funded_htlc(htlc_outpoint, HTLC_AMOUNT);
+        let fee_outpoint = chain.fund(address(5).script_pubkey(), Amount::from_sat(10_000));
+        let fee_utxo = chain.get_utxo(&fee_outpoint).unwrap();
+
+        let psbt = htlc.create_spend_psbt(SpendPath::Redeem, None).unwrap();
+
+        // what the fee wallet does: add its input and change, then sign only its own input
+        let mut psbt = round_trip(&psbt);
+        let mut tx = psbt.unsigned_tx.clone();
+        add_fee_to_txn(&mut tx, fee_outpoint, fee_utxo.value, FEE, address(5)).unwrap();
+        let prevouts = [
+            psbt.inputs[0].witness_utxo.clone().unwrap(),
+            fee_utxo.clone(),
+        ];
+        let sighash = SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(1, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .unwrap();
+        let secp = Secp256k1::new();
+        let tweaked = keypair(5).tap_tweak(&secp, None).to_inner();
+        let signature =
+            secp.sign_schnorr_no_aux_rand(&Message::from_digest(sighash.to_byte_array()), &tweaked);
+        psbt.unsigned_tx = tx;
+        psbt.inputs.push(bitcoin::psbt::Input {
+            witness_utxo: Some(fee_utxo),
+            tap_key_sig: Some(bitcoin::taproot::Signature {
+                sig: signature,
+                hash_ty: TapSighashType::Default,
+            }),
+            ..Default::default()
+        });
+        psbt.outputs.push(Default::default());
+
+        let tx = finalize_spend_psbt(round_trip(&psbt)).unwrap();
+        assert_eq!(tx.input.len(), 2);
+        let txid = chain.broadcast(&tx).unwrap();
+        let output = chain.get_utxo(&OutPoint::new(txid, 0)).unwrap();
+        assert_eq!(output.value, HTLC_AMOUNT);
+    }
+
+    #[test]
+    fn unsigned_inputs_are_rejected() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let mut psbt = htlc
+            .create_spend_psbt(SpendPath::Redeem, Some(FEE))
+            .unwrap();
+        psbt.inputs[0].proprietary.clear();
+        let err = finalize_spend_psbt(psbt).unwrap_err();
+        assert!(err.to_string().contains("not signed"), "{}", err);
+    }
+
+    #[test]
+    fn tampered_outputs_fail_validation() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+
+        let mut psbt = htlc
+            .create_spend_psbt(SpendPath::Redeem, Some(FEE))
+            .unwrap();
+        psbt.unsigned_tx.output[0].script_pubkey = address(4).script_pubkey();
+        let err = finalize_spend_psbt(psbt).unwrap_err();
+        assert!(
+            err.to_string().contains("failed script validation"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn funding_psbt_from_chain_is_signed_by_the_input_owner() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        // the funder's own coins, nothing the tool holds keys for
+        let funder_outpoint = chain.fund(address(1).script_pubkey(), HTLC_AMOUNT * 2);
+        let funder_utxo = chain.get_utxo(&funder_outpoint).unwrap();
+        let htlc_script_pubkey = htlc
+            .address_with_fee(Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+
+        let err = funding_psbt_from_chain(
+            &chain,
+            &[funder_outpoint],
+            htlc_script_pubkey.clone(),
+            HTLC_AMOUNT,
+            FEE,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("change address"), "{}", err);
+        let mut psbt = funding_psbt_from_chain(
+            &chain,
+            &[funder_outpoint],
+            htlc_script_pubkey,
+            HTLC_AMOUNT,
+            FEE,
+            Some(&address(1)),
+        )
+        .unwrap();
+        assert_eq!(psbt.inputs[0].witness_utxo, Some(funder_utxo.clone()));
+        assert_eq!(psbt.unsigned_tx.output[1].value, HTLC_AMOUNT - FEE);
+
+        // what the funder's wallet does with the PSBT
+        let mut psbt_from_wallet = round_trip(&psbt);
+        let mut tx = psbt_from_wallet.unsigned_tx.clone();
+        sign_key_spend(&mut tx, 0, &[funder_utxo], 1);
+        psbt_from_wallet.inputs[0].final_script_witness = Some(tx.input[0].witness.clone());
+        psbt = round_trip(&psbt_from_wallet);
+
+        let tx = finalize_spend_psbt(psbt).unwrap();
+        chain.broadcast(&tx).unwrap();
+        chain.mine_blocks(1);
+        htlc.record_funding(&tx).unwrap();
+        chain
+            .broadcast(&htlc.create_redeem_tx_with_fee(FEE).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn offline_funding_psbt_is_recorded() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        let wallet_outpoint = chain.fund(address(1).script_pubkey(), HTLC_AMOUNT + FEE);
+        let wallet_utxo = chain.get_utxo(&wallet_outpoint).unwrap();
+        let htlc_script_pubkey = htlc
+            .address_with_fee(Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+
+        let mut psbt = funding_psbt(
+            &[(wallet_outpoint, wallet_utxo.clone())],
+            htlc_script_pubkey,
+            HTLC_AMOUNT,
+            None,
+        )
+        .unwrap();
+        let mut tx = psbt.unsigned_tx.clone();
+        sign_key_spend(&mut tx, 0, &[wallet_utxo], 1);
+        psbt.inputs[0].final_script_witness = Some(tx.input[0].witness.clone());
+
+        let tx = finalize_spend_psbt(round_trip(&psbt)).unwrap();
+        chain.broadcast(&tx).unwrap();
+        chain.mine_blocks(1);
+        let outpoint = htlc.record_funding(&tx).unwrap();
+        assert_eq!(outpoint, OutPoint::new(tx.txid(), 0));
+
+        let redeem_tx = htlc.create_redeem_tx_with_fee(FEE).unwrap();
+        chain.broadcast(&redeem_tx).unwrap();
+    }
+}
@@ -1,336 +1,340 @@
-use crate::htlc::signature_building::{BIP0340_CHALLENGE_TAG, DUST_AMOUNT, G_X, TAPSIGHASH_TAG};
+//! The covenant leaves, put together from fragments with `CovenantBuilder`.
+//!
+//! Every leaf rebuilds the sigmsg of its spend from witness pieces and constants, with the
+//! outputs it allows committed in the script, and checks it with the G signature trick. The
+//! leaves only differ in their guards and in how the sigmsg is put together.
+
+use crate::htlc::contract::RedeemConfig;
+use crate::htlc::signature_building::{BIP0340_CHALLENGE_TAG, G_X, TAPSIGHASH_TAG};
+use bitcoin::blockdata::script::PushBytesBuf;
+use bitcoin::consensus::encode::serialize;
 use bitcoin::opcodes::all::{
-    OP_2DUP, OP_CAT, OP_CHECKSIG, OP_CSV, OP_DROP, OP_DUP, OP_EQUALVERIFY, OP_FROMALTSTACK,
-    OP_HASH256, OP_ROT, OP_SHA256, OP_SWAP, OP_TOALTSTACK, OP_CHECKSIGVERIFY
+    OP_CAT, OP_CHECKSIG, OP_CSV, OP_DROP, OP_DUP, OP_EQUALVERIFY, OP_FROMALTSTACK, OP_ROT,
+    OP_SHA256, OP_SIZE, OP_SWAP, OP_TOALTSTACK,
 };
 use bitcoin::script::Builder;
-use bitcoin::{Address, Script, ScriptBuf, Sequence, XOnlyPublicKey};
-use bitcoin::blockdata::script::PushBytesBuf;
+use bitcoin::{Address, Script, ScriptBuf};
 
-pub(crate) fn htlc_redeem_script(redeem_address:&Address,payment_hash:&str) -> ScriptBuf {
-    let mut builder = Script::builder();
-    builder = builder
-    .push_opcode(OP_SHA256)
-    .push_slice(PushBytesBuf::try_from(hex::decode(payment_hash).expect("Invalid secret hash hex")).unwrap())
-    .push_opcode(OP_EQUALVERIFY)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK) 
-    .push_slice(PushBytesBuf::try_from(hex::decode("0083020000000000000002").expect("Invalid secret hash hex")).unwrap())
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_DUP) 
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_FROMALTSTACK)
-    // Push [length, script_pubkey_bytes...]
-        .push_slice({
-            let script_pubkey = redeem_address.script_pubkey(); // Store Script
-            let script_bytes = script_pubkey.as_bytes(); // Borrow
-            let length = script_bytes.len() as u8;
-            if script_bytes.len() > 255 {
-                panic!("ScriptPubKey too long: {} bytes", script_bytes.len());
-            }
-            let mut bytes_with_length = vec![length];
-            bytes_with_length.extend_from_slice(script_bytes);
-            PushBytesBuf::try_from(bytes_with_length).expect("Invalid scriptPubKey bytes")
-        })
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SHA256)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_slice(*TAPSIGHASH_TAG) // push tag
-        .push_opcode(OP_SHA256) // hash tag
-        .push_opcode(OP_DUP) // dup hash
-        .push_opcode(OP_ROT) // move the sighash to the top of the stack
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SHA256) 
-        .push_slice(*BIP0340_CHALLENGE_TAG) 
-        .push_opcode(OP_SHA256)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_ROT) // bring challenge to the top of the stack
-        .push_slice(*G_X) // G is used for the pubkey and K
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_SHA256) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_SWAP)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_ROT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_DUP) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_EQUALVERIFY) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_CHECKSIG);
-    let script = builder.into_script();
-    // println!("script: {:?}", script);
-    script
+/// epoch, SINGLE|ANYONECANPAY, version 2, lock time 0 and a script path spend, the sigmsg fields
+/// before the input that every SINGLE|ANYONECANPAY leaf has in common
+pub(crate) const SINGLE_ACP_SIGMSG_PREFIX: [u8; 11] = [
+    0x00, 0x83, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+];
+
+/// a covenant leaf, fragment by fragment. The guards come first, then the sigmsg is rebuilt with
+/// the signature pieces stashed on the altstack, and `cat_checksig` ends the leaf
+#[derive(Debug, Default)]
+pub(crate) struct CovenantBuilder(Builder);
+
+impl From<Builder> for CovenantBuilder {
+    fn from(builder: Builder) -> Self {
+        CovenantBuilder(builder)
+    }
 }
 
-pub(crate) fn htlc_refund_script(refund_address:&Address, lock_time: &i64) -> ScriptBuf {
-    let mut builder = Script::builder();
-    builder = builder
-        .push_int(*lock_time)
-        .push_opcode(OP_CSV) 
-        .push_opcode(OP_DROP) 
-        .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK) 
-    .push_slice(PushBytesBuf::try_from(hex::decode("0083020000000000000002").expect("Invalid secret hash hex")).unwrap())
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_DUP) 
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_FROMALTSTACK)
-    // Push [length, script_pubkey_bytes...]
-        .push_slice({
-            let script_pubkey = refund_address.script_pubkey(); // Store Script
-            let script_bytes = script_pubkey.as_bytes(); // Borrow
-            let length = script_bytes.len() as u8;
-            if script_bytes.len() > 255 {
-                panic!("ScriptPubKey too long: {} bytes", script_bytes.len());
+impl CovenantBuilder {
+    pub(crate) fn new() -> Self {
+        CovenantBuilder(Builder::new())
+    }
+
+    /// the script so far, to add opcodes specific to one contract
+    pub(crate) fn into_builder(self) -> Builder {
+        self.0
+    }
+
+    pub(crate) fn into_script(self) -> ScriptBuf {
+        self.0.into_script()
+    }
+
+    fn map(self, f: impl FnOnce(Builder) -> Builder) -> Self {
+        CovenantBuilder(f(self.0))
+    }
+
+    /// concatenate the `n + 1` elements on top of the stack
+    fn cat(self, n: usize) -> Self {
+        self.map(|builder| (0..n).fold(builder, |builder, _| builder.push_opcode(OP_CAT)))
+    }
+
+    /// the preimages on top of the stack must hash to the payment hashes in order, they are
+    /// consumed
+    pub(crate) fn hashlock_guard(self, redeem_config: &RedeemConfig) -> Self {
+        let mut builder = self.0;
+        for hashlock in redeem_config.hashlocks() {
+            if redeem_config.size_guard() {
+                builder = builder
+                    .push_opcode(OP_SIZE)
+                    .push_int(32)
+                    .push_opcode(OP_EQUALVERIFY);
             }
-            let mut bytes_with_length = vec![length];
-            bytes_with_length.extend_from_slice(script_bytes);
-            PushBytesBuf::try_from(bytes_with_length).expect("Invalid scriptPubKey bytes")
+            builder = builder
+                .push_opcode(redeem_config.hash_lock().opcode())
+                .push_slice(
+                    PushBytesBuf::try_from(hashlock.payment_hash.as_bytes().to_vec())
+                        .expect("payment hashes are at most 32 bytes"),
+                )
+                .push_opcode(OP_EQUALVERIFY);
+        }
+        CovenantBuilder(builder)
+    }
+
+    /// the input must wait `blocks` blocks after its output confirmed
+    pub(crate) fn csv_guard(self, blocks: i64) -> Self {
+        self.map(|builder| {
+            builder
+                .push_int(blocks)
+                .push_opcode(OP_CSV)
+                .push_opcode(OP_DROP)
         })
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SHA256)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_slice(*TAPSIGHASH_TAG) // push tag
-        .push_opcode(OP_SHA256) // hash tag
-        .push_opcode(OP_DUP) // dup hash
-        .push_opcode(OP_ROT) // move the sighash to the top of the stack
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SHA256) 
-        .push_slice(*BIP0340_CHALLENGE_TAG) 
-        .push_opcode(OP_SHA256)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_ROT) // bring challenge to the top of the stack
-        .push_slice(*G_X) // G is used for the pubkey and K
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_SHA256) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_SWAP)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_ROT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_DUP) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_EQUALVERIFY) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_CHECKSIG);
-    let script = builder.into_script();
-    // println!("script: {:?}", script);
-    script
-}
+    }
 
-pub(crate) fn htlc_redeem_script_with_fee(reedeem_address:&Address,payment_hash:&str) -> ScriptBuf {
-      let mut builder = Script::builder();
-    builder = builder
-    .push_opcode(OP_SHA256)
-    .push_slice(PushBytesBuf::try_from(hex::decode(payment_hash).expect("Invalid secret hash hex")).unwrap())
-    .push_opcode(OP_EQUALVERIFY)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK) 
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    // Push [length, script_pubkey_bytes...]
-        .push_slice({
-            let script_pubkey = reedeem_address.script_pubkey(); // Store Script
-            let script_bytes = script_pubkey.as_bytes(); // Borrow
-            let length = script_bytes.len() as u8;
-            if script_bytes.len() > 255 {
-                panic!("ScriptPubKey too long: {} bytes", script_bytes.len());
-            }
-            let mut bytes_with_length = vec![length];
-            bytes_with_length.extend_from_slice(script_bytes);
-            PushBytesBuf::try_from(bytes_with_length).expect("Invalid scriptPubKey bytes")
+    /// move the signature pieces `[b+1] [b] sig[0..63]` on top of the stack to the altstack
+    pub(crate) fn stash_signature(self) -> Self {
+        self.map(|builder| {
+            builder
+                .push_opcode(OP_TOALTSTACK)
+                .push_opcode(OP_TOALTSTACK)
+                .push_opcode(OP_TOALTSTACK)
         })
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SHA256)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_slice(*TAPSIGHASH_TAG) // push tag
-        .push_opcode(OP_SHA256) // hash tag
-        .push_opcode(OP_DUP) // dup hash
-        .push_opcode(OP_ROT) // move the sighash to the top of the stack
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SHA256) 
-        .push_slice(*BIP0340_CHALLENGE_TAG) 
-        .push_opcode(OP_SHA256)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_ROT) // bring challenge to the top of the stack
-        .push_slice(*G_X) // G is used for the pubkey and K
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_SHA256) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_SWAP)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_ROT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_DUP) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_EQUALVERIFY) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_CHECKSIG);
-    let script = builder.into_script();
-    // println!("script: {:?}", script);
-    script
-}
+    }
 
-pub(crate) fn htlc_refund_script_with_fee(refund_address:&Address, lock_time: &i64) -> ScriptBuf {
-    let mut builder = Script::builder();
-    builder = builder
-    .push_int(*lock_time)
-    .push_opcode(OP_CSV) 
-    .push_opcode(OP_DROP)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK)
-    .push_opcode(OP_TOALTSTACK) 
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SWAP)
-    // Push [length, script_pubkey_bytes...]
-        .push_slice({
-            let script_pubkey = refund_address.script_pubkey(); // Store Script
-            let script_bytes = script_pubkey.as_bytes(); // Borrow
-            let length = script_bytes.len() as u8;
-            if script_bytes.len() > 255 {
-                panic!("ScriptPubKey too long: {} bytes", script_bytes.len());
-            }
-            let mut bytes_with_length = vec![length];
-            bytes_with_length.extend_from_slice(script_bytes);
-            PushBytesBuf::try_from(bytes_with_length).expect("Invalid scriptPubKey bytes")
+    /// hash the output value on top of the stack with `script_pubkey`, the sha_single_output or
+    /// sha_outputs of a transaction with that one output
+    pub(crate) fn output_commitment(self, script_pubkey: &Script) -> Self {
+        let txout_script =
+            PushBytesBuf::try_from(serialize(script_pubkey)).expect("script pubkeys are short");
+        self.map(|builder| builder.push_slice(txout_script))
+            .cat(1)
+            .map(|builder| builder.push_opcode(OP_SHA256))
+    }
+
+    /// the SIGHASH_SINGLE|ANYONECANPAY sigmsg of a spend paying the whole input to
+    /// `script_pubkey`, from the witness `prevout amount spent_script_pubkey||sequence
+    /// leaf||keyver||codesep`, top first
+    pub(crate) fn single_acp_sigmsg(self, script_pubkey: &Script) -> Self {
+        self.map(|builder| {
+            builder
+                .push_slice(SINGLE_ACP_SIGMSG_PREFIX)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_TOALTSTACK)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_FROMALTSTACK)
         })
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_SHA256)
-    .push_opcode(OP_SWAP)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_opcode(OP_CAT)
-    .push_slice(*TAPSIGHASH_TAG) // push tag
-        .push_opcode(OP_SHA256) // hash tag
-        .push_opcode(OP_DUP) // dup hash
-        .push_opcode(OP_ROT) // move the sighash to the top of the stack
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SHA256) 
-        .push_slice(*BIP0340_CHALLENGE_TAG) 
-        .push_opcode(OP_SHA256)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_ROT) // bring challenge to the top of the stack
-        .push_slice(*G_X) // G is used for the pubkey and K
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_DUP)
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_TOALTSTACK) 
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_SHA256) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_SWAP)
-        .push_opcode(OP_CAT) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_ROT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_DUP) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_ROT) 
-        .push_opcode(OP_EQUALVERIFY) 
-        .push_opcode(OP_FROMALTSTACK) 
-        .push_opcode(OP_CAT)
-        .push_opcode(OP_SWAP) 
-        .push_opcode(OP_CHECKSIG);
-    let script = builder.into_script();
-  
-  // println!("script: {:?}", script);
-  script
+        .output_commitment(script_pubkey)
+        .map(|builder| {
+            builder
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_CAT)
+        })
+    }
+
+    /// the SIGHASH_DEFAULT sigmsg of a spend with a single output to `script_pubkey`, the fee
+    /// coming out of its value, from the sigmsg pieces on the witness
+    pub(crate) fn default_sigmsg(self, script_pubkey: &Script) -> Self {
+        self.cat(4)
+            .map(|builder| builder.push_opcode(OP_SWAP))
+            .output_commitment(script_pubkey)
+            .map(|builder| builder.push_opcode(OP_SWAP))
+            .cat(9)
+    }
+
+    /// the BIP340 tagged hash with `tag` of the top of the stack
+    pub(crate) fn tagged_hash(self, tag: &[u8]) -> Self {
+        let tag = PushBytesBuf::try_from(tag.to_vec()).expect("tags are short");
+        self.map(|builder| {
+            builder
+                .push_slice(tag)
+                .push_opcode(OP_SHA256)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_ROT)
+        })
+        .cat(2)
+        .map(|builder| builder.push_opcode(OP_SHA256))
+    }
+
+    /// turn the sigmsg on top of the stack into the BIP340 challenge and check it with the G
+    /// signature trick, the signature pieces waiting on the altstack
+    pub(crate) fn cat_checksig(self) -> Self {
+        self.tagged_hash(TAPSIGHASH_TAG.as_slice()).map(|builder| {
+            builder
+                .push_slice(*BIP0340_CHALLENGE_TAG)
+                .push_opcode(OP_SHA256)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_ROT) // bring challenge to the top of the stack
+                .push_slice(*G_X) // G is used for the pubkey and K
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_TOALTSTACK)
+                .push_opcode(OP_TOALTSTACK)
+                .push_opcode(OP_ROT)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_SHA256)
+                .push_opcode(OP_FROMALTSTACK)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_FROMALTSTACK)
+                .push_opcode(OP_FROMALTSTACK)
+                .push_opcode(OP_ROT)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_FROMALTSTACK)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_ROT)
+                .push_opcode(OP_EQUALVERIFY)
+                .push_opcode(OP_FROMALTSTACK)
+                .push_opcode(OP_CAT)
+                .push_opcode(OP_SWAP)
+                .push_opcode(OP_CHECKSIG)
+        })
+    }
 }
 
+pub(crate) fn htlc_redeem_script(
+    redeem_address: &Address,
+    redeem_config: &RedeemConfig,
+) -> ScriptBuf {
+    CovenantBuilder::new()
+        .hashlock_guard(redeem_config)
+        .stash_signature()
+        .single_acp_sigmsg(&redeem_address.script_pubkey())
+        .cat_checksig()
+        .into_script()
+}
 
+pub(crate) fn htlc_refund_script(refund_address: &Address, lock_time: &i64) -> ScriptBuf {
+    CovenantBuilder::new()
+        .csv_guard(*lock_time)
+        .stash_signature()
+        .single_acp_sigmsg(&refund_address.script_pubkey())
+        .cat_checksig()
+        .into_script()
+}
 
+pub(crate) fn htlc_redeem_script_with_fee(
+    redeem_address: &Address,
+    redeem_config: &RedeemConfig,
+) -> ScriptBuf {
+    CovenantBuilder::new()
+        .hashlock_guard(redeem_config)
+        .stash_signature()
+        .default_sigmsg(&redeem_address.script_pubkey())
+        .cat_checksig()
+        .into_script()
+}
+
+pub(crate) fn htlc_refund_script_with_fee(refund_address: &Address, lock_time: &i64) -> ScriptBuf {
+    CovenantBuilder::new()
+        .csv_guard(*lock_time)
+        .stash_signature()
+        .default_sigmsg(&refund_address.script_pubkey())
+        .cat_checksig()
+        .into_script()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::hashlock::HashLock;
+    use crate::testing::{PREIMAGE, htlc};
+
+    // the scripts before they were assembled from fragments, for `htlc(PREIMAGE)`
+    const REDEEM: &str = "\
+        a8207d71c056feba9afeb8ee135b8c83695b1ecf948a96d24494592a5743c6779a57886b6b6b0b00830200000000\
+        000000027c7e7c766b7e7c7e6c23225120a674c2b152a383126b1e2a3a0683eb07a4d6568983a73dfb26dec2a7a9\
+        fb06437ea87e7c7e0a54617053696768617368a8767b7e7ea811424950303334302f6368616c6c656e6765a8767b\
+        2079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f817987676766b6b7b7e7e7e7ea86c7c\
+        7e6c6c7b7c766c7e7b886c7e7cac";
+    const REFUND: &str = "\
+        0114b2756b6b6b0b00830200000000000000027c7e7c766b7e7c7e6c23225120d3a0d82bbfae272329e54e3b3410\
+        efc98646eaf06b7b6bf7cadceee05ba1447e7ea87e7c7e0a54617053696768617368a8767b7e7ea8114249503033\
+        34302f6368616c6c656e6765a8767b2079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8\
+        17987676766b6b7b7e7e7e7ea86c7c7e6c6c7b7c766c7e7b886c7e7cac";
+    const REDEEM_WITH_FEE: &str = "\
+        a8207d71c056feba9afeb8ee135b8c83695b1ecf948a96d24494592a5743c6779a57886b6b6b7e7e7e7e7c232251\
+        20a674c2b152a383126b1e2a3a0683eb07a4d6568983a73dfb26dec2a7a9fb06437ea87c7e7e7e7e7e7e7e7e7e0a\
+        54617053696768617368a8767b7e7ea811424950303334302f6368616c6c656e6765a8767b2079be667ef9dcbbac\
+        55a06295ce870b07029bfcdb2dce28d959f2815b16f817987676766b6b7b7e7e7e7ea86c7c7e6c6c7b7c766c7e7b\
+        886c7e7cac";
+    const REFUND_WITH_FEE: &str = "\
+        0114b2756b6b6b7e7e7e7e7c23225120d3a0d82bbfae272329e54e3b3410efc98646eaf06b7b6bf7cadceee05ba1\
+        447e7ea87c7e7e7e7e7e7e7e7e7e0a54617053696768617368a8767b7e7ea811424950303334302f6368616c6c65\
+        6e6765a8767b2079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f817987676766b6b7b7e\
+        7e7e7ea86c7c7e6c6c7b7c766c7e7b886c7e7cac";
+    // two HASH160 locks with the size guard
+    const MULTI_REDEEM_WITH_FEE: &str = "\
+        82012088a91401010101010101010101010101010101010101018882012088a91402020202020202020202020202\
+        02020202020202886b6b6b7e7e7e7e7c23225120a674c2b152a383126b1e2a3a0683eb07a4d6568983a73dfb26de\
+        c2a7a9fb06437ea87c7e7e7e7e7e7e7e7e7e0a54617053696768617368a8767b7e7ea811424950303334302f6368\
+        616c6c656e6765a8767b2079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798767676\
+        6b6b7b7e7e7e7ea86c7c7e6c6c7b7c766c7e7b886c7e7cac";
+
+    #[test]
+    fn leaves_are_byte_exact() {
+        let htlc = htlc(PREIMAGE);
+        let redeem_address = htlc.redeem_address.as_ref().unwrap();
+        let redeem_config = htlc.redeem_config.as_ref().unwrap();
+        let refund = htlc.refund_config.as_ref().unwrap();
+        for (script, expected) in [
+            (htlc_redeem_script(redeem_address, redeem_config), REDEEM),
+            (
+                htlc_refund_script(&refund.refund_address, &refund.refund_lock),
+                REFUND,
+            ),
+            (
+                htlc_redeem_script_with_fee(redeem_address, redeem_config),
+                REDEEM_WITH_FEE,
+            ),
+            (
+                htlc_refund_script_with_fee(&refund.refund_address, &refund.refund_lock),
+                REFUND_WITH_FEE,
+            ),
+        ] {
+            assert_eq!(hex::encode(script.as_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn hashlock_guard_is_byte_exact() {
+        let htlc = htlc(PREIMAGE);
+        let payment_hashes = [[1u8; 20], [2u8; 20]]
+            .iter()
+            .map(|hash| hex::encode(hash).parse().unwrap())
+            .collect();
+        let mut redeem_config =
+            RedeemConfig::with_hash_locks(HashLock::Hash160, payment_hashes).unwrap();
+        redeem_config.set_size_guard(true);
+        let script =
+            htlc_redeem_script_with_fee(htlc.redeem_address.as_ref().unwrap(), &redeem_config);
+        assert_eq!(hex::encode(script.as_bytes()), MULTI_REDEEM_WITH_FEE);
+    }
+
+    #[test]
+    fn fragments_compose_the_leaves() {
+        let htlc = htlc(PREIMAGE);
+        let refund = htlc.refund_config.as_ref().unwrap();
+        let script_pubkey = refund.refund_address.script_pubkey();
+        // the refund is the pay-to leaf of the vaults behind a CSV guard
+        let pay_to = CovenantBuilder::new()
+            .stash_signature()
+            .single_acp_sigmsg(&script_pubkey)
+            .cat_checksig()
+            .into_script();
+        let guard = CovenantBuilder::new()
+            .csv_guard(refund.refund_lock)
+            .into_script();
+        let refund_script = htlc_refund_script(&refund.refund_address, &refund.refund_lock);
+        assert_eq!(
+            refund_script.as_bytes(),
+            [guard.as_bytes(), pay_to.as_bytes()].concat()
+        );
+    }
+}
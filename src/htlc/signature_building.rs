@@ -275,16 +275,14 @@ pub(crate) fn get_sigmsg_components<S: Into<TapLeafHash>>(
     // If an annex is present (the lowest bit of spend_type is set):
     //      sha_annex (32): the SHA256 of (compact_size(size of annex) || annex), where annex
     //      includes the mandatory 0x50 prefix.
-    if spec.annex {
-        if let Some(annex) = annex {
-            let mut encoded_annex = Vec::new();
-            let mut enc = sha256::Hash::engine();
-            annex.consensus_encode(&mut enc)?;
-            let hash = sha256::Hash::from_engine(enc);
-            hash.consensus_encode(&mut encoded_annex)?;
-            debug!("annex: {:?}", encoded_annex.to_hex_string(Case::Lower));
-            components.push(encoded_annex);
-        }
+    if let (true, Some(annex)) = (spec.annex, annex) {
+        let mut encoded_annex = Vec::new();
+        let mut enc = sha256::Hash::engine();
+        annex.consensus_encode(&mut enc)?;
+        let hash = sha256::Hash::from_engine(enc);
+        hash.consensus_encode(&mut encoded_annex)?;
+        debug!("annex: {:?}", encoded_annex.to_hex_string(Case::Lower));
+        components.push(encoded_annex);
     }
 
     // * Data about this output:
@@ -420,6 +418,9 @@ pub(crate) struct ContractComponents {
 pub(crate) enum GrindField {
     LockTime,
     Sequence,
+    /// bump the relative height lock of the first input. Keeps BIP68 enabled, so this only ever
+    /// delays the spend by `counter` blocks
+    RelativeHeight,
 }
 
 pub(crate) fn grind_transaction<S>(
@@ -427,6 +428,7 @@ pub(crate) fn grind_transaction<S>(
     grind_field: GrindField,
     prevouts: &[TxOut],
     leaf_hash: S,
+    sighash_type: TapSighashType,
 ) -> anyhow::Result<ContractComponents>
 where
     S: Into<TapLeafHash> + Clone,
@@ -435,6 +437,7 @@ where
     let mut counter = 0;
 
     let mut spend_tx = initial_tx.clone();
+    let base_height = initial_tx.input[0].sequence.to_consensus_u32();
 
     loop {
         match grind_field {
@@ -446,6 +449,9 @@ where
                 // set the sequence number of the last input to the counter, we'll use that to pay fees if there is more than one input
                 spend_tx.input.last_mut().unwrap().sequence = Sequence::from_consensus(counter);
             }
+            GrindField::RelativeHeight => {
+                spend_tx.input[0].sequence = Sequence::from_consensus(base_height + counter)
+            }
         }
         debug!("grinding counter {}", counter);
 
@@ -456,7 +462,7 @@ where
             prevouts,
             None,
             leaf_hash.clone(),
-            TapSighashType::Default,
+            sighash_type,
         )?;
         let sigmsg = compute_sigmsg_from_components(&components_for_signature)?;
         let challenge = compute_challenge(&sigmsg);
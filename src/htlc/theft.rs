@@ -0,0 +1,249 @@
+//! Spends of a funded HTLC that break its rules, to show the covenant rejects each of them.
+//!
+//! Every attempt starts from a transaction the contract would build for the honest party and
+//! changes what an attacker would change. The redeem is the interesting base once the preimage is
+//! known, an attacker learns it from the redeem sitting in the mempool. Without the preimage the
+//! refund is used instead. Each attempt is made against both trees, the plain leaves signing
+//! SINGLE|ANYONECANPAY and the leaves paying their fee from the HTLC. The attempts are checked
+//! with the in-crate interpreter, which needs no node and reports the failing opcode.
+
+use anyhow::{anyhow, Result};
+use bitcoin::{Address, Amount, Network, Sequence, Transaction, TxOut};
+
+use crate::htlc::contract::HTLC;
+use crate::interpreter::{self, ScriptError};
+use crate::secret;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum TheftAttempt {
+    /// pay the HTLC to the attacker instead of the redeem or refund address
+    AttackerAddress,
+    /// redeem with preimages that don't hash to the payment hashes
+    WrongPreimage,
+    /// refund before the relative lock expired. Only the sequence is rewritten, which the CSV in
+    /// the refund leaf rejects; a refund keeping its sequence is script valid and only held back
+    /// by the BIP68 check of the chain
+    EarlyRefund,
+    /// pay the HTLC's address less and the attacker the difference
+    ModifiedValue,
+}
+
+impl TheftAttempt {
+    pub(crate) const ALL: [TheftAttempt; 4] = [
+        TheftAttempt::AttackerAddress,
+        TheftAttempt::WrongPreimage,
+        TheftAttempt::EarlyRefund,
+        TheftAttempt::ModifiedValue,
+    ];
+
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            TheftAttempt::AttackerAddress => "spend to the attacker's address",
+            TheftAttempt::WrongPreimage => "redeem with a wrong preimage",
+            TheftAttempt::EarlyRefund => "refund before the CSV lock",
+            TheftAttempt::ModifiedValue => "skim the output value",
+        }
+    }
+
+    /// the attacker's spend of `htlc` through `tree`, paying `attacker`
+    pub(crate) fn build_tx(
+        self,
+        htlc: &HTLC,
+        tree: SpendTree,
+        attacker: &Address,
+        fee: Amount,
+    ) -> Result<Transaction> {
+        match self {
+            TheftAttempt::AttackerAddress => {
+                let mut tx = honest_spend(htlc, tree, fee)?;
+                tx.output[0].script_pubkey = attacker.script_pubkey();
+                Ok(tx)
+            }
+            TheftAttempt::WrongPreimage => {
+                let hashlocks = htlc
+                    .redeem_config
+                    .as_ref()
+                    .ok_or(anyhow!("the HTLC has no redeem leaf"))?
+                    .hashlocks()
+                    .len();
+                let preimages = (0..hashlocks)
+                    .map(|_| secret::random_preimage())
+                    .collect::<Result<Vec<_>, _>>()?;
+                match tree {
+                    SpendTree::Plain => htlc.create_redeem_tx_single_anyonecanpay(&preimages),
+                    SpendTree::WithFee => htlc.create_redeem_tx_with_preimages(fee, &preimages),
+                }
+            }
+            TheftAttempt::EarlyRefund => {
+                let mut tx = refund(htlc, tree, fee)?;
+                // a sequence without a relative lock makes the refund final right away
+                tx.input[0].sequence = Sequence::ZERO;
+                Ok(tx)
+            }
+            TheftAttempt::ModifiedValue => {
+                let mut tx = honest_spend(htlc, tree, fee)?;
+                let skimmed = tx.output[0].value / 2;
+                tx.output[0].value -= skimmed;
+                tx.output.push(TxOut {
+                    value: skimmed,
+                    script_pubkey: attacker.script_pubkey(),
+                });
+                Ok(tx)
+            }
+        }
+    }
+}
+
+/// which of the HTLC's two taproot trees a spend goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum SpendTree {
+    /// the leaves signing SINGLE|ANYONECANPAY, fees come from an extra input
+    Plain,
+    /// the leaves paying their fee from the HTLC amount
+    WithFee,
+}
+
+impl SpendTree {
+    pub(crate) const ALL: [SpendTree; 2] = [SpendTree::Plain, SpendTree::WithFee];
+
+    /// the address funding this tree
+    pub(crate) fn address(self, htlc: &HTLC, network: Network) -> Result<Address> {
+        match self {
+            SpendTree::Plain => htlc.address(network),
+            SpendTree::WithFee => htlc.address_with_fee(network),
+        }
+    }
+}
+
+/// the redeem when the preimages are known, the refund otherwise
+fn honest_spend(htlc: &HTLC, tree: SpendTree, fee: Amount) -> Result<Transaction> {
+    let preimages = htlc
+        .redeem_config
+        .as_ref()
+        .and_then(|redeem_config| redeem_config.preimages());
+    match (preimages, tree) {
+        (Some(preimages), SpendTree::Plain) => htlc.create_redeem_tx_single_anyonecanpay(&preimages),
+        (Some(_), SpendTree::WithFee) => htlc.create_redeem_tx_with_fee(fee),
+        (None, _) => refund(htlc, tree, fee),
+    }
+}
+
+fn refund(htlc: &HTLC, tree: SpendTree, fee: Amount) -> Result<Transaction> {
+    match tree {
+        SpendTree::Plain => htlc.create_refund_tx(),
+        SpendTree::WithFee => htlc.create_refund_tx_with_fee(fee),
+    }
+}
+
+/// run the HTLC input of `tx` through the interpreter, as a spend of `tree`
+pub(crate) fn check_spend(
+    htlc: &HTLC,
+    tree: SpendTree,
+    tx: &Transaction,
+    network: Network,
+) -> Result<Result<(), ScriptError>> {
+    let funded = htlc
+        .htlc_funded_utxo
+        .as_ref()
+        .ok_or(anyhow!("the HTLC is not funded"))?;
+    let prevout = TxOut {
+        script_pubkey: tree.address(htlc, network)?.script_pubkey(),
+        value: funded.amount,
+    };
+    Ok(interpreter::verify_input(tx, 0, &[prevout]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::mock::MockChain;
+    use crate::htlc::contract::RedeemConfig;
+    use crate::testing::{address, htlc, new_chain, pay_to, FEE, HTLC_AMOUNT, PREIMAGE, REFUND_LOCK};
+    use bitcoin::opcodes::all::OP_EQUALVERIFY;
+    use bitcoin::OutPoint;
+
+    fn deposit(chain: &MockChain, tree: SpendTree) -> HTLC {
+        let mut htlc = htlc(PREIMAGE);
+        let script_pubkey = tree.address(&htlc, Network::Regtest).unwrap().script_pubkey();
+        let tx = pay_to(chain, script_pubkey, HTLC_AMOUNT);
+        htlc.set_funded_htlc(OutPoint::new(tx.txid(), 0), HTLC_AMOUNT);
+        htlc
+    }
+
+    #[test]
+    fn covenant_rejects_every_attempt() {
+        for tree in SpendTree::ALL {
+            let chain = new_chain();
+            let htlc = deposit(&chain, tree);
+
+            let honest = honest_spend(&htlc, tree, FEE).unwrap();
+            assert_eq!(
+                check_spend(&htlc, tree, &honest, Network::Regtest).unwrap(),
+                Ok(()),
+                "{:?}",
+                tree
+            );
+            for (attempt, expected) in [
+                (TheftAttempt::AttackerAddress, ScriptError::SignatureFailed),
+                (
+                    TheftAttempt::WrongPreimage,
+                    ScriptError::Verify(OP_EQUALVERIFY),
+                ),
+                (TheftAttempt::EarlyRefund, ScriptError::UnsatisfiedLockTime),
+                (TheftAttempt::ModifiedValue, ScriptError::SignatureFailed),
+            ] {
+                let tx = attempt.build_tx(&htlc, tree, &address(4), FEE).unwrap();
+                assert_eq!(
+                    check_spend(&htlc, tree, &tx, Network::Regtest).unwrap(),
+                    Err(expected),
+                    "{:?} {:?}",
+                    tree,
+                    attempt
+                );
+                assert!(chain.test_accept(&tx).is_err(), "{:?} {:?}", tree, attempt);
+            }
+
+            // a refund keeping its sequence passes the script, the chain holds it until the lock
+            let refund = refund(&htlc, tree, FEE).unwrap();
+            assert_eq!(
+                check_spend(&htlc, tree, &refund, Network::Regtest).unwrap(),
+                Ok(())
+            );
+            let err = chain.test_accept(&refund).unwrap_err();
+            assert!(err.to_string().contains("non-BIP68-final"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn refund_is_attacked_without_the_preimage() {
+        for tree in SpendTree::ALL {
+            let chain = new_chain();
+            let mut htlc = deposit(&chain, tree);
+            // the funder's copy of the contract doesn't know the preimage
+            let payment_hash = htlc.redeem_config.as_ref().unwrap().payment_hash().clone();
+            htlc.redeem_config = Some(RedeemConfig::new_unchecked(payment_hash, None));
+            chain.mine_blocks(REFUND_LOCK as u64);
+
+            let tx = TheftAttempt::AttackerAddress
+                .build_tx(&htlc, tree, &address(4), FEE)
+                .unwrap();
+            assert_eq!(tx.input[0].sequence.0, REFUND_LOCK as u32);
+            assert_eq!(
+                check_spend(&htlc, tree, &tx, Network::Regtest).unwrap(),
+                Err(ScriptError::SignatureFailed)
+            );
+        }
+    }
+
+    #[test]
+    fn redeem_errors_are_not_hidden_by_the_refund() {
+        let chain = new_chain();
+        let mut htlc = deposit(&chain, SpendTree::WithFee);
+        // the preimage is known, so a broken redeem must not turn into a refund attempt
+        htlc.redeem_address = None;
+        let err = TheftAttempt::AttackerAddress
+            .build_tx(&htlc, SpendTree::WithFee, &address(4), FEE)
+            .unwrap_err();
+        assert!(err.to_string().contains("redeem"), "{}", err);
+    }
+}
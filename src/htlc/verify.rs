@@ -0,0 +1,440 @@
+//! The checks a redeemer runs on an HTLC a funder hands over, before doing anything that relies on
+//! it, like paying the lightning invoice of a submarine swap.
+//!
+//! Everything is recomputed from the contract: the leaves, the taproot tree and the output key.
+//! The internal key is checked against H, or H + rG with the `r` of the contract, so an address
+//! with a key path the funder could sweep never passes. H is the BIP341 constant and H + rG is
+//! computed here rather than by `nums`, which built the tree being checked.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use bitcoin::key::Parity;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::{Address, Amount, Network, OutPoint, Transaction, Txid, XOnlyPublicKey};
+
+use crate::htlc::descriptor::HtlcDescriptor;
+use crate::htlc::leaves::ExtraLeaf;
+use crate::nums::NumsTweak;
+
+/// H of BIP341, written out
+const BIP341_NUMS: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// what the funder claims to have paid to
+#[derive(Debug)]
+pub(crate) enum Funding {
+    Address(Address),
+    Tx(Transaction),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Mismatch {
+    /// the tree commits to another internal key, which may have a key path spend
+    InternalKey {
+        expected: XOnlyPublicKey,
+        actual: XOnlyPublicKey,
+    },
+    /// the address is not the one of the contract
+    Address { expected: Address, actual: Address },
+    /// no output of the funding transaction pays the contract
+    NotFunded(Txid),
+    /// the contract holds less than agreed
+    Amount { expected: Amount, actual: Amount },
+    /// the refund unlocks sooner than agreed
+    Lock { minimum: u16, actual: i64 },
+    /// the refund lock is not a relative block height
+    InvalidLock(i64),
+    /// a recovery leaf unlocks before the redeem window closes
+    EarlyRecovery { recovery: u16, refund: i64 },
+    /// an arbiter leaf repeats a key, whoever holds it signs twice
+    ArbiterDuplicateKey(XOnlyPublicKey),
+    /// an arbiter leaf leaves the redeemer out, the other two keys settle without them
+    ArbiterWithoutRedeemer(XOnlyPublicKey),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::InternalKey { expected, actual } => write!(
+                f,
+                "internal key {} is not the NUMS key {}",
+                actual, expected
+            ),
+            Mismatch::Address { expected, actual } => {
+                write!(f, "address {} is not the contract's {}", actual, expected)
+            }
+            Mismatch::NotFunded(txid) => write!(f, "{} does not pay the contract", txid),
+            Mismatch::Amount { expected, actual } => {
+                write!(f, "the contract holds {}, expected {}", actual, expected)
+            }
+            Mismatch::Lock { minimum, actual } => write!(
+                f,
+                "the refund unlocks after {} blocks, expected at least {}",
+                actual, minimum
+            ),
+            Mismatch::InvalidLock(lock) => {
+                write!(f, "refund lock {} is not a relative block height", lock)
+            }
+            Mismatch::EarlyRecovery { recovery, refund } => write!(
+                f,
+                "the recovery leaf unlocks after {} blocks, no later than the refund after {}",
+                recovery, refund
+            ),
+            Mismatch::ArbiterDuplicateKey(key) => {
+                write!(f, "the arbiter leaf has key {} more than once", key)
+            }
+            Mismatch::ArbiterWithoutRedeemer(key) => {
+                write!(
+                    f,
+                    "the arbiter leaf does not have the redeemer's key {}",
+                    key
+                )
+            }
+        }
+    }
+}
+
+/// what was agreed on besides the contract itself
+#[derive(Debug, Default)]
+pub(crate) struct Terms {
+    /// the least the contract must hold, only checked against a funding transaction
+    pub amount: Option<Amount>,
+    /// the fewest blocks the refund must wait
+    pub min_lock: Option<u16>,
+    /// the redeemer's key, which every arbiter leaf must have
+    pub redeemer_key: Option<XOnlyPublicKey>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Verification {
+    /// the address recomputed from the contract
+    pub address: Address,
+    /// the funding output, when checking a funding transaction that pays the contract
+    pub outpoint: Option<OutPoint>,
+    pub amount: Option<Amount>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Verification {
+    pub(crate) fn is_valid(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// check `funding` against the contract of `descriptor` and the agreed `terms`
+pub(crate) fn verify(
+    descriptor: &HtlcDescriptor,
+    funding: &Funding,
+    terms: &Terms,
+    network: Network,
+) -> Result<Verification> {
+    let spend_info = descriptor.taproot_spend_info()?;
+    let address = Address::p2tr_tweaked(spend_info.output_key(), network);
+    let mut mismatches = Vec::new();
+
+    mismatches.extend(check_internal_key(
+        spend_info.internal_key(),
+        descriptor.nums_tweak.as_ref(),
+    ));
+
+    let refund_lock = descriptor.refund_config.refund_lock;
+    if !(1..=0xffff).contains(&refund_lock) {
+        mismatches.push(Mismatch::InvalidLock(refund_lock));
+    } else if let Some(minimum) = terms
+        .min_lock
+        .filter(|minimum| refund_lock < *minimum as i64)
+    {
+        mismatches.push(Mismatch::Lock {
+            minimum,
+            actual: refund_lock,
+        });
+    }
+
+    for leaf in descriptor.extra_leaves.iter() {
+        match leaf {
+            ExtraLeaf::Recovery { lock, .. } if *lock as i64 <= refund_lock => {
+                mismatches.push(Mismatch::EarlyRecovery {
+                    recovery: *lock,
+                    refund: refund_lock,
+                });
+            }
+            ExtraLeaf::Arbiter { keys } => {
+                for (i, key) in keys.iter().enumerate() {
+                    if keys[..i].contains(key) {
+                        mismatches.push(Mismatch::ArbiterDuplicateKey(*key));
+                    }
+                }
+                if let Some(redeemer_key) = terms.redeemer_key
+                    && !keys.contains(&redeemer_key)
+                {
+                    mismatches.push(Mismatch::ArbiterWithoutRedeemer(redeemer_key));
+                }
+            }
+            ExtraLeaf::Recovery { .. } => {}
+        }
+    }
+
+    let (mut outpoint, mut amount) = (None, None);
+    match funding {
+        Funding::Address(actual) => {
+            if *actual != address {
+                mismatches.push(Mismatch::Address {
+                    expected: address.clone(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+        Funding::Tx(tx) => {
+            let script_pubkey = address.script_pubkey();
+            match tx
+                .output
+                .iter()
+                .position(|output| output.script_pubkey == script_pubkey)
+            {
+                Some(vout) => {
+                    let value = tx.output[vout].value;
+                    outpoint = Some(OutPoint::new(tx.txid(), vout as u32));
+                    amount = Some(value);
+                    if let Some(expected) = terms.amount.filter(|expected| value < *expected) {
+                        mismatches.push(Mismatch::Amount {
+                            expected,
+                            actual: value,
+                        });
+                    }
+                }
+                None => mismatches.push(Mismatch::NotFunded(tx.txid())),
+            }
+        }
+    }
+
+    Ok(Verification {
+        address,
+        outpoint,
+        amount,
+        mismatches,
+    })
+}
+
+/// whether `actual` is H, or H + rG with `tweak` as r
+fn check_internal_key(actual: XOnlyPublicKey, tweak: Option<&NumsTweak>) -> Option<Mismatch> {
+    let h = XOnlyPublicKey::from_str(BIP341_NUMS).expect("H is a valid x coordinate");
+    let expected = match tweak.map(|tweak| SecretKey::from_slice(&tweak.to_byte_array())) {
+        None => h,
+        // a zero r leaves H as it is
+        Some(Err(_)) => h,
+        Some(Ok(r)) => {
+            let rg = PublicKey::from_secret_key(&Secp256k1::signing_only(), &r);
+            PublicKey::from_x_only_public_key(h, Parity::Even)
+                .combine(&rg)
+                .expect("nobody knows the discrete log of H to cancel it out")
+                .x_only_public_key()
+                .0
+        }
+    };
+    (actual != expected).then_some(Mismatch::InternalKey { expected, actual })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainBackend;
+    use crate::htlc::descriptor::FeeMode;
+    use crate::nums::NumsTweak;
+    use crate::testing::{HTLC_AMOUNT, PREIMAGE, REFUND_LOCK, address, deposit, htlc, new_chain};
+
+    fn descriptor() -> HtlcDescriptor {
+        HtlcDescriptor::from_htlc(&htlc(PREIMAGE), FeeMode::Stack).unwrap()
+    }
+
+    #[test]
+    fn randomized_nums_key_needs_the_disclosed_tweak() {
+        let mut htlc = htlc(PREIMAGE);
+        htlc.nums_tweak = Some(NumsTweak::random().unwrap());
+        let randomized = HtlcDescriptor::from_htlc(&htlc, FeeMode::Stack).unwrap();
+        let internal_key = randomized.taproot_spend_info().unwrap().internal_key();
+        assert_ne!(internal_key, crate::nums::nums_point());
+        let funding = Funding::Address(randomized.address(Network::Regtest).unwrap());
+
+        let verification =
+            verify(&randomized, &funding, &Terms::default(), Network::Regtest).unwrap();
+        assert!(verification.is_valid(), "{:?}", verification.mismatches);
+        // the descriptor round trips the tweak, the counterparty gets the same tree
+        let parsed = HtlcDescriptor::parse(&randomized.to_string(), Network::Regtest).unwrap();
+        assert_eq!(parsed.nums_tweak, htlc.nums_tweak);
+        // without the tweak the address is another one
+        let verification =
+            verify(&descriptor(), &funding, &Terms::default(), Network::Regtest).unwrap();
+        assert!(matches!(
+            verification.mismatches.as_slice(),
+            [Mismatch::Address { .. }]
+        ));
+    }
+
+    #[test]
+    fn funding_transaction_passes_the_agreed_terms() {
+        let chain = new_chain();
+        let mut htlc = htlc(PREIMAGE);
+        deposit(&chain, &mut htlc);
+        let outpoint = htlc.htlc_funded_utxo.as_ref().unwrap().htlc_outpoint;
+        let funding_tx = chain.get_tx(&outpoint.txid).unwrap().unwrap();
+        let terms = Terms {
+            amount: Some(HTLC_AMOUNT),
+            min_lock: Some(REFUND_LOCK as u16),
+            redeemer_key: None,
+        };
+
+        let verification = verify(
+            &descriptor(),
+            &Funding::Tx(funding_tx.clone()),
+            &terms,
+            Network::Regtest,
+        )
+        .unwrap();
+        assert!(verification.is_valid(), "{:?}", verification.mismatches);
+        assert_eq!(verification.outpoint, Some(outpoint));
+
+        let stricter = Terms {
+            amount: Some(HTLC_AMOUNT + Amount::from_sat(1)),
+            min_lock: Some(REFUND_LOCK as u16 + 1),
+            redeemer_key: None,
+        };
+        let verification = verify(
+            &descriptor(),
+            &Funding::Tx(funding_tx),
+            &stricter,
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(
+            verification.mismatches,
+            vec![
+                Mismatch::Lock {
+                    minimum: REFUND_LOCK as u16 + 1,
+                    actual: REFUND_LOCK,
+                },
+                Mismatch::Amount {
+                    expected: HTLC_AMOUNT + Amount::from_sat(1),
+                    actual: HTLC_AMOUNT,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn other_addresses_are_reported() {
+        let descriptor = descriptor();
+        let expected = descriptor.address(Network::Regtest).unwrap();
+        let verification = verify(
+            &descriptor,
+            &Funding::Address(expected.clone()),
+            &Terms::default(),
+            Network::Regtest,
+        )
+        .unwrap();
+        assert!(verification.is_valid());
+
+        // the other tree of the same contract is a different address
+        let other_tree = HtlcDescriptor::from_htlc(&htlc(PREIMAGE), FeeMode::Input).unwrap();
+        let actual = other_tree.address(Network::Regtest).unwrap();
+        let verification = verify(
+            &descriptor,
+            &Funding::Address(actual.clone()),
+            &Terms::default(),
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(
+            verification.mismatches,
+            vec![Mismatch::Address { expected, actual }]
+        );
+        let key_path = verify(
+            &descriptor,
+            &Funding::Address(address(4)),
+            &Terms::default(),
+            Network::Regtest,
+        )
+        .unwrap();
+        assert!(!key_path.is_valid());
+    }
+
+    #[test]
+    fn recovery_before_the_refund_is_reported() {
+        let mut htlc = htlc(PREIMAGE);
+        let cold_key = crate::testing::keypair(8).x_only_public_key().0;
+        htlc.extra_leaves = vec![ExtraLeaf::Recovery {
+            cold_key,
+            lock: REFUND_LOCK as u16,
+        }];
+        let descriptor = HtlcDescriptor::from_htlc(&htlc, FeeMode::Stack).unwrap();
+        let parsed = HtlcDescriptor::parse(&descriptor.to_string(), Network::Regtest).unwrap();
+        assert_eq!(parsed.extra_leaves, htlc.extra_leaves);
+
+        let funding = Funding::Address(htlc.address_with_fee(Network::Regtest).unwrap());
+        let verification = verify(&parsed, &funding, &Terms::default(), Network::Regtest).unwrap();
+        assert_eq!(
+            verification.mismatches,
+            vec![Mismatch::EarlyRecovery {
+                recovery: REFUND_LOCK as u16,
+                refund: REFUND_LOCK,
+            }]
+        );
+    }
+
+    #[test]
+    fn internal_keys_other_than_nums_are_reported() {
+        let tweak = NumsTweak::random().unwrap();
+        let h = crate::nums::nums_point();
+        assert_eq!(h.to_string(), BIP341_NUMS);
+        assert_eq!(check_internal_key(h, None), None);
+        let tweaked = crate::nums::internal_key(Some(&tweak));
+        assert_eq!(check_internal_key(tweaked, Some(&tweak)), None);
+
+        // a funder's own key, H without the agreed tweak and H + rG for another r
+        let funder_key = crate::testing::keypair(4).x_only_public_key().0;
+        let other_tweak = NumsTweak::random().unwrap();
+        for (actual, tweak) in [
+            (funder_key, None),
+            (funder_key, Some(&tweak)),
+            (h, Some(&tweak)),
+            (tweaked, None),
+            (crate::nums::internal_key(Some(&other_tweak)), Some(&tweak)),
+        ] {
+            let expected = if tweak.is_some() { tweaked } else { h };
+            assert_eq!(
+                check_internal_key(actual, tweak),
+                Some(Mismatch::InternalKey { expected, actual })
+            );
+        }
+    }
+
+    #[test]
+    fn arbiter_leaves_must_hold_the_redeemer_once() {
+        let key = |n| crate::testing::keypair(n).x_only_public_key().0;
+        let (funder, redeemer, arbiter) = (key(5), key(6), key(7));
+        let terms = Terms {
+            redeemer_key: Some(redeemer),
+            ..Terms::default()
+        };
+        let mismatches = |keys| {
+            let mut htlc = htlc(PREIMAGE);
+            htlc.extra_leaves = vec![ExtraLeaf::Arbiter { keys }];
+            let descriptor = HtlcDescriptor::from_htlc(&htlc, FeeMode::Stack).unwrap();
+            let funding = Funding::Address(descriptor.address(Network::Regtest).unwrap());
+            verify(&descriptor, &funding, &terms, Network::Regtest)
+                .unwrap()
+                .mismatches
+        };
+
+        assert_eq!(mismatches([funder, redeemer, arbiter]), vec![]);
+        // the funder signs twice and settles with the arbiter alone
+        assert_eq!(
+            mismatches([funder, redeemer, funder]),
+            vec![Mismatch::ArbiterDuplicateKey(funder)]
+        );
+        assert_eq!(
+            mismatches([funder, arbiter, key(8)]),
+            vec![Mismatch::ArbiterWithoutRedeemer(redeemer)]
+        );
+    }
+}
@@ -0,0 +1,731 @@
+//! A small tapscript interpreter with BIP347 OP_CAT enabled.
+//!
+//! This mirrors the consensus rules of the OP_CAT enabled bitcoind closely enough to check our
+//! covenant spends without a node: taproot commitment, tapscript execution (including
+//! OP_SUCCESSx, MINIMALIF and the sigops budget), BIP340 signatures and BIP68/112 lock checks.
+//! It is not a replacement for bitcoind and only knows about taproot prevouts.
+
+use std::fmt;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
+use bitcoin::key::Secp256k1;
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::{ClassifyContext, Opcode};
+use bitcoin::opcodes::Class;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{schnorr, Message};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TAPROOT_ANNEX_PREFIX};
+use bitcoin::{Script, Sequence, TapLeafHash, TapSighashType, Transaction, TxOut, XOnlyPublicKey};
+
+/// BIP342 / BIP347 element size limit
+pub(crate) const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// combined size limit of the stack and altstack
+pub(crate) const MAX_STACK_SIZE: usize = 1000;
+/// budget consumed by every executed signature check (BIP342)
+const VALIDATION_WEIGHT_PER_SIGOP: i64 = 50;
+/// budget granted on top of the witness size (BIP342)
+const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+/// script numbers are 4 bytes, except for the lock time opcodes which accept 5
+const MAX_NUM_SIZE: usize = 4;
+const MAX_LOCKTIME_NUM_SIZE: usize = 5;
+/// BIP68 flags of a sequence number
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ScriptError {
+    /// the prevout is not a segwit v1 output
+    NotTaproot,
+    /// the input has no witness
+    EmptyWitness,
+    /// the control block could not be parsed
+    InvalidControlBlock,
+    /// the script is not committed to by the prevout's output key
+    WitnessProgramMismatch,
+    /// the key path signature failed to verify
+    InvalidKeySpendSignature,
+    /// an opcode needed more stack items than there were
+    StackUnderflow(Opcode),
+    /// the stack and altstack combined grew beyond `MAX_STACK_SIZE`
+    StackSize,
+    /// an element larger than `MAX_SCRIPT_ELEMENT_SIZE` was pushed
+    PushSize(usize),
+    /// a number operand was too long
+    NumberOverflow,
+    /// an `OP_*VERIFY` opcode found a false value
+    Verify(Opcode),
+    /// OP_RETURN or a reserved opcode was executed
+    OpReturn,
+    /// an opcode that is not allowed in tapscript was found
+    BadOpcode(Opcode),
+    /// OP_IF/OP_ELSE/OP_ENDIF are not balanced
+    UnbalancedConditional,
+    /// the OP_IF argument was not empty or exactly 0x01
+    MinimalIf,
+    /// a non empty signature failed to verify
+    SignatureFailed,
+    /// the signature has an invalid size or sighash type
+    InvalidSignatureEncoding,
+    /// an empty public key was given to a signature opcode
+    EmptyPublicKey,
+    /// too many signature checks for the size of the witness
+    SigopsBudget,
+    /// a lock time argument was negative
+    NegativeLockTime,
+    /// OP_CHECKSEQUENCEVERIFY or OP_CHECKLOCKTIMEVERIFY is not satisfied by the transaction
+    UnsatisfiedLockTime,
+    /// the script did not leave exactly one element on the stack
+    CleanStack(usize),
+    /// the script finished with a false value on the stack
+    EvalFalse,
+    /// the sighash could not be computed
+    Sighash(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::NotTaproot => write!(f, "prevout is not a taproot output"),
+            ScriptError::EmptyWitness => write!(f, "witness is empty"),
+            ScriptError::InvalidControlBlock => write!(f, "invalid control block"),
+            ScriptError::WitnessProgramMismatch => {
+                write!(f, "script is not committed to by the output key")
+            }
+            ScriptError::InvalidKeySpendSignature => write!(f, "invalid key path signature"),
+            ScriptError::StackUnderflow(op) => write!(f, "stack underflow at {}", op),
+            ScriptError::StackSize => write!(f, "stack size limit exceeded"),
+            ScriptError::PushSize(size) => write!(f, "element of {} bytes exceeds push limit", size),
+            ScriptError::NumberOverflow => write!(f, "script number overflow"),
+            ScriptError::Verify(op) => write!(f, "{} failed", op),
+            ScriptError::OpReturn => write!(f, "OP_RETURN executed"),
+            ScriptError::BadOpcode(op) => write!(f, "opcode {} not allowed in tapscript", op),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::MinimalIf => write!(f, "OP_IF argument must be empty or 0x01"),
+            ScriptError::SignatureFailed => write!(f, "signature verification failed"),
+            ScriptError::InvalidSignatureEncoding => write!(f, "invalid signature encoding"),
+            ScriptError::EmptyPublicKey => write!(f, "empty public key"),
+            ScriptError::SigopsBudget => write!(f, "sigops budget exceeded"),
+            ScriptError::NegativeLockTime => write!(f, "negative lock time"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "lock time requirement not satisfied"),
+            ScriptError::CleanStack(size) => {
+                write!(f, "script left {} elements on the stack, expected 1", size)
+            }
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+            ScriptError::Sighash(e) => write!(f, "could not compute sighash: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// The spend a script is executed for, needed by the signature and lock time opcodes.
+pub(crate) struct ExecContext<'a> {
+    pub(crate) tx: &'a Transaction,
+    pub(crate) input_index: usize,
+    pub(crate) prevouts: &'a [TxOut],
+    pub(crate) leaf_hash: TapLeafHash,
+    pub(crate) annex: Option<Annex<'a>>,
+}
+
+/// Verify input `input_index` of `tx` spending the taproot output `prevouts[input_index]`.
+pub(crate) fn verify_input(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+) -> Result<(), ScriptError> {
+    let prevout = prevouts
+        .get(input_index)
+        .ok_or_else(|| ScriptError::Sighash("missing prevout".to_string()))?;
+    if !prevout.script_pubkey.is_p2tr() {
+        return Err(ScriptError::NotTaproot);
+    }
+    let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..34])
+        .map_err(|_| ScriptError::NotTaproot)?;
+
+    let mut witness: Vec<&[u8]> = tx.input[input_index].witness.iter().collect();
+    if witness.is_empty() {
+        return Err(ScriptError::EmptyWitness);
+    }
+    let annex = match witness.last() {
+        Some(last) if witness.len() >= 2 && last.first() == Some(&TAPROOT_ANNEX_PREFIX) => {
+            let annex = Annex::new(last).map_err(|e| ScriptError::Sighash(e.to_string()))?;
+            witness.pop();
+            Some(annex)
+        }
+        _ => None,
+    };
+
+    let secp = Secp256k1::verification_only();
+    if witness.len() == 1 {
+        // key path spend
+        let (signature, sighash_type) = parse_signature(witness[0])?;
+        let sighash = SighashCache::new(tx)
+            .taproot_signature_hash(input_index, &Prevouts::All(prevouts), annex, None, sighash_type)
+            .map_err(|e| ScriptError::Sighash(e.to_string()))?;
+        let message = Message::from_digest(sighash.to_byte_array());
+        return secp
+            .verify_schnorr(&signature, &message, &output_key)
+            .map_err(|_| ScriptError::InvalidKeySpendSignature);
+    }
+
+    let control_block =
+        ControlBlock::decode(witness.pop().unwrap()).map_err(|_| ScriptError::InvalidControlBlock)?;
+    let script = Script::from_bytes(witness.pop().unwrap());
+    if !control_block.verify_taproot_commitment(&secp, output_key, script) {
+        return Err(ScriptError::WitnessProgramMismatch);
+    }
+    if control_block.leaf_version != LeafVersion::TapScript {
+        // unknown leaf versions are anyone-can-spend for forward compatibility
+        return Ok(());
+    }
+
+    let stack: Vec<Vec<u8>> = witness.iter().map(|item| item.to_vec()).collect();
+    let mut ctx = ExecContext {
+        tx,
+        input_index,
+        prevouts,
+        leaf_hash: TapLeafHash::from_script(script, LeafVersion::TapScript),
+        annex,
+    };
+    let budget = VALIDATION_WEIGHT_OFFSET + tx.input[input_index].witness.size() as i64;
+    let stack = execute(script, stack, &mut ctx, budget)?;
+    match stack.as_slice() {
+        [top] if cast_to_bool(top) => Ok(()),
+        [_] => Err(ScriptError::EvalFalse),
+        other => Err(ScriptError::CleanStack(other.len())),
+    }
+}
+
+/// Execute a tapscript against an initial stack, returning the final stack.
+pub(crate) fn execute(
+    script: &Script,
+    mut stack: Vec<Vec<u8>>,
+    ctx: &mut ExecContext,
+    mut budget: i64,
+) -> Result<Vec<Vec<u8>>, ScriptError> {
+    let instructions: Vec<Instruction> = script
+        .instructions()
+        .collect::<Result<_, _>>()
+        .map_err(|_| ScriptError::BadOpcode(OP_INVALIDOPCODE))?;
+
+    // any OP_SUCCESSx makes the script succeed before anything is executed. OP_CAT is the one
+    // we know how to execute.
+    for instruction in instructions.iter() {
+        if matches!(instruction, Instruction::Op(op)
+            if *op != OP_CAT && op.classify(ClassifyContext::TapScript) == Class::SuccessOp)
+        {
+            return Ok(vec![vec![1]]);
+        }
+    }
+
+    for item in stack.iter() {
+        if item.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(ScriptError::PushSize(item.len()));
+        }
+    }
+
+    let mut alt_stack: Vec<Vec<u8>> = Vec::new();
+    let mut exec_stack: Vec<bool> = Vec::new();
+    let mut code_separator_pos = u32::MAX;
+
+    for (op_index, instruction) in instructions.iter().enumerate() {
+        let executing = exec_stack.iter().all(|e| *e);
+        let op = match instruction {
+            Instruction::PushBytes(bytes) => {
+                if executing {
+                    if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(ScriptError::PushSize(bytes.len()));
+                    }
+                    stack.push(bytes.as_bytes().to_vec());
+                }
+                check_stack_size(&stack, &alt_stack)?;
+                continue;
+            }
+            Instruction::Op(op) => *op,
+        };
+
+        match op {
+            OP_IF | OP_NOTIF => {
+                let mut value = false;
+                if executing {
+                    let top = pop(&mut stack, op)?;
+                    if top.len() > 1 || (top.len() == 1 && top[0] != 1) {
+                        return Err(ScriptError::MinimalIf);
+                    }
+                    value = top.len() == 1;
+                    if op == OP_NOTIF {
+                        value = !value;
+                    }
+                }
+                exec_stack.push(value);
+                continue;
+            }
+            OP_ELSE => {
+                let last = exec_stack.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *last = !*last;
+                continue;
+            }
+            OP_ENDIF => {
+                exec_stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                continue;
+            }
+            _ if !executing => continue,
+            _ => {}
+        }
+
+        match op {
+            OP_PUSHNUM_NEG1 => stack.push(encode_num(-1)),
+            op if op.to_u8() >= OP_PUSHNUM_1.to_u8() && op.to_u8() <= OP_PUSHNUM_16.to_u8() => {
+                stack.push(encode_num((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64))
+            }
+            OP_NOP | OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9
+            | OP_NOP10 => {}
+            OP_CLTV => check_lock_time(&stack, ctx)?,
+            OP_CSV => check_sequence(&stack, ctx)?,
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(&mut stack, op)?) {
+                    return Err(ScriptError::Verify(op));
+                }
+            }
+            OP_RETURN | OP_RESERVED | OP_VER | OP_RESERVED1 | OP_RESERVED2 => {
+                return Err(ScriptError::OpReturn)
+            }
+
+            // stack operations
+            OP_TOALTSTACK => alt_stack.push(pop(&mut stack, op)?),
+            OP_FROMALTSTACK => stack.push(alt_stack.pop().ok_or(ScriptError::StackUnderflow(op))?),
+            OP_2DROP => {
+                need(&stack, 2, op)?;
+                stack.truncate(stack.len() - 2);
+            }
+            OP_2DUP => {
+                need(&stack, 2, op)?;
+                let n = stack.len();
+                stack.extend_from_within(n - 2..);
+            }
+            OP_3DUP => {
+                need(&stack, 3, op)?;
+                let n = stack.len();
+                stack.extend_from_within(n - 3..);
+            }
+            OP_2OVER => {
+                need(&stack, 4, op)?;
+                let n = stack.len();
+                stack.extend_from_within(n - 4..n - 2);
+            }
+            OP_2ROT => {
+                need(&stack, 6, op)?;
+                let n = stack.len();
+                let moved: Vec<Vec<u8>> = stack.drain(n - 6..n - 4).collect();
+                stack.extend(moved);
+            }
+            OP_2SWAP => {
+                need(&stack, 4, op)?;
+                let n = stack.len();
+                stack[n - 4..].rotate_left(2);
+            }
+            OP_IFDUP => {
+                let top = peek(&stack, 0, op)?.clone();
+                if cast_to_bool(&top) {
+                    stack.push(top);
+                }
+            }
+            OP_DEPTH => stack.push(encode_num(stack.len() as i64)),
+            OP_DROP => {
+                pop(&mut stack, op)?;
+            }
+            OP_DUP => stack.push(peek(&stack, 0, op)?.clone()),
+            OP_NIP => {
+                need(&stack, 2, op)?;
+                stack.remove(stack.len() - 2);
+            }
+            OP_OVER => stack.push(peek(&stack, 1, op)?.clone()),
+            OP_PICK | OP_ROLL => {
+                let n = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                if n < 0 || n as usize >= stack.len() {
+                    return Err(ScriptError::StackUnderflow(op));
+                }
+                let index = stack.len() - 1 - n as usize;
+                let item = if op == OP_PICK {
+                    stack[index].clone()
+                } else {
+                    stack.remove(index)
+                };
+                stack.push(item);
+            }
+            OP_ROT => {
+                need(&stack, 3, op)?;
+                let n = stack.len();
+                stack[n - 3..].rotate_left(1);
+            }
+            OP_SWAP => {
+                need(&stack, 2, op)?;
+                let n = stack.len();
+                stack.swap(n - 2, n - 1);
+            }
+            OP_TUCK => {
+                need(&stack, 2, op)?;
+                let top = stack[stack.len() - 1].clone();
+                stack.insert(stack.len() - 2, top);
+            }
+
+            // splice
+            OP_CAT => {
+                let b = pop(&mut stack, op)?;
+                let mut a = pop(&mut stack, op)?;
+                if a.len() + b.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize(a.len() + b.len()));
+                }
+                a.extend(b);
+                stack.push(a);
+            }
+            OP_SIZE => stack.push(encode_num(peek(&stack, 0, op)?.len() as i64)),
+
+            // bitwise logic
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let b = pop(&mut stack, op)?;
+                let a = pop(&mut stack, op)?;
+                if op == OP_EQUALVERIFY {
+                    if a != b {
+                        return Err(ScriptError::Verify(op));
+                    }
+                } else {
+                    stack.push(encode_bool(a == b));
+                }
+            }
+
+            // arithmetic
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let a = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let result = match op {
+                    OP_1ADD => a + 1,
+                    OP_1SUB => a - 1,
+                    OP_NEGATE => -a,
+                    OP_ABS => a.abs(),
+                    OP_NOT => (a == 0) as i64,
+                    _ => (a != 0) as i64,
+                };
+                stack.push(encode_num(result));
+            }
+            OP_ADD | OP_SUB | OP_BOOLAND | OP_BOOLOR | OP_NUMEQUAL | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL | OP_LESSTHAN | OP_GREATERTHAN | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL | OP_MIN | OP_MAX => {
+                let b = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let a = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let result = match op {
+                    OP_ADD => a + b,
+                    OP_SUB => a - b,
+                    OP_BOOLAND => (a != 0 && b != 0) as i64,
+                    OP_BOOLOR => (a != 0 || b != 0) as i64,
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                    OP_NUMNOTEQUAL => (a != b) as i64,
+                    OP_LESSTHAN => (a < b) as i64,
+                    OP_GREATERTHAN => (a > b) as i64,
+                    OP_LESSTHANOREQUAL => (a <= b) as i64,
+                    OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                    OP_MIN => a.min(b),
+                    _ => a.max(b),
+                };
+                if op == OP_NUMEQUALVERIFY {
+                    if result == 0 {
+                        return Err(ScriptError::Verify(op));
+                    }
+                } else {
+                    stack.push(encode_num(result));
+                }
+            }
+            OP_WITHIN => {
+                let max = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let min = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let x = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                stack.push(encode_bool(min <= x && x < max));
+            }
+
+            // crypto
+            OP_RIPEMD160 => {
+                let a = pop(&mut stack, op)?;
+                stack.push(ripemd160::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_SHA1 => {
+                let a = pop(&mut stack, op)?;
+                stack.push(sha1::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_SHA256 => {
+                let a = pop(&mut stack, op)?;
+                stack.push(sha256::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_HASH160 => {
+                let a = pop(&mut stack, op)?;
+                stack.push(hash160::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_HASH256 => {
+                let a = pop(&mut stack, op)?;
+                stack.push(sha256d::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_CODESEPARATOR => code_separator_pos = op_index as u32,
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pubkey = pop(&mut stack, op)?;
+                let signature = pop(&mut stack, op)?;
+                let valid = check_schnorr(&signature, &pubkey, ctx, code_separator_pos, &mut budget)?;
+                if op == OP_CHECKSIGVERIFY {
+                    if !valid {
+                        return Err(ScriptError::Verify(op));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            OP_CHECKSIGADD => {
+                let pubkey = pop(&mut stack, op)?;
+                let n = decode_num(&pop(&mut stack, op)?, MAX_NUM_SIZE)?;
+                let signature = pop(&mut stack, op)?;
+                let valid = check_schnorr(&signature, &pubkey, ctx, code_separator_pos, &mut budget)?;
+                stack.push(encode_num(n + valid as i64));
+            }
+            op => return Err(ScriptError::BadOpcode(op)),
+        }
+        check_stack_size(&stack, &alt_stack)?;
+    }
+
+    if !exec_stack.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(stack)
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>, op: Opcode) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow(op))
+}
+
+fn peek(stack: &[Vec<u8>], depth: usize, op: Opcode) -> Result<&Vec<u8>, ScriptError> {
+    need(stack, depth + 1, op)?;
+    Ok(&stack[stack.len() - 1 - depth])
+}
+
+fn need(stack: &[Vec<u8>], items: usize, op: Opcode) -> Result<(), ScriptError> {
+    if stack.len() < items {
+        return Err(ScriptError::StackUnderflow(op));
+    }
+    Ok(())
+}
+
+fn check_stack_size(stack: &[Vec<u8>], alt_stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+        return Err(ScriptError::StackSize);
+    }
+    Ok(())
+}
+
+fn parse_signature(bytes: &[u8]) -> Result<(schnorr::Signature, TapSighashType), ScriptError> {
+    let sighash_type = match bytes.len() {
+        64 => TapSighashType::Default,
+        65 if bytes[64] != 0 => TapSighashType::from_consensus_u8(bytes[64])
+            .map_err(|_| ScriptError::InvalidSignatureEncoding)?,
+        _ => return Err(ScriptError::InvalidSignatureEncoding),
+    };
+    let signature = schnorr::Signature::from_slice(&bytes[..64])
+        .map_err(|_| ScriptError::InvalidSignatureEncoding)?;
+    Ok((signature, sighash_type))
+}
+
+/// BIP342 signature opcode semantics: empty signatures push false, failing non empty ones abort
+fn check_schnorr(
+    signature: &[u8],
+    pubkey: &[u8],
+    ctx: &mut ExecContext,
+    code_separator_pos: u32,
+    budget: &mut i64,
+) -> Result<bool, ScriptError> {
+    if signature.is_empty() {
+        return Ok(false);
+    }
+    *budget -= VALIDATION_WEIGHT_PER_SIGOP;
+    if *budget < 0 {
+        return Err(ScriptError::SigopsBudget);
+    }
+    if pubkey.is_empty() {
+        return Err(ScriptError::EmptyPublicKey);
+    }
+    if pubkey.len() != 32 {
+        // unknown public key types are treated as valid for upgradability
+        return Ok(true);
+    }
+    let pubkey = XOnlyPublicKey::from_slice(pubkey).map_err(|_| ScriptError::SignatureFailed)?;
+    let (signature, sighash_type) = parse_signature(signature)?;
+    let sighash = SighashCache::new(ctx.tx)
+        .taproot_signature_hash(
+            ctx.input_index,
+            &Prevouts::All(ctx.prevouts),
+            ctx.annex.clone(),
+            Some((ctx.leaf_hash, code_separator_pos)),
+            sighash_type,
+        )
+        .map_err(|e| ScriptError::Sighash(e.to_string()))?;
+    let message = Message::from_digest(sighash.to_byte_array());
+    Secp256k1::verification_only()
+        .verify_schnorr(&signature, &message, &pubkey)
+        .map_err(|_| ScriptError::SignatureFailed)?;
+    Ok(true)
+}
+
+/// BIP65
+fn check_lock_time(stack: &[Vec<u8>], ctx: &ExecContext) -> Result<(), ScriptError> {
+    let required = decode_num(peek(stack, 0, OP_CLTV)?, MAX_LOCKTIME_NUM_SIZE)?;
+    if required < 0 {
+        return Err(ScriptError::NegativeLockTime);
+    }
+    let required = LockTime::from_consensus(required as u32);
+    if !ctx.tx.lock_time.is_same_unit(required)
+        || required.to_consensus_u32() > ctx.tx.lock_time.to_consensus_u32()
+        || ctx.tx.input[ctx.input_index].sequence == Sequence::MAX
+    {
+        return Err(ScriptError::UnsatisfiedLockTime);
+    }
+    Ok(())
+}
+
+/// BIP112
+fn check_sequence(stack: &[Vec<u8>], ctx: &ExecContext) -> Result<(), ScriptError> {
+    let required = decode_num(peek(stack, 0, OP_CSV)?, MAX_LOCKTIME_NUM_SIZE)?;
+    if required < 0 {
+        return Err(ScriptError::NegativeLockTime);
+    }
+    let required = required as u32;
+    if required & SEQUENCE_DISABLE_FLAG != 0 {
+        return Ok(());
+    }
+    let sequence = ctx.tx.input[ctx.input_index].sequence.to_consensus_u32();
+    let mask = SEQUENCE_TYPE_FLAG | 0x0000ffff;
+    if ctx.tx.version.0 < 2
+        || sequence & SEQUENCE_DISABLE_FLAG != 0
+        || (required & SEQUENCE_TYPE_FLAG) != (sequence & SEQUENCE_TYPE_FLAG)
+        || (required & mask) > (sequence & mask)
+    {
+        return Err(ScriptError::UnsatisfiedLockTime);
+    }
+    Ok(())
+}
+
+pub(crate) fn cast_to_bool(bytes: &[u8]) -> bool {
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte != 0 {
+            // negative zero is false
+            return !(i == bytes.len() - 1 && *byte == 0x80);
+        }
+    }
+    false
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+/// decode a minimally sized little endian sign-magnitude script number
+pub(crate) fn decode_num(bytes: &[u8], max_size: usize) -> Result<i64, ScriptError> {
+    if bytes.len() > max_size {
+        return Err(ScriptError::NumberOverflow);
+    }
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    let sign_bit = 0x80i64 << (8 * (bytes.len() - 1));
+    if value & sign_bit != 0 {
+        Ok(-(value & !sign_bit))
+    } else {
+        Ok(value)
+    }
+}
+
+pub(crate) fn encode_num(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return vec![];
+    }
+    let negative = value < 0;
+    let mut abs = value.unsigned_abs();
+    let mut result = Vec::new();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if result.last().unwrap() & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0 });
+    } else if negative {
+        *result.last_mut().unwrap() |= 0x80;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::script::Builder;
+    use bitcoin::ScriptBuf;
+
+    fn run(script: ScriptBuf, stack: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let tx = Transaction {
+            version: bitcoin::transaction::Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![bitcoin::TxIn {
+                sequence: Sequence::from_height(10),
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let mut ctx = ExecContext {
+            tx: &tx,
+            input_index: 0,
+            prevouts: &[],
+            leaf_hash: TapLeafHash::from_script(&script, LeafVersion::TapScript),
+            annex: None,
+        };
+        execute(&script, stack, &mut ctx, 1000)
+    }
+
+    #[test]
+    fn script_numbers_round_trip() {
+        for n in [0i64, 1, -1, 127, 128, -128, 255, 256, 32767, -32768, 0x7fffffff] {
+            assert_eq!(decode_num(&encode_num(n), 5).unwrap(), n);
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert_eq!(decode_num(&[1, 2, 3, 4, 5], 4), Err(ScriptError::NumberOverflow));
+    }
+
+    #[test]
+    fn cat_concatenates_and_respects_the_element_limit() {
+        let script = Builder::new().push_opcode(OP_CAT).into_script();
+        let stack = run(script.clone(), vec![vec![0xaa], vec![0xbb, 0xcc]]).unwrap();
+        assert_eq!(stack, vec![vec![0xaa, 0xbb, 0xcc]]);
+
+        let err = run(script, vec![vec![0; 300], vec![0; 221]]).unwrap_err();
+        assert_eq!(err, ScriptError::PushSize(521));
+    }
+
+    #[test]
+    fn csv_compares_against_the_input_sequence() {
+        let satisfied = Builder::new().push_int(10).push_opcode(OP_CSV).into_script();
+        assert!(run(satisfied, vec![]).is_ok());
+
+        let too_early = Builder::new().push_int(11).push_opcode(OP_CSV).into_script();
+        assert_eq!(run(too_early, vec![]).unwrap_err(), ScriptError::UnsatisfiedLockTime);
+    }
+
+    #[test]
+    fn op_success_short_circuits_execution() {
+        let script = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_MUL)
+            .into_script();
+        assert_eq!(run(script, vec![]).unwrap(), vec![vec![1]]);
+    }
+}
@@ -1,42 +1,377 @@
+mod atomic_swap;
+mod chain;
 mod htlc;
+mod interpreter;
+mod nums;
+mod output;
+#[cfg(test)]
+mod regtest;
+#[cfg(test)]
+mod testing;
 mod wallet;
+mod secret;
 mod settings;
-use htlc::contract::{RedeemConfig, RefundConfig, HTLC};
-use bitcoin::{locktime, Address, Amount, Network, OutPoint, Transaction, Txid};
+mod submarine_swap;
+mod vault;
+use htlc::contract::{add_fee_to_txn, RedeemConfig, RefundConfig, HTLC};
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, OutPoint, Transaction, TxOut, Txid, Weight, XOnlyPublicKey};
 use std::str::FromStr;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use crate::htlc::descriptor::{FeeMode, HtlcDescriptor};
+use crate::htlc::hashlock::{HashLock, PaymentHash, Preimage};
+use crate::htlc::psbt::SpendPath;
+use crate::htlc::leaves::ExtraLeaf;
+use crate::htlc::theft::{check_spend, SpendTree, TheftAttempt};
+use crate::htlc::verify::{Funding, Terms};
+use crate::nums::NumsTweak;
+use crate::output::{OutputFormat, Report};
+use bitcoincore_rpc::jsonrpc::serde_json::json;
+use crate::secret::SecretStore;
 use crate::settings::Settings;
 use clap::Parser;
 use std::path::PathBuf;
 use log::{debug, error, info};
+use crate::submarine_swap::{submarine_htlc, Bolt11Invoice};
+use crate::atomic_swap::{AtomicSwap, SwapParty, SwapState};
+use crate::vault::daily_limit::{DailyLimitVault, VaultUtxo, MAX_VAULT_AMOUNT};
+use crate::vault::delayed::{DelayedVault, VaultState};
 use crate::wallet::Wallet;
-use crate::htlc::contract::{HtlcFunded,add_fee_to_txn,compute_taproot_sighash};
-use bitcoin::consensus::Encodable;
-use bitcoincore_rpc::{RawTx, RpcApi};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::htlc::contract::HtlcFunded;
 
 #[derive(Parser)]
 struct Cli {
     #[arg(short, long, default_value = "settings.toml")]
     settings_file: PathBuf,
 
+    /// where the HTLC contract is stored between commands
+    #[arg(short, long, default_value = "htlc_contract.json")]
+    contract_file: PathBuf,
+
+    /// encrypted preimage store
+    #[arg(long, default_value = "secrets.json")]
+    secrets_file: PathBuf,
+
+    /// where the daily limit vault is stored between commands
+    #[arg(long, default_value = "limit_vault.json")]
+    limit_vault_file: PathBuf,
+
+    /// where the delayed vault is stored between commands
+    #[arg(long, default_value = "vault.json")]
+    vault_file: PathBuf,
+
+    /// where the atomic swap is stored between commands
+    #[arg(long, default_value = "swap.json")]
+    swap_file: PathBuf,
+
+    /// print the result as text or as a single JSON document
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
     #[command(subcommand)]
     action: Action,
 }
 
-#[derive(Parser)]
+/// funding the HTLC from an external wallet instead of the miner wallet
+#[derive(clap::Args)]
+struct PsbtFunding {
+    /// print an unsigned funding PSBT spending `--input`s instead of paying from the miner wallet
+    #[arg(long, requires = "inputs")]
+    psbt: bool,
+    /// an output of the funding wallet to spend, as txid:vout
+    #[arg(long = "input", requires = "psbt")]
+    inputs: Vec<OutPoint>,
+    /// receives what the inputs hold beyond the amount and fee
+    #[arg(long, requires = "psbt")]
+    change_address: Option<String>,
+    /// fee of the funding transaction, in sats
+    #[arg(long, default_value_t = 1000)]
+    funding_fee: u64,
+}
 
-//TODO: add redeem, refund, redeem steal, refund steal actions
+#[derive(Parser)]
 enum Action {
-    Deposit{refund_address:String,redeem_address:String,payment_hash:String},
-    AdHokTesting,
+    Deposit{
+        refund_address:String,
+        redeem_address:String,
+        /// several payment hashes make a multi hash lock HTLC, redeemed with all their preimages
+        #[arg(required = true)]
+        payment_hashes:Vec<PaymentHash>,
+        /// hash function of the redeem leaf, hash160 interoperates with pre-taproot swap protocols
+        #[arg(long, value_enum, default_value_t = HashLock::Sha256)]
+        hash_lock: HashLock,
+        /// require a 32 byte preimage in the redeem leaf, for swaps with chains enforcing the size
+        #[arg(long)]
+        size_guard: bool,
+        #[command(flatten)]
+        funding: PsbtFunding,
+        /// use H + rG as the internal key instead of the NUMS point H, the redeemer needs r to verify
+        #[arg(long)]
+        randomize_nums: bool,
+        /// an extra leaf, arbiter=<key>:<key>:<key> for a 2-of-3 or recovery=<key>:<blocks> for a cold key
+        #[arg(long = "leaf")]
+        extra_leaves:Vec<ExtraLeaf>,
+    },
+    /// fund an HTLC with the payment hash and amount of a BOLT11 invoice, redeemable by the swap service
+    SubmarineSwap{
+        invoice:String,
+        refund_address:String,
+        /// the swap service paying the invoice
+        redeem_address:String,
+        /// blocks before the refund, has to outlast the invoice expiry and its final CLTV delta
+        #[arg(long, default_value_t = 100)]
+        refund_lock:u16,
+        #[command(flatten)]
+        funding: PsbtFunding,
+    },
+    /// record the transaction funding the HTLC, from a signed funding PSBT (which is broadcast) or a txid
+    RecordFunding{
+        #[arg(long, conflicts_with = "txid", required_unless_present = "txid")]
+        psbt: Option<String>,
+        #[arg(long)]
+        txid: Option<Txid>,
+        /// only check the signed psbt with testmempoolaccept
+        #[arg(long, requires = "psbt")]
+        dry_run: bool,
+    },
+    /// pay the funded HTLC to the redeem address, revealing the preimages
+    Redeem{
+        /// a preimage to record in the contract file first, one for each payment hash it lacks
+        #[arg(long = "preimage")]
+        preimages:Vec<Preimage>,
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+        /// print the spend as a PSBT instead, for an external wallet to add the fee input of the
+        /// plain tree and hand back to `finalize-psbt`
+        #[arg(long, conflicts_with = "dry_run")]
+        psbt: bool,
+    },
+    /// pay the funded HTLC back to the refund address once the refund lock passed
+    Refund{
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+        /// print the spend as a PSBT instead, for an external wallet to add the fee input of the
+        /// plain tree and hand back to `finalize-psbt`
+        #[arg(long, conflicts_with = "dry_run")]
+        psbt: bool,
+    },
+    /// finalize and broadcast a spend PSBT from `redeem --psbt` or `refund --psbt` once its fee is paid
+    FinalizePsbt{
+        psbt:String,
+        /// only check the finalized spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// generate a preimage and print its payment hash. Creates the store on first use
+    NewSecret{
+        /// draw the preimage from the OS instead of deriving it from the store's seed
+        #[arg(long)]
+        random: bool,
+        /// prompt for the hex seed of an old store, to recover its preimages into a new one
+        #[arg(long)]
+        recover: bool,
+    },
+    /// print the preimage for a contract index or payment hash
+    RevealSecret{id:String},
+    /// fund a vault paying its owner at most `daily_limit` sats every `period` blocks
+    LimitVaultDeposit{
+        owner_address:String,
+        /// sats
+        daily_limit:u64,
+        /// sats
+        amount:u64,
+        #[arg(long, default_value_t = 144)]
+        period:u16,
+        /// the most a withdrawal may pay in fees, in sats
+        #[arg(long, default_value_t = 10_000)]
+        max_fee:u64,
+    },
+    /// withdraw `amount` sats to the owner, the rest goes back to the vault
+    LimitVaultWithdraw{
+        amount:u64,
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// print the descriptor of the contract, or reconstruct the address of a counterparty's descriptor
+    Describe{
+        /// a descriptor to reconstruct instead of describing the contract file
+        descriptor:Option<String>,
+        /// the tree of the contract file, found from its funding transaction by default
+        #[arg(long, value_enum)]
+        fee:Option<FeeMode>,
+    },
+    /// check an HTLC from a funder: its tree, NUMS internal key, funding amount and refund lock
+    Verify{
+        /// the contract's descriptor, the contract file is used when omitted
+        #[arg(long)]
+        descriptor:Option<String>,
+        /// the tree of the contract file, the one `deposit` funds by default
+        #[arg(long, value_enum, default_value_t)]
+        fee:FeeMode,
+        /// the address the funder handed over
+        #[arg(long, conflicts_with = "txid", required_unless_present = "txid")]
+        address:Option<String>,
+        /// the funder's funding transaction
+        #[arg(long)]
+        txid:Option<Txid>,
+        /// the least the contract must hold, in sats
+        #[arg(long, requires = "txid")]
+        amount:Option<u64>,
+        /// the fewest blocks the refund must wait
+        #[arg(long)]
+        min_lock:Option<u16>,
+        /// the redeemer's key, checked to be in every arbiter leaf
+        #[arg(long)]
+        redeemer_key:Option<XOnlyPublicKey>,
+    },
+    /// report the script size and witness weight of every leaf, in both trees
+    Analyze{
+        /// a descriptor to analyze instead of the contract file
+        #[arg(long)]
+        descriptor:Option<String>,
+        #[arg(long, default_value_t = 1000)]
+        fee:u64,
+    },
+    /// try to take the funded HTLC in ways the covenant forbids and report how each is rejected
+    Steal{
+        attacker_address:String,
+        /// a single attempt, all of them by default
+        #[arg(long, value_enum)]
+        attempt:Option<TheftAttempt>,
+        /// a single tree, both of them by default
+        #[arg(long, value_enum)]
+        tree:Option<SpendTree>,
+        #[arg(long, default_value_t = 1000)]
+        fee:u64,
+    },
+    /// fund a vault whose withdrawals wait `delay` blocks and can be cancelled to `cold_address`
+    VaultDeposit{
+        cold_address:String,
+        /// sats
+        amount:u64,
+        #[arg(long, default_value_t = 10)]
+        delay:u16,
+    },
+    /// print the state of the vault
+    VaultStatus,
+    /// start a withdrawal to `target_address`, which completes after the delay
+    VaultTrigger{
+        target_address:String,
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// send the vaulted or triggered coins to the cold address
+    VaultCancel{
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// pay a triggered withdrawal to its target once the delay passed
+    VaultComplete{
+        /// sats, estimated from the chain backend's fee rate by default
+        #[arg(long)]
+        fee:Option<u64>,
+        /// only check the spend with testmempoolaccept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// set up an atomic swap, A paid by the initiator and B by the participant, both keeping a copy
+    SwapNew{
+        payment_hash:PaymentHash,
+        /// sats the initiator locks in A
+        amount_a:u64,
+        /// sats the participant locks in B
+        amount_b:u64,
+        /// the initiator's address for B
+        #[arg(long)]
+        initiator_redeem_address:String,
+        /// where A goes back to after the initiator lock
+        #[arg(long)]
+        initiator_refund_address:String,
+        /// the participant's address for A
+        #[arg(long)]
+        participant_redeem_address:String,
+        /// where B goes back to after the participant lock
+        #[arg(long)]
+        participant_refund_address:String,
+        /// blocks before A refunds
+        #[arg(long, default_value_t = 48)]
+        initiator_lock:u16,
+        /// blocks before B refunds, at least 12 fewer than the initiator lock
+        #[arg(long, default_value_t = 24)]
+        participant_lock:u16,
+    },
+    /// print the state of the swap
+    SwapStatus,
+    /// record the transaction paying A
+    SwapFundA{
+        txid:Txid,
+    },
+    /// check the confirmed transaction paying B against A before the initiator redeems it
+    SwapVerifyB{
+        txid:Txid,
+    },
+    /// the initiator redeems B, revealing the preimage
+    SwapRedeemB{
+        preimage:Preimage,
+        /// sats, paid out of B
+        #[arg(long, default_value_t = 1000)]
+        fee:u64,
+    },
+    /// the participant takes the preimage from B's redeem and redeems A with it
+    SwapRedeemA{
+        /// sats, paid out of A
+        #[arg(long, default_value_t = 1000)]
+        fee:u64,
+    },
+    /// fund an HTLC on regtest and refund it after the lock
+    AdHokTesting{
+        /// fund from a coin the miner wallet has, check the funding with testmempoolaccept and
+        /// the refund with the script interpreter, without broadcasting or mining anything
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 fn main() -> Result<()> {
     env_logger::init();
-    println!("Htlc using OP_CAT");
+    info!("Htlc using OP_CAT");
 
     let args = Cli::parse();
-    
+    match run(&args) {
+        Ok(report) => {
+            report.print(args.output);
+            Ok(())
+        }
+        Err(e) if args.output == OutputFormat::Json => {
+            Report::new("error").field("error", e.to_string()).print(args.output);
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn run(args:&Cli)-> Result<Report> {
     let settings = match Settings::from_toml_file(&args.settings_file) {
         Ok(settings) => settings,
         Err(e) => {
@@ -50,35 +385,59 @@ fn main() -> Result<()> {
             settings
         }
     };
-    match args.action {
-        Action::Deposit{refund_address,redeem_address,payment_hash} => deposit(&refund_address,&redeem_address,100 as i64,&payment_hash,&settings)?,
-        Action::AdHokTesting => ad_hoc_testing(&settings)?,
-    };
-    Ok(())
+    match &args.action {
+        Action::Deposit{refund_address,redeem_address,payment_hashes,hash_lock,size_guard,funding,randomize_nums,extra_leaves} => {
+            let mut redeem_config = RedeemConfig::with_hash_locks(*hash_lock,payment_hashes.clone())?;
+            redeem_config.set_size_guard(*size_guard);
+            deposit(refund_address,redeem_address,100,redeem_config,funding,*randomize_nums,extra_leaves.clone(),&args.contract_file,&settings)
+        },
+        Action::SubmarineSwap{invoice,refund_address,redeem_address,refund_lock,funding} => submarine_swap(invoice,refund_address,redeem_address,*refund_lock as i64,funding,&args.contract_file,&settings),
+        Action::RecordFunding{psbt,txid,dry_run} => record_funding(psbt.clone(),*txid,*dry_run,&args.contract_file,&settings),
+        Action::Redeem{preimages,fee,dry_run,psbt} => redeem(preimages,fee.map(Amount::from_sat),*dry_run,*psbt,&args.contract_file,&settings),
+        Action::Refund{fee,dry_run,psbt} => refund(fee.map(Amount::from_sat),*dry_run,*psbt,&args.contract_file,&settings),
+        Action::FinalizePsbt{psbt,dry_run} => finalize_psbt(psbt,*dry_run,&settings),
+        Action::NewSecret{random,recover} => new_secret(*random,*recover,&args.secrets_file),
+        Action::RevealSecret{id} => reveal_secret(id,&args.secrets_file),
+        Action::LimitVaultDeposit{owner_address,daily_limit,amount,period,max_fee} => limit_vault_deposit(owner_address,Amount::from_sat(*daily_limit),Amount::from_sat(*amount),*period,Amount::from_sat(*max_fee),&args.limit_vault_file,&settings),
+        Action::LimitVaultWithdraw{amount,fee,dry_run} => limit_vault_withdraw(Amount::from_sat(*amount),fee.map(Amount::from_sat),*dry_run,&args.limit_vault_file,&settings),
+        Action::Describe{descriptor,fee} => describe(descriptor.as_deref(),*fee,&args.contract_file,&settings),
+        Action::Verify{descriptor,fee,address,txid,amount,min_lock,redeemer_key} => verify(descriptor.as_deref(),*fee,address.as_deref(),*txid,Terms{amount:amount.map(Amount::from_sat),min_lock:*min_lock,redeemer_key:*redeemer_key},&args.contract_file,&settings),
+        Action::Analyze{descriptor,fee} => analyze(descriptor.as_deref(),Amount::from_sat(*fee),&args.contract_file,&settings),
+        Action::Steal{attacker_address,attempt,tree,fee} => steal(attacker_address,*attempt,*tree,Amount::from_sat(*fee),&args.contract_file,&settings),
+        Action::VaultDeposit{cold_address,amount,delay} => vault_deposit(cold_address,Amount::from_sat(*amount),*delay,&args.vault_file,&settings),
+        Action::VaultStatus => vault_status(&args.vault_file,&settings),
+        Action::VaultTrigger{target_address,fee,dry_run} => vault_trigger(target_address,fee.map(Amount::from_sat),*dry_run,&args.vault_file,&settings),
+        Action::VaultCancel{fee,dry_run} => vault_cancel(fee.map(Amount::from_sat),*dry_run,&args.vault_file,&settings),
+        Action::VaultComplete{fee,dry_run} => vault_complete(fee.map(Amount::from_sat),*dry_run,&args.vault_file,&settings),
+        Action::SwapNew{payment_hash,amount_a,amount_b,initiator_redeem_address,initiator_refund_address,participant_redeem_address,participant_refund_address,initiator_lock,participant_lock} => {
+            let initiator = swap_party(initiator_redeem_address,initiator_refund_address,&settings)?;
+            let participant = swap_party(participant_redeem_address,participant_refund_address,&settings)?;
+            let amounts = (Amount::from_sat(*amount_a),Amount::from_sat(*amount_b));
+            swap_new(payment_hash.clone(),initiator,participant,amounts,*initiator_lock as i64,*participant_lock as i64,&args.swap_file,&settings)
+        },
+        Action::SwapStatus => swap_status(&args.swap_file,&settings),
+        Action::SwapFundA{txid} => swap_fund_a(*txid,&args.swap_file,&settings),
+        Action::SwapVerifyB{txid} => swap_verify_b(*txid,&args.swap_file,&settings),
+        Action::SwapRedeemB{preimage,fee} => swap_redeem_b(*preimage,Amount::from_sat(*fee),&args.swap_file,&settings),
+        Action::SwapRedeemA{fee} => swap_redeem_a(Amount::from_sat(*fee),&args.swap_file,&settings),
+        Action::AdHokTesting{dry_run} => ad_hoc_testing(*dry_run,&settings),
+    }
 }
 
-fn deposit(refund_address:&str,redeem_address:&str,locktime:i64,payment_hash:&str,settings: &Settings)-> Result<()> {
-    let miner_wallet = Wallet::new("miner", &settings);
-    while miner_wallet.get_balance()? < Amount::from_btc(1.0f64)? {
-        debug!("Mining some blocks to get some coins");
-        miner_wallet.mine_blocks(Some(1))?;
-    };
-
-    println!("Miner wallet balance: {:?}", miner_wallet.get_new_address()?);
-
-    println!("Making htlc contract");
+#[allow(clippy::too_many_arguments)]
+fn deposit(refund_address:&str,redeem_address:&str,locktime:i64,redeem_config:RedeemConfig,funding:&PsbtFunding,randomize_nums:bool,extra_leaves:Vec<ExtraLeaf>,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    info!("Making htlc contract");
 
     let redeem_address = Address::from_str(redeem_address)?.require_network(settings.network)?;
 
     let refund_address = Address::from_str(refund_address)?.require_network(settings.network)?;
 
-    let redeem_config = RedeemConfig {
-        payment_hash: payment_hash.to_string(),
-        preimage: None,
-    };
 
+    for leaf in extra_leaves.iter() {
+        leaf.check_refund_lock(locktime)?;
+    }
     let refund_config = RefundConfig {
-        refund_address: refund_address,
+        refund_address,
         refund_lock: locktime,
     };
     let htlc_contract = HTLC {
@@ -86,53 +445,691 @@ fn deposit(refund_address:&str,redeem_address:&str,locktime:i64,payment_hash:&st
         redeem_address: Some(redeem_address),
         redeem_config: Some(redeem_config),
         refund_config: Some(refund_config),
+        nums_tweak: if randomize_nums { Some(NumsTweak::random()?) } else { None },
+        extra_leaves,
     };
+    fund_htlc(htlc_contract,Amount::from_sat(100_000_000),funding,contract_file,settings)
+}
+
+fn submarine_swap(invoice:&str,refund_address:&str,redeem_address:&str,locktime:i64,funding:&PsbtFunding,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let invoice = Bolt11Invoice::from_str(invoice)?;
+    let amount = invoice.amount().ok_or(anyhow!("invoice has no amount"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let redeem_address = Address::from_str(redeem_address)?.require_network(settings.network)?;
+    let refund_address = Address::from_str(refund_address)?.require_network(settings.network)?;
+    let htlc_contract = submarine_htlc(&invoice,settings.network,amount,redeem_address,refund_address,locktime,now)?;
+    info!("Making submarine swap htlc for payment hash {}", invoice.payment_hash);
+
+    let report = fund_htlc(htlc_contract,amount,funding,contract_file,settings)?;
+    Ok(report.field("payment_hash", invoice.payment_hash.to_string()))
+}
+
+/// pay `amount` into the contract from the miner wallet, or print a funding PSBT for it
+fn fund_htlc(mut htlc_contract:HTLC,amount:Amount,funding:&PsbtFunding,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
     let htlc_address:Address = htlc_contract.address(settings.network)?;
-    println!("htlc address: {:?}", htlc_address);
-    let deposit_tx = miner_wallet.send(&htlc_address, Amount::from_sat(100_000_000))?;
+    let mut report = Report::new("funded")
+        .say(format!("htlc address: {}", htlc_address))
+        .address(&htlc_address)
+        .field("amount", amount.to_sat());
+    if let Some(nums_tweak) = htlc_contract.nums_tweak {
+        report = report
+            .say(format!("internal key H + rG with r: {}", nums_tweak))
+            .field("nums_tweak", nums_tweak.to_string());
+    }
+
+    if funding.psbt {
+        // whoever signs and broadcasts this reports back with `record-funding`
+        let change_address = match &funding.change_address {
+            Some(address) => Some(Address::from_str(address)?.require_network(settings.network)?),
+            None => None,
+        };
+        let chain = chain::backend_from_settings(settings)?;
+        let funding_psbt = htlc::psbt::funding_psbt_from_chain(chain.as_ref(),&funding.inputs,htlc_address.script_pubkey(),amount,Amount::from_sat(funding.funding_fee),change_address.as_ref())?;
+        htlc_contract.to_file(contract_file)?;
+        let mut report = report
+            .say(format!("funding psbt: {}", funding_psbt))
+            .field("psbt", funding_psbt.to_string());
+        report.status = "awaiting_funding".to_string();
+        return Ok(report);
+    }
+
+    let miner_wallet = Wallet::new("miner", settings)?;
+    while miner_wallet.get_balance()? < amount {
+        debug!("Mining some blocks to get some coins");
+        miner_wallet.mine_blocks(Some(1))?;
+    };
+
+    debug!("Miner wallet balance: {}", miner_wallet.get_balance()?);
+
+    let deposit_tx = miner_wallet.send(&htlc_address, amount)?;
 
     let htlc_funded = HtlcFunded {
         htlc_outpoint: deposit_tx,
-        amount: Amount::from_sat(100_000_000),
+        amount,
     };
+    htlc_contract.htlc_funded_utxo = Some(htlc_funded);
     miner_wallet.mine_blocks(Some(1))?;
-    println!("Funding htlc contract {:?}",htlc_contract);
-    Ok(())
+    htlc_contract.to_file(contract_file)?;
+    debug!("Funding htlc contract {:?}",htlc_contract);
+    Ok(report
+        .say(format!("htlc funded at {}", deposit_tx))
+        .outpoint(deposit_tx))
 }
 
-fn ad_hoc_testing(settings: &Settings)-> Result<()>{
-    let preimage = "6644fd23b8327a04d86bdadbeba6903c1e9bfef68f9c9ee7c00cc8f59529430c";
-    let payment_hash = "7d71c056feba9afeb8ee135b8c83695b1ecf948a96d24494592a5743c6779a57";
-    let locktime = 20 as i64;
+fn record_funding(psbt:Option<String>,txid:Option<Txid>,dry_run:bool,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut htlc_contract = HTLC::from_file(contract_file)?;
+    let chain = chain::backend_from_settings(settings)?;
 
-    let miner_wallet = Wallet::new("miner", &settings);
-    while miner_wallet.get_balance()? < Amount::from_btc(2.0f64)? {
+    let funding_tx = match (psbt, txid) {
+        (Some(psbt), _) => {
+            let funding_tx = htlc::psbt::finalize_spend_psbt(Psbt::from_str(&psbt)?)?;
+            if dry_run {
+                return dry_run_report(&funding_tx,settings);
+            }
+            let txid = chain.broadcast(&funding_tx)?;
+            info!("broadcast funding transaction {}", txid);
+            funding_tx
+        }
+        (None, Some(txid)) => chain
+            .get_tx(&txid)?
+            .ok_or(anyhow!("funding transaction {} not found", txid))?,
+        (None, None) => return Err(anyhow!("either a signed psbt or a txid is required")),
+    };
+
+    let outpoint = htlc_contract.record_funding(&funding_tx)?;
+    htlc_contract.to_file(contract_file)?;
+    Ok(Report::new("funded")
+        .say(format!("htlc funded at {}", outpoint))
+        .outpoint(outpoint)
+        .tx(&funding_tx))
+}
+
+fn redeem(preimages:&[Preimage],fee:Option<Amount>,dry_run:bool,psbt:bool,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut htlc_contract = HTLC::from_file(contract_file)?;
+    let redeem_config = htlc_contract.redeem_config.as_mut().ok_or(anyhow!("the contract has no redeem config"))?;
+    for preimage in preimages {
+        redeem_config.set_preimage(*preimage)?;
+    }
+    if psbt {
+        return spend_psbt_report(&htlc_contract,SpendPath::Redeem,fee,settings);
+    }
+    let (redeem_tx,fee) = spend_htlc(&htlc_contract,SpendPath::Redeem,fee,settings)?;
+    if dry_run {
+        return dry_run_report(&redeem_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&redeem_tx)?;
+    // keep the preimages, the redeem reveals them anyway
+    htlc_contract.to_file(contract_file)?;
+    let redeem_address = htlc_contract.redeem_address.as_ref().ok_or(anyhow!("the contract has no redeem address"))?;
+    Ok(Report::new("redeemed")
+        .say(format!("htlc redeemed to {} in {}", redeem_address, txid))
+        .address(redeem_address)
+        .tx(&redeem_tx)
+        .fee(fee))
+}
+
+fn refund(fee:Option<Amount>,dry_run:bool,psbt:bool,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let htlc_contract = HTLC::from_file(contract_file)?;
+    if psbt {
+        return spend_psbt_report(&htlc_contract,SpendPath::Refund,fee,settings);
+    }
+    let (refund_tx,fee) = spend_htlc(&htlc_contract,SpendPath::Refund,fee,settings)?;
+    if dry_run {
+        return dry_run_report(&refund_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&refund_tx)?;
+    let refund_address = &htlc_contract.refund_config.as_ref().ok_or(anyhow!("the contract has no refund config"))?.refund_address;
+    Ok(Report::new("refunded")
+        .say(format!("htlc refunded to {} in {}", refund_address, txid))
+        .address(refund_address)
+        .tx(&refund_tx)
+        .fee(fee))
+}
+
+/// the tree the funding transaction of the contract pays
+fn funded_tree(htlc_contract:&HTLC,settings: &Settings)-> Result<FeeMode> {
+    let funded = htlc_contract.htlc_funded_utxo.as_ref().ok_or(anyhow!("the HTLC is not funded"))?;
+    let outpoint = funded.htlc_outpoint;
+    let funding_tx = chain::backend_from_settings(settings)?
+        .get_tx(&outpoint.txid)?
+        .ok_or(anyhow!("funding transaction {} not found", outpoint.txid))?;
+    let script_pubkey = &funding_tx.output[outpoint.vout as usize].script_pubkey;
+    if *script_pubkey == htlc_contract.address_with_fee(settings.network)?.script_pubkey() { Ok(FeeMode::Stack) } else { Ok(FeeMode::Input) }
+}
+
+/// the spend of the funded HTLC along `path` and its fee. The `_with_fee` leaves pay the fee out
+/// of the HTLC, the plain ones `deposit` funds get a fee input from the miner wallet
+fn spend_htlc(htlc_contract:&HTLC,path:SpendPath,fee:Option<Amount>,settings: &Settings)-> Result<(Transaction,Amount)> {
+    match funded_tree(htlc_contract,settings)? {
+        FeeMode::Stack => {
+            let fee = stack_fee(htlc_contract,path,fee,settings)?;
+            let tx = match path {
+                SpendPath::Redeem => htlc_contract.create_redeem_tx_with_fee(fee)?,
+                SpendPath::Refund => htlc_contract.create_refund_tx_with_fee(fee)?,
+            };
+            Ok((tx,fee))
+        }
+        FeeMode::Input => {
+            let tx = match path {
+                SpendPath::Redeem => htlc_contract.create_redeem_tx()?,
+                SpendPath::Refund => htlc_contract.create_refund_tx()?,
+            };
+            let fee = covenant_fee(fee,&tx,settings)?;
+            Ok((pay_covenant_fee(tx,fee,settings)?,fee))
+        }
+    }
+}
+
+/// `fee`, or the estimate for the spend along `path` whose `_with_fee` leaf pays it
+fn stack_fee(htlc_contract:&HTLC,path:SpendPath,fee:Option<Amount>,settings: &Settings)-> Result<Amount> {
+    if let Some(fee) = fee {
+        return Ok(fee);
+    }
+    // the fee only changes the output amount, a draft weighs what the spend will
+    let draft = match path {
+        SpendPath::Redeem => htlc_contract.create_redeem_tx_with_fee(Amount::ZERO)?,
+        SpendPath::Refund => htlc_contract.create_refund_tx_with_fee(Amount::ZERO)?,
+    };
+    estimate_fee(draft.weight(),settings)
+}
+
+/// the spend along `path` as a PSBT. On the plain tree its fee is left to the wallet adding an input
+fn spend_psbt_report(htlc_contract:&HTLC,path:SpendPath,fee:Option<Amount>,settings: &Settings)-> Result<Report> {
+    let fee = match funded_tree(htlc_contract,settings)? {
+        FeeMode::Stack => Some(stack_fee(htlc_contract,path,fee,settings)?),
+        FeeMode::Input => None,
+    };
+    let psbt = htlc_contract.create_spend_psbt(path,fee)?;
+    let mut report = Report::new("awaiting_signature")
+        .say(format!("spend psbt: {}", psbt))
+        .field("psbt", psbt.to_string());
+    report.fee = fee;
+    Ok(report)
+}
+
+fn finalize_psbt(psbt:&str,dry_run:bool,settings: &Settings)-> Result<Report> {
+    let spend_tx = htlc::psbt::finalize_spend_psbt(Psbt::from_str(psbt)?)?;
+    if dry_run {
+        return dry_run_report(&spend_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&spend_tx)?;
+    Ok(Report::new("broadcast")
+        .say(format!("spend broadcast in {}", txid))
+        .tx(&spend_tx))
+}
+
+fn new_secret(random:bool,recover:bool,secrets_file:&PathBuf)-> Result<Report> {
+    if recover && secrets_file.exists() {
+        return Err(anyhow!("{} already exists, a seed only applies to a new store", secrets_file.display()));
+    }
+    let seed = if recover { Some(secret::read_seed()?) } else { None };
+    let passphrase = secret::read_passphrase()?;
+    let mut store = if secrets_file.exists() {
+        SecretStore::from_file(secrets_file)?
+    } else {
+        info!("creating secret store {}", secrets_file.display());
+        SecretStore::create(&passphrase, seed)?
+    };
+
+    let entry = store.unlock(&passphrase)?.new_secret(random)?;
+    store.to_file(secrets_file)?;
+    Ok(Report::new("created")
+        .say(format!("secret {} payment hash: {}", entry.index, entry.payment_hash))
+        .field("index", entry.index)
+        .field("payment_hash", &entry.payment_hash))
+}
+
+fn reveal_secret(id:&str,secrets_file:&PathBuf)-> Result<Report> {
+    let mut store = SecretStore::from_file(secrets_file)?;
+    let preimage = store.unlock(&secret::read_passphrase()?)?.reveal(id)?;
+    Ok(Report::new("revealed")
+        .say(format!("preimage: {}", preimage))
+        .field("preimage", preimage))
+}
+
+fn limit_vault_deposit(owner_address:&str,daily_limit:Amount,amount:Amount,period:u16,max_fee:Amount,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let owner_address = Address::from_str(owner_address)?.require_network(settings.network)?;
+    let mut vault = DailyLimitVault::new(owner_address,daily_limit,period,max_fee)?;
+    if amount > MAX_VAULT_AMOUNT {
+        return Err(anyhow!("a vault holds at most {}", MAX_VAULT_AMOUNT));
+    }
+    let vault_address = vault.address(settings.network)?;
+    info!("vault address: {}", vault_address);
+
+    let miner_wallet = Wallet::new("miner", settings)?;
+    while miner_wallet.get_balance()? < amount {
         debug!("Mining some blocks to get some coins");
         miner_wallet.mine_blocks(Some(1))?;
     };
+    let outpoint = miner_wallet.send(&vault_address, amount)?;
+    miner_wallet.mine_blocks(Some(1))?;
+    vault.utxo = Some(VaultUtxo { outpoint, amount });
+    vault.to_file(vault_file)?;
+    Ok(Report::new("funded")
+        .say(format!("vault address: {}", vault_address))
+        .say(format!("vault funded at {}", outpoint))
+        .address(vault_address)
+        .outpoint(outpoint))
+}
 
-    // person who will deposit funds to htlc and redeem after timeout 
-    let initializer_wallet = Wallet::new("initializer", &settings);
+fn limit_vault_withdraw(amount:Amount,fee:Option<Amount>,dry_run:bool,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut vault = DailyLimitVault::from_file(vault_file)?;
+    let fee = match fee {
+        Some(fee) => fee,
+        // the fee only changes amounts, a draft paying the most it may weighs what the withdrawal will
+        None => estimate_fee(vault.create_withdrawal_tx(amount,vault.max_fee)?.weight(),settings)?,
+    };
+    let withdrawal_tx = vault.create_withdrawal_tx(amount,fee)?;
+    if dry_run {
+        return dry_run_report(&withdrawal_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&withdrawal_tx)?;
+    let outpoint = vault.record_funding(&withdrawal_tx)?;
+    vault.to_file(vault_file)?;
+    Ok(Report::new("withdrawn")
+        .say(format!("withdrew {} in {}, the vault continues at {}", amount, txid, outpoint))
+        .tx(&withdrawal_tx)
+        .fee(fee)
+        .outpoint(outpoint))
+}
 
-    // minner sending funds to initializer wallet
+fn describe(descriptor:Option<&str>,fee:Option<FeeMode>,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let descriptor = match descriptor {
+        Some(descriptor) => HtlcDescriptor::parse(descriptor,settings.network)?,
+        None => {
+            let htlc_contract = HTLC::from_file(contract_file)?;
+            let fee = match (fee, &htlc_contract.htlc_funded_utxo) {
+                (Some(fee), _) => fee,
+                (None, Some(_)) => funded_tree(&htlc_contract,settings)?,
+                (None, None) => FeeMode::default(),
+            };
+            HtlcDescriptor::from_htlc(&htlc_contract,fee)?
+        }
+    };
+    let address = descriptor.address(settings.network)?;
+    Ok(Report::new("described")
+        .say(descriptor.to_string())
+        .say(format!("address: {}", address))
+        .address(address)
+        .field("descriptor", descriptor.to_string()))
+}
+
+fn verify(descriptor:Option<&str>,fee:FeeMode,address:Option<&str>,txid:Option<Txid>,terms:Terms,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let descriptor = match descriptor {
+        Some(descriptor) => HtlcDescriptor::parse(descriptor,settings.network)?,
+        None => HtlcDescriptor::from_htlc(&HTLC::from_file(contract_file)?,fee)?,
+    };
+    let funding = match (address, txid) {
+        (Some(address), _) => Funding::Address(Address::from_str(address)?.require_network(settings.network)?),
+        (None, Some(txid)) => Funding::Tx(chain::backend_from_settings(settings)?
+            .get_tx(&txid)?
+            .ok_or(anyhow!("funding transaction {} not found", txid))?),
+        (None, None) => return Err(anyhow!("give the funder's address or funding txid")),
+    };
+    let verification = htlc::verify::verify(&descriptor,&funding,&terms,settings.network)?;
+    let status = if verification.is_valid() { "verified" } else { "mismatch" };
+    let mut report = Report::new(status).address(&verification.address);
+    // the extra leaves are spendable by their keys alone, the redeemer has to know whose they are
+    for leaf in descriptor.extra_leaves.iter() {
+        report = report.say(format!("extra leaf {}", leaf));
+    }
+    if let Some(outpoint) = verification.outpoint {
+        report = report.outpoint(outpoint);
+    }
+    if let Some(amount) = verification.amount {
+        report = report.field("amount", amount.to_sat());
+    }
+    for mismatch in verification.mismatches.iter() {
+        report = report.say(format!("MISMATCH: {}", mismatch));
+    }
+    if verification.is_valid() {
+        report = report.say(format!("{} matches the contract", verification.address));
+    }
+    let mismatches: Vec<String> = verification.mismatches.iter().map(|mismatch| mismatch.to_string()).collect();
+    Ok(report.field("mismatches", mismatches))
+}
+
+fn analyze(descriptor:Option<&str>,fee:Amount,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let htlc_contract = match descriptor {
+        Some(descriptor) => HtlcDescriptor::parse(descriptor,settings.network)?.to_htlc(),
+        None => HTLC::from_file(contract_file)?,
+    };
+    let analyses = htlc::analysis::analyze(&htlc_contract,Amount::from_sat(100_000_000),fee)?;
+    let mut report = Report::new("analyzed");
+    for analysis in analyses.iter() {
+        report = report.say(format!("{} ({} fee): script {} bytes, control block {} bytes, {} stack items of at most {} bytes, witness {} WU, spend {} vbytes",
+            analysis.leaf, analysis.fee, analysis.script_size, analysis.control_block_size, analysis.stack_items, analysis.max_element_size, analysis.witness_weight, analysis.spend_vbytes));
+        if analysis.preimage_size > 32 {
+            report = report.say(format!("{} ({} fee): without the size guard, sized for {} byte preimages", analysis.leaf, analysis.fee, analysis.preimage_size));
+        }
+    }
+    Ok(report.field("leaves", analyses))
+}
+
+fn steal(attacker_address:&str,attempt:Option<TheftAttempt>,tree:Option<SpendTree>,fee:Amount,contract_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let attacker_address = Address::from_str(attacker_address)?.require_network(settings.network)?;
+    let htlc_contract = HTLC::from_file(contract_file)?;
+    let attempts = match attempt {
+        Some(attempt) => vec![attempt],
+        None => TheftAttempt::ALL.to_vec(),
+    };
+    let trees = match tree {
+        Some(tree) => vec![tree],
+        None => SpendTree::ALL.to_vec(),
+    };
+    let mut report = Report::new("rejected");
+    let mut results = Vec::new();
+    let mut accepted = 0;
+    for (tree, attempt) in trees.iter().flat_map(|tree| attempts.iter().map(move |attempt| (*tree, *attempt))) {
+        let tx = attempt.build_tx(&htlc_contract,tree,&attacker_address,fee)?;
+        let rejection = check_spend(&htlc_contract,tree,&tx,settings.network)?.err();
+        report = match &rejection {
+            None => {
+                accepted += 1;
+                report.say(format!("{:?} {}: ACCEPTED by the interpreter, txid {}", tree, attempt.description(), tx.txid()))
+            }
+            Some(e) => report.say(format!("{:?} {}: rejected, {}", tree, attempt.description(), e)),
+        };
+        results.push(json!({
+            "tree": format!("{:?}", tree),
+            "attempt": attempt.description(),
+            "txid": tx.txid(),
+            "rejected": rejection.is_some(),
+            "reason": rejection.map(|e| e.to_string()),
+        }));
+    }
+    if accepted > 0 {
+        return Err(anyhow!("{} theft attempts would have succeeded", accepted));
+    }
+    Ok(report.field("attempts", results))
+}
+
+fn vault_deposit(cold_address:&str,amount:Amount,delay:u16,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let cold_address = Address::from_str(cold_address)?.require_network(settings.network)?;
+    let mut vault = DelayedVault::new(cold_address,delay)?;
+    let vault_address = vault.address(settings.network)?;
+    info!("vault address: {}", vault_address);
+
+    let miner_wallet = Wallet::new("miner", settings)?;
+    while miner_wallet.get_balance()? < amount {
+        debug!("Mining some blocks to get some coins");
+        miner_wallet.mine_blocks(Some(1))?;
+    };
+    let deposit = miner_wallet.send(&vault_address, amount)?;
+    miner_wallet.mine_blocks(Some(1))?;
+    let deposit_tx = miner_wallet.chain().get_tx(&deposit.txid)?
+        .ok_or(anyhow!("deposit transaction {} not found", deposit.txid))?;
+    let outpoint = vault.record_deposit(&deposit_tx)?;
+    vault.to_file(vault_file)?;
+    Ok(Report::new("vaulted")
+        .say(format!("vault address: {}", vault_address))
+        .say(format!("vault funded at {}", outpoint))
+        .address(vault_address)
+        .outpoint(outpoint))
+}
+
+fn vault_status(vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let vault = DelayedVault::from_file(vault_file)?;
+    let vault_address = vault.address(settings.network)?;
+    let status = match vault.state {
+        VaultState::Empty => "empty",
+        VaultState::Vaulted { .. } => "vaulted",
+        VaultState::Triggered { .. } => "triggered",
+        VaultState::Completed { .. } => "completed",
+        VaultState::Cancelled { .. } => "cancelled",
+    };
+    let report = Report::new(status)
+        .say(format!("vault address: {}", vault_address))
+        .say(format!("cold address: {}", vault.cold_address))
+        .address(vault_address)
+        .field("cold_address", vault.cold_address.to_string())
+        .field("delay", vault.delay);
+    let report = match &vault.state {
+        VaultState::Empty => report.say("not funded"),
+        VaultState::Vaulted { utxo } => report
+            .say(format!("{} vaulted at {}", utxo.amount, utxo.outpoint))
+            .outpoint(utxo.outpoint)
+            .field("amount", utxo.amount.to_sat()),
+        VaultState::Triggered { trigger_tx } => {
+            let target = Address::from_script(&trigger_tx.output[1].script_pubkey, settings.network)?;
+            let height = chain::backend_from_settings(settings)?.get_block_height()?;
+            report
+                .say(format!("withdrawal of {} to {} triggered in {}", trigger_tx.output[0].value, target, trigger_tx.txid()))
+                .say(format!("completes {} blocks after the trigger confirmed, the tip is at {}", vault.delay, height))
+                .tx(trigger_tx)
+                .field("target", target.to_string())
+                .field("amount", trigger_tx.output[0].value.to_sat())
+                .field("height", height)
+        }
+        VaultState::Completed { txid } => report.say(format!("withdrawn in {}", txid)).field("txid", txid),
+        VaultState::Cancelled { txid } => report.say(format!("cancelled to the cold address in {}", txid)).field("txid", txid),
+    };
+    Ok(report)
+}
+
+fn vault_trigger(target_address:&str,fee:Option<Amount>,dry_run:bool,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let target_address = Address::from_str(target_address)?.require_network(settings.network)?;
+    let mut vault = DelayedVault::from_file(vault_file)?;
+    let trigger_tx = vault.create_trigger_tx(&target_address)?;
+    let fee = covenant_fee(fee,&trigger_tx,settings)?;
+    // the fee input also pays for the output announcing the target
+    let announcement = trigger_tx.output[1].value;
+    let trigger_tx = pay_covenant_fee(trigger_tx, fee + announcement, settings)?;
+    if dry_run {
+        return dry_run_report(&trigger_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&trigger_tx)?;
+    vault.record_trigger(&trigger_tx)?;
+    vault.to_file(vault_file)?;
+    Ok(Report::new("triggered")
+        .say(format!("withdrawal to {} triggered in {}, complete it in {} blocks", target_address, txid, vault.delay))
+        .address(target_address)
+        .tx(&trigger_tx)
+        .fee(fee))
+}
+
+fn vault_cancel(fee:Option<Amount>,dry_run:bool,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut vault = DelayedVault::from_file(vault_file)?;
+    let cancel_tx = vault.create_cancel_tx()?;
+    let fee = covenant_fee(fee,&cancel_tx,settings)?;
+    let cancel_tx = pay_covenant_fee(cancel_tx, fee, settings)?;
+    if dry_run {
+        return dry_run_report(&cancel_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&cancel_tx)?;
+    vault.record_spend(&cancel_tx)?;
+    vault.to_file(vault_file)?;
+    Ok(Report::new("cancelled")
+        .say(format!("vault cancelled to {} in {}", vault.cold_address, txid))
+        .address(&vault.cold_address)
+        .tx(&cancel_tx)
+        .fee(fee))
+}
+
+fn vault_complete(fee:Option<Amount>,dry_run:bool,vault_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut vault = DelayedVault::from_file(vault_file)?;
+    let complete_tx = vault.create_complete_tx()?;
+    let fee = covenant_fee(fee,&complete_tx,settings)?;
+    let complete_tx = pay_covenant_fee(complete_tx, fee, settings)?;
+    if dry_run {
+        return dry_run_report(&complete_tx,settings);
+    }
+    let txid = chain::backend_from_settings(settings)?.broadcast(&complete_tx)?;
+    vault.record_spend(&complete_tx)?;
+    vault.to_file(vault_file)?;
+    Ok(Report::new("completed")
+        .say(format!("withdrawal completed in {}", txid))
+        .tx(&complete_tx)
+        .fee(fee))
+}
+
+fn swap_party(redeem_address:&str,refund_address:&str,settings: &Settings)-> Result<SwapParty> {
+    Ok(SwapParty {
+        redeem_address: Address::from_str(redeem_address)?.require_network(settings.network)?,
+        refund_address: Address::from_str(refund_address)?.require_network(settings.network)?,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn swap_new(payment_hash:PaymentHash,initiator:SwapParty,participant:SwapParty,amounts:(Amount,Amount),initiator_lock:i64,participant_lock:i64,swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let swap = AtomicSwap::new(payment_hash,initiator,participant,amounts,initiator_lock,participant_lock)?;
+    swap.to_file(swap_file)?;
+    let address_a = swap.address_a(settings.network)?;
+    let address_b = swap.address_b(settings.network)?;
+    Ok(Report::new("created")
+        .say(format!("the initiator pays {} to A at {}", swap.amount_a, address_a))
+        .say(format!("the participant pays {} to B at {} once A is funded", swap.amount_b, address_b))
+        .field("address_a", address_a.to_string())
+        .field("address_b", address_b.to_string()))
+}
+
+fn swap_status(swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let swap = AtomicSwap::from_file(swap_file)?;
+    let status = match swap.state {
+        SwapState::Created => "created",
+        SwapState::AFunded { .. } => "a_funded",
+        SwapState::BVerified => "b_verified",
+        SwapState::BRedeemed { .. } => "b_redeemed",
+        SwapState::PreimageExtracted => "preimage_extracted",
+        SwapState::ARedeemed { .. } => "a_redeemed",
+    };
+    let address_a = swap.address_a(settings.network)?;
+    let address_b = swap.address_b(settings.network)?;
+    Ok(Report::new(status)
+        .say(format!("swap {}", swap.state))
+        .say(format!("A: {} at {}", swap.amount_a, address_a))
+        .say(format!("B: {} at {}", swap.amount_b, address_b))
+        .field("address_a", address_a.to_string())
+        .field("address_b", address_b.to_string())
+        .field("amount_a", swap.amount_a.to_sat())
+        .field("amount_b", swap.amount_b.to_sat()))
+}
+
+/// the funding transaction `txid` from the chain backend
+fn swap_funding_tx(txid:Txid,settings: &Settings)-> Result<Transaction> {
+    chain::backend_from_settings(settings)?.get_tx(&txid)?.ok_or(anyhow!("funding transaction {} not found", txid))
+}
+
+fn swap_fund_a(txid:Txid,swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut swap = AtomicSwap::from_file(swap_file)?;
+    let funding_tx = swap_funding_tx(txid,settings)?;
+    let outpoint = swap.fund_a(chain::backend_from_settings(settings)?.as_ref(),&funding_tx)?;
+    swap.to_file(swap_file)?;
+    Ok(Report::new("a_funded")
+        .say(format!("A funded at {}, the participant pays B next", outpoint))
+        .outpoint(outpoint))
+}
+
+fn swap_verify_b(txid:Txid,swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut swap = AtomicSwap::from_file(swap_file)?;
+    let funding_tx = swap_funding_tx(txid,settings)?;
+    let outpoint = swap.verify_b(chain::backend_from_settings(settings)?.as_ref(),&funding_tx)?;
+    swap.to_file(swap_file)?;
+    Ok(Report::new("b_verified")
+        .say(format!("B funded at {} with a safe refund lock, the initiator can redeem it", outpoint))
+        .outpoint(outpoint))
+}
+
+fn swap_redeem_b(preimage:Preimage,fee:Amount,swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut swap = AtomicSwap::from_file(swap_file)?;
+    let txid = swap.redeem_b(chain::backend_from_settings(settings)?.as_ref(),preimage,fee)?;
+    swap.to_file(swap_file)?;
+    Ok(Report::new("b_redeemed")
+        .say(format!("B redeemed in {}, the preimage is public now", txid))
+        .field("txid", txid)
+        .fee(fee))
+}
+
+fn swap_redeem_a(fee:Amount,swap_file:&PathBuf,settings: &Settings)-> Result<Report> {
+    let mut swap = AtomicSwap::from_file(swap_file)?;
+    let chain = chain::backend_from_settings(settings)?;
+    let preimage = swap.extract_preimage(chain.as_ref())?;
+    // keep the preimage even if the redeem fails, it is all A needs
+    swap.to_file(swap_file)?;
+    let txid = swap.redeem_a(chain.as_ref(),fee)?;
+    swap.to_file(swap_file)?;
+    Ok(Report::new("a_redeemed")
+        .say(format!("preimage {} taken from B's redeem", preimage))
+        .say(format!("A redeemed in {}", txid))
+        .field("txid", txid)
+        .fee(fee))
+}
+
+/// what testmempoolaccept thinks of `tx`, for the `--dry-run` of the spend commands
+fn dry_run_report(tx:&Transaction,settings: &Settings)-> Result<Report> {
+    let result = Wallet::new("miner", settings)?.test_accept(tx)?;
+    let mut report = Report::new(if result.allowed { "accepted" } else { "rejected" })
+        .say(format!("dry run: {}", result))
+        .tx(tx)
+        .field("vsize", result.vsize)
+        .field("reject_reason", &result.reject_reason);
+    report.fee = result.fee;
+    Ok(report)
+}
+
+/// blocks an estimated fee aims to confirm in
+const FEE_TARGET_BLOCKS: u16 = 6;
+
+/// the miner wallet's P2WPKH fee input and change output, added to a covenant spend after grinding
+const FEE_INPUT_WEIGHT: Weight = Weight::from_vb_unchecked(68 + 31);
+
+/// the fee the chain backend's fee rate asks of a transaction of `weight`
+fn estimate_fee(weight:Weight,settings: &Settings)-> Result<Amount> {
+    let fee_rate = chain::backend_from_settings(settings)?.estimate_fee_rate(FEE_TARGET_BLOCKS)?;
+    let fee = fee_rate.fee_wu(weight).ok_or(anyhow!("a fee of {} for {} overflows", fee_rate, weight))?;
+    debug!("estimated a fee of {} at {}", fee, fee_rate);
+    Ok(fee)
+}
+
+/// `fee`, or the estimate for `tx` once `pay_covenant_fee` added its fee input
+fn covenant_fee(fee:Option<Amount>,tx:&Transaction,settings: &Settings)-> Result<Amount> {
+    match fee {
+        Some(fee) => Ok(fee),
+        None => estimate_fee(tx.weight() + FEE_INPUT_WEIGHT,settings),
+    }
+}
+
+/// add a miner wallet input paying `fee` to a covenant spend signed with SIGHASH_SINGLE|ANYONECANPAY.
+/// The input is a confirmed coin the wallet already has, so nothing is sent or mined
+fn pay_covenant_fee(mut tx:Transaction,fee:Amount,settings: &Settings)-> Result<Transaction> {
+    let miner_wallet = Wallet::new("miner", settings)?;
+    let covenant_outpoint = tx.input[0].previous_output;
+    let covenant_prevout = miner_wallet.chain()
+        .get_tx(&covenant_outpoint.txid)?
+        .ok_or(anyhow!("transaction {} not found", covenant_outpoint.txid))?
+        .output[covenant_outpoint.vout as usize]
+        .clone();
+
+    // leave some change so the fee input doesn't go to dust
+    let fee_amount = fee + Amount::from_sat(10_000);
+    let (fee_outpoint, fee_prevout) = miner_wallet.find_utxo(fee_amount)?
+        .ok_or(anyhow!("the miner wallet has no confirmed coin of {} to pay the fee", fee_amount))?;
+    let change_address = miner_wallet.get_new_address()?;
+    add_fee_to_txn(&mut tx, fee_outpoint, fee_prevout.value, fee, change_address)?;
+    miner_wallet.sign_tx_with_prevouts(&tx, &[(covenant_outpoint, covenant_prevout)])
+}
+
+fn ad_hoc_testing(dry_run:bool,settings: &Settings)-> Result<Report>{
+    let preimage = secret::random_preimage()?;
+    let locktime = 20;
+
+    let miner_wallet = Wallet::new("miner", settings)?;
+
+    // person who will deposit funds to htlc and redeem after timeout 
+    let initializer_wallet = Wallet::new("initializer", settings)?;
     let initializer_address = initializer_wallet.get_new_address()?;
-    let deposit_tx = miner_wallet.send(&initializer_address, Amount::from_sat(100_000_000))?;
-    miner_wallet.mine_blocks(Some(1))?; // confirm the transfer to initializer wallet
-    println!("initializer address balance: {:?}", initializer_wallet.get_balance()?);
 
     // person who will redeem from the htlc 
-    let redeem_wallet = Wallet::new("redeem", &settings);
+    let redeem_wallet = Wallet::new("redeem", settings)?;
 
     //setting redeem config
-    let redeem_config = RedeemConfig {
-        payment_hash: payment_hash.to_string(),
-        preimage: Some(preimage.to_string()),
-    };
+    let redeem_config = RedeemConfig::new(preimage.payment_hash(), Some(preimage))?;
 
     let redeemer_address = redeem_wallet.get_new_address()?;
     //setting refund config
     let refund_config = RefundConfig {
-        refund_address: initializer_address,
+        refund_address: initializer_address.clone(),
         refund_lock: locktime,
     };
 
@@ -142,18 +1139,33 @@ fn ad_hoc_testing(settings: &Settings)-> Result<()>{
         redeem_address: Some(redeemer_address),
         redeem_config: Some(redeem_config),
         refund_config: Some(refund_config),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
     };
+    if dry_run {
+        return ad_hoc_dry_run(htlc_contract,&miner_wallet,settings);
+    }
+
+    while miner_wallet.get_balance()? < Amount::from_btc(2.0f64)? {
+        debug!("Mining some blocks to get some coins");
+        miner_wallet.mine_blocks(Some(1))?;
+    };
+
+    // minner sending funds to initializer wallet
+    miner_wallet.send(&initializer_address, Amount::from_sat(100_000_000))?;
+    miner_wallet.mine_blocks(Some(1))?; // confirm the transfer to initializer wallet
+    info!("initializer address balance: {:?}", initializer_wallet.get_balance()?);
 
     //creating htlc address
     let htlc_address:Address = htlc_contract.address_with_fee(settings.network)?;
-    println!("htlc address: {:?}", htlc_address);
+    info!("htlc address: {:?}", htlc_address);
 
     //sending funds to htlc address
     let deposit_tx = initializer_wallet.send(&htlc_address, Amount::from_sat(100_000_000))?;
     miner_wallet.mine_blocks(Some(1))?; // confirm the deposit transaction
 
     //checking the initializer balance 
-    println!("initializer balance: {:?}", initializer_wallet.get_balance()?);
+    info!("initializer balance: {:?}", initializer_wallet.get_balance()?);
 
     //htlc funded info
     let htlc_funded = HtlcFunded {
@@ -175,17 +1187,15 @@ fn ad_hoc_testing(settings: &Settings)-> Result<()>{
 
     //creating creating refund trantion with fee
     //creatint 101 block to get utxo to be refundable
-    for i in 0..102 {
+    for _ in 0..102 {
         miner_wallet.mine_blocks(Some(1))?;
     }
-    let mut fee_amount = Amount::from_sat(1000);
-    let mut refund_tx:Transaction = htlc_contract.create_refund_tx_with_fee(fee_amount)?;
-    let mut serialized_tx = Vec::new();
-    refund_tx.consensus_encode(&mut serialized_tx).unwrap();
-    let txid = redeem_wallet.broadcast_tx(&serialized_tx, None)?;
+    let fee_amount = Amount::from_sat(1000);
+    let refund_tx:Transaction = htlc_contract.create_refund_tx_with_fee(fee_amount)?;
+    let txid = redeem_wallet.chain().broadcast(&refund_tx)?;
     miner_wallet.mine_blocks(Some(1))?;
-    println!("sent refund transaction txid: {}", txid);
-    println!("refund address balance: {:?}", initializer_wallet.get_balance()?);
+    info!("sent refund transaction txid: {}", txid);
+    info!("refund address balance: {:?}", initializer_wallet.get_balance()?);
 
     // //fee wallet
     // let fee_wallet = Wallet::new("fee", &settings);
@@ -208,7 +1218,37 @@ fn ad_hoc_testing(settings: &Settings)-> Result<()>{
 
 
 
-    Ok(())
+    Ok(Report::new("refunded").tx(&refund_tx))
+}
+
+/// fund the ad hoc HTLC from a coin the miner wallet already has, then check the funding with
+/// testmempoolaccept and the refund with the script interpreter. Nothing is broadcast or mined,
+/// so the refund lock is left to the chain
+fn ad_hoc_dry_run(mut htlc_contract:HTLC,miner_wallet:&Wallet,settings: &Settings)-> Result<Report> {
+    let amount = Amount::from_sat(100_000_000);
+    let funding_fee = Amount::from_sat(1000);
+    let (outpoint, prevout) = miner_wallet.find_utxo(amount + funding_fee)?
+        .ok_or(anyhow!("the miner wallet has no confirmed coin of {}, a dry run doesn't mine one", amount + funding_fee))?;
+    let change_value = prevout.value - amount - funding_fee;
+    let change = if change_value > Amount::ZERO {
+        Some(TxOut { value: change_value, script_pubkey: miner_wallet.get_new_address()?.script_pubkey() })
+    } else {
+        None
+    };
+    let htlc_address = htlc_contract.address_with_fee(settings.network)?;
+    let funding_psbt = htlc::psbt::funding_psbt(&[(outpoint, prevout)], htlc_address.script_pubkey(), amount, change)?;
+    let funding_tx = htlc::psbt::finalize_spend_psbt(miner_wallet.process_psbt(&funding_psbt)?)?;
+
+    let htlc_outpoint = htlc_contract.record_funding(&funding_tx)?;
+    let refund_tx = htlc_contract.create_refund_tx_with_fee(Amount::from_sat(1000))?;
+    let prevouts = [funding_tx.output[htlc_outpoint.vout as usize].clone()];
+    let refund = match interpreter::verify_input(&refund_tx, 0, &prevouts) {
+        Ok(()) => "passes".to_string(),
+        Err(e) => format!("fails ({})", e),
+    };
+    Ok(dry_run_report(&funding_tx,settings)?
+        .say(format!("the refund {} the script interpreter", refund))
+        .field("refund_raw_tx", bitcoin::consensus::encode::serialize_hex(&refund_tx)))
 }
 
 // fn main() {
@@ -0,0 +1,174 @@
+//! The unspendable internal key of every taproot output in this crate.
+//!
+//! BIP341 suggests H = lift_x(sha256(G uncompressed)), a point nobody knows the discrete log of,
+//! so the outputs have no key path. Every HTLC using H is recognizable once its internal key is
+//! revealed by a script path spend, so an HTLC can instead use H + rG for a random `r`. The `r`
+//! goes to the counterparty with the rest of the contract, who recomputes the key and so knows
+//! there is still no key path. Anyone without `r` sees an ordinary looking key.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::key::Parity;
+use bitcoin::secp256k1::constants::{GENERATOR_X, GENERATOR_Y};
+use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1};
+use bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::secret::{self, SecretError};
+
+#[derive(Debug)]
+pub(crate) enum NumsError {
+    Hex(hex::FromHexError),
+    InvalidLength(usize),
+    /// not below the curve order
+    OutOfRange,
+    Random(SecretError),
+}
+
+impl fmt::Display for NumsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumsError::Hex(e) => write!(f, "invalid NUMS tweak hex: {}", e),
+            NumsError::InvalidLength(length) => {
+                write!(f, "NUMS tweak is {} bytes, expected 32", length)
+            }
+            NumsError::OutOfRange => write!(f, "NUMS tweak is not below the curve order"),
+            NumsError::Random(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for NumsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NumsError::Hex(e) => Some(e),
+            NumsError::Random(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// the `r` of H + rG
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct NumsTweak([u8; 32]);
+
+impl NumsTweak {
+    /// a tweak from the OS CSPRNG
+    pub(crate) fn random() -> Result<Self, NumsError> {
+        NumsTweak::from_byte_array(secret::random_bytes().map_err(NumsError::Random)?)
+    }
+
+    pub(crate) fn from_byte_array(bytes: [u8; 32]) -> Result<Self, NumsError> {
+        Scalar::from_be_bytes(bytes).map_err(|_| NumsError::OutOfRange)?;
+        Ok(NumsTweak(bytes))
+    }
+
+    pub(crate) fn to_byte_array(self) -> [u8; 32] {
+        self.0
+    }
+
+    fn scalar(&self) -> Scalar {
+        Scalar::from_be_bytes(self.0).expect("checked on construction")
+    }
+}
+
+impl FromStr for NumsTweak {
+    type Err = NumsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(NumsError::Hex)?;
+        let bytes = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| NumsError::InvalidLength(bytes.len()))?;
+        NumsTweak::from_byte_array(bytes)
+    }
+}
+
+impl TryFrom<String> for NumsTweak {
+    type Error = NumsError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<NumsTweak> for String {
+    fn from(value: NumsTweak) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for NumsTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for NumsTweak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NumsTweak({})", self)
+    }
+}
+
+/// H, lift_x(sha256(G uncompressed)), derived from the secp256k1 constants
+pub(crate) fn nums_point() -> XOnlyPublicKey {
+    let mut generator = vec![0x04];
+    generator.extend_from_slice(&GENERATOR_X);
+    generator.extend_from_slice(&GENERATOR_Y);
+    let hash = sha256::Hash::hash(&generator);
+    XOnlyPublicKey::from_slice(hash.as_byte_array())
+        .expect("the hash of G is the x coordinate of a curve point")
+}
+
+/// H, or H + rG with `tweak` as r
+pub(crate) fn internal_key(tweak: Option<&NumsTweak>) -> XOnlyPublicKey {
+    let nums = nums_point();
+    match tweak {
+        None => nums,
+        Some(tweak) => {
+            PublicKey::from_x_only_public_key(nums, Parity::Even)
+                .add_exp_tweak(&Secp256k1::verification_only(), &tweak.scalar())
+                .expect("nobody knows the discrete log of H to cancel it out")
+                .x_only_public_key()
+                .0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nums_point_is_the_one_of_bip341() {
+        assert_eq!(
+            nums_point().to_string(),
+            "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0"
+        );
+    }
+
+    #[test]
+    fn tweaked_key_is_h_plus_rg() {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let tweak = NumsTweak::from_byte_array(one).unwrap();
+        let secp = Secp256k1::new();
+        let g = PublicKey::from_secret_key(
+            &secp,
+            &bitcoin::secp256k1::SecretKey::from_slice(&one).unwrap(),
+        );
+        let h = PublicKey::from_x_only_public_key(nums_point(), Parity::Even);
+        assert_eq!(
+            internal_key(Some(&tweak)),
+            h.combine(&g).unwrap().x_only_public_key().0
+        );
+        assert_ne!(
+            internal_key(Some(&NumsTweak::random().unwrap())),
+            nums_point()
+        );
+        assert!(NumsTweak::from_byte_array([0xff; 32]).is_err());
+        assert_eq!(tweak.to_string().parse::<NumsTweak>().unwrap(), tweak);
+    }
+}
@@ -0,0 +1,123 @@
+//! What a command prints once it is done, as text for people or as one JSON document for scripts.
+//!
+//! Commands return a `Report` instead of printing, everything printed on the way goes through
+//! `log` so stdout only ever holds the report.
+
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::{Amount, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::jsonrpc::serde_json::{self, Map, Value};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// the result of a command. The common fields have fixed names, anything else goes in `extra`
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Report {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outpoint: Option<OutPoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<Txid>,
+    /// the consensus serialized transaction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_tx: Option<String>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        with = "bitcoin::amount::serde::as_sat::opt"
+    )]
+    pub fee: Option<Amount>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+    /// the text output, one line each
+    #[serde(skip)]
+    lines: Vec<String>,
+}
+
+impl Report {
+    pub(crate) fn new(status: &str) -> Self {
+        Report {
+            status: status.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// add a line to the text output
+    pub(crate) fn say(mut self, line: impl Into<String>) -> Self {
+        self.lines.push(line.into());
+        self
+    }
+
+    pub(crate) fn address(mut self, address: impl ToString) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    pub(crate) fn outpoint(mut self, outpoint: OutPoint) -> Self {
+        self.outpoint = Some(outpoint);
+        self
+    }
+
+    /// the txid and the raw transaction of `tx`
+    pub(crate) fn tx(mut self, tx: &Transaction) -> Self {
+        self.txid = Some(tx.txid());
+        self.raw_tx = Some(serialize_hex(tx));
+        self
+    }
+
+    pub(crate) fn fee(mut self, fee: Amount) -> Self {
+        self.fee = Some(fee);
+        self
+    }
+
+    /// a command specific field, only in the JSON output
+    pub(crate) fn field(mut self, key: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("report fields serialize to JSON");
+        self.extra.insert(key.to_string(), value);
+        self
+    }
+
+    pub(crate) fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                for line in self.lines.iter() {
+                    println!("{}", line);
+                }
+            }
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(self).expect("reports serialize to JSON")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_report_has_only_the_fields_set() {
+        let report = Report::new("funded")
+            .say("htlc funded")
+            .outpoint(OutPoint::null())
+            .fee(Amount::from_sat(1000))
+            .field("payment_hash", "00");
+        let json: Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "status": "funded",
+                "outpoint": OutPoint::null().to_string(),
+                "fee": 1000,
+                "payment_hash": "00",
+            })
+        );
+    }
+}
@@ -0,0 +1,589 @@
+//! End to end tests against a real OP_CAT enabled bitcoind.
+//!
+//! Set `OP_CAT_BITCOIND` to the `bitcoind` binary built by `scripts/build_bitcoincore.sh`
+//! (`just test-regtest` does this). Each test starts its own node in a fresh temporary datadir on
+//! free ports and tears it down afterwards, so the tests can run in parallel. Without the
+//! variable the tests are skipped.
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bitcoin::psbt::{self, Psbt};
+use bitcoin::{Amount, Network, OutPoint, TxIn, TxOut};
+use bitcoincore_rpc::RpcApi;
+
+use crate::atomic_swap::{AtomicSwap, SwapParty};
+use crate::chain::bitcoind::BitcoindError;
+use crate::htlc::contract::{RedeemConfig, RefundConfig, HTLC};
+use crate::htlc::psbt::{finalize_spend_psbt, funding_psbt_from_chain};
+use crate::settings::Settings;
+use crate::testing::{FEE, HTLC_AMOUNT, PAYMENT_HASH, PREIMAGE, REFUND_LOCK};
+use crate::wallet::Wallet;
+
+const BITCOIND_ENV: &str = "OP_CAT_BITCOIND";
+
+static NODE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A bitcoind child process owning its datadir
+struct Bitcoind {
+    child: Child,
+    datadir: PathBuf,
+    settings: Settings,
+}
+
+impl Bitcoind {
+    /// start a node, or `None` when `OP_CAT_BITCOIND` is not set
+    fn start() -> Option<Self> {
+        Self::start_with_txindex(true)
+    }
+
+    /// start a node that only knows mempool and wallet transactions
+    fn start_without_txindex() -> Option<Self> {
+        Self::start_with_txindex(false)
+    }
+
+    fn start_with_txindex(txindex: bool) -> Option<Self> {
+        let Some(binary) = std::env::var_os(BITCOIND_ENV) else {
+            eprintln!("{} not set, skipping regtest test", BITCOIND_ENV);
+            return None;
+        };
+
+        let datadir = std::env::temp_dir().join(format!(
+            "cat-htlc-regtest-{}-{}",
+            std::process::id(),
+            NODE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&datadir);
+        std::fs::create_dir_all(&datadir).expect("could not create datadir");
+        let rpc_port = free_port();
+
+        let child = Command::new(binary)
+            .arg("-regtest")
+            .arg("-server=1")
+            .arg(format!("-txindex={}", txindex as u8))
+            .arg("-listen=0")
+            .arg("-fallbackfee=0.0001")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("could not start bitcoind");
+
+        // authenticate with the cookie bitcoind writes into the datadir
+        let settings = Settings {
+            network: Network::Regtest,
+            rpc_port: Some(rpc_port),
+            bitcoin_datadir: Some(datadir.clone()),
+            ..Settings::default()
+        };
+        let node = Bitcoind {
+            child,
+            datadir,
+            settings,
+        };
+        node.wait_for_rpc().expect("bitcoind did not come up");
+        Some(node)
+    }
+
+    fn wait_for_rpc(&self) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let ready = Wallet::create_rpc_client(&self.settings, None)
+                .map_err(anyhow::Error::from)
+                .and_then(|client| Ok(client.get_blockchain_info()?));
+            match ready {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() > deadline => return Err(e),
+                Err(_) => std::thread::sleep(Duration::from_millis(200)),
+            }
+        }
+    }
+
+    fn wallet(&self, name: &str) -> Wallet {
+        Wallet::new(name, &self.settings).expect("could not open wallet")
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        if let Ok(client) = Wallet::create_rpc_client(&self.settings, None) {
+            let _ = client.stop();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("could not find a free port")
+}
+
+/// The wallets of one scenario and the funded HTLC between them
+struct Scenario {
+    miner: Wallet,
+    initializer: Wallet,
+    redeem: Wallet,
+    htlc: HTLC,
+}
+
+/// mine coins, move them to the initializer and let it fund an HTLC redeemable with `preimage`
+fn funded_htlc(node: &Bitcoind, preimage: &str) -> Result<Scenario> {
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+
+    let initializer = node.wallet("initializer");
+    let initializer_address = initializer.get_new_address()?;
+    miner.send(&initializer_address, HTLC_AMOUNT + Amount::from_sat(100_000))?;
+    miner.mine_blocks(Some(1))?;
+
+    let redeem = node.wallet("redeem");
+    let mut htlc = HTLC {
+        htlc_funded_utxo: None,
+        redeem_address: Some(redeem.get_new_address()?),
+        redeem_config: Some(RedeemConfig::new_unchecked(
+            PAYMENT_HASH.parse().unwrap(),
+            Some(preimage.parse().unwrap()),
+        )),
+        refund_config: Some(RefundConfig {
+            refund_address: initializer_address,
+            refund_lock: REFUND_LOCK,
+        }),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
+    };
+
+    let htlc_address = htlc.address_with_fee(Network::Regtest)?;
+    let outpoint = initializer.send(&htlc_address, HTLC_AMOUNT)?;
+    miner.mine_blocks(Some(1))?;
+    htlc.set_funded_htlc(outpoint, HTLC_AMOUNT);
+
+    Ok(Scenario {
+        miner,
+        initializer,
+        redeem,
+        htlc,
+    })
+}
+
+#[test]
+fn regtest_redeem() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+
+    let redeem_tx = scenario.htlc.create_redeem_tx_with_fee(FEE)?;
+    scenario.redeem.chain().broadcast(&redeem_tx)?;
+    scenario.miner.mine_blocks(Some(1))?;
+
+    assert_eq!(scenario.redeem.get_balance()?, HTLC_AMOUNT - FEE);
+    Ok(())
+}
+
+#[test]
+fn regtest_refund() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+    let balance_before = scenario.initializer.get_balance()?;
+
+    scenario.miner.mine_blocks(Some(REFUND_LOCK as u64))?;
+    let refund_tx = scenario.htlc.create_refund_tx_with_fee(FEE)?;
+    scenario.initializer.chain().broadcast(&refund_tx)?;
+    scenario.miner.mine_blocks(Some(1))?;
+
+    assert_eq!(
+        scenario.initializer.get_balance()?,
+        balance_before + HTLC_AMOUNT - FEE
+    );
+    Ok(())
+}
+
+#[test]
+fn regtest_early_refund_is_rejected() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+
+    let refund_tx = scenario.htlc.create_refund_tx_with_fee(FEE)?;
+    let err = scenario
+        .initializer
+        .chain()
+        .broadcast(&refund_tx)
+        .err()
+        .ok_or(anyhow!("refund before the lock was accepted"))?;
+    assert!(err.to_string().contains("non-BIP68-final"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn regtest_test_accept_does_not_broadcast() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+
+    let refund_tx = scenario.htlc.create_refund_tx_with_fee(FEE)?;
+    let result = scenario.initializer.test_accept(&refund_tx)?;
+    assert!(!result.allowed);
+    assert_eq!(result.reject_reason.as_deref(), Some("non-BIP68-final"));
+
+    let redeem_tx = scenario.htlc.create_redeem_tx_with_fee(FEE)?;
+    let result = scenario.redeem.test_accept(&redeem_tx)?;
+    assert!(result.allowed, "{}", result);
+    assert_eq!(result.fee, Some(FEE));
+    assert_eq!(result.vsize, Some(redeem_tx.vsize() as u64));
+    assert_eq!(scenario.redeem.chain().get_tx(&redeem_tx.txid())?, None);
+    Ok(())
+}
+
+#[test]
+fn regtest_lookups_without_txindex() -> Result<()> {
+    let Some(node) = Bitcoind::start_without_txindex() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+    let chain = scenario.miner.chain();
+    let funding = scenario.htlc.htlc_funded_utxo.as_ref().unwrap().htlc_outpoint;
+
+    // the funding is confirmed, only an index finds it
+    let err = chain.get_tx(&funding.txid).unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref(),
+            Some(BitcoindError::TxindexRequired(txid)) if *txid == funding.txid
+        ),
+        "{}",
+        err
+    );
+    assert!(chain.get_confirmation_height(&funding.txid).is_err());
+
+    // the mempool, the utxo set and blocks don't need one
+    assert_eq!(chain.find_spend(&funding)?, None);
+    let redeem_tx = scenario.htlc.create_redeem_tx_with_fee(FEE)?;
+    chain.broadcast(&redeem_tx)?;
+    assert_eq!(chain.get_tx(&redeem_tx.txid())?, Some(redeem_tx.clone()));
+    assert_eq!(chain.get_confirmation_height(&redeem_tx.txid())?, None);
+    assert_eq!(chain.find_spend(&funding)?, Some(redeem_tx.clone()));
+    scenario.miner.mine_blocks(Some(1))?;
+    assert_eq!(chain.find_spend(&funding)?, Some(redeem_tx));
+    Ok(())
+}
+
+#[test]
+fn regtest_find_utxo_spends_nothing() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+    let height = scenario.initializer.chain().get_block_height()?;
+    let balance = scenario.initializer.get_balance()?;
+
+    // the initializer kept the change of funding the HTLC
+    let (outpoint, prevout) = scenario
+        .initializer
+        .find_utxo(Amount::from_sat(10_000))?
+        .ok_or(anyhow!("the change of the HTLC funding was not found"))?;
+    let funding_tx = scenario
+        .initializer
+        .chain()
+        .get_tx(&outpoint.txid)?
+        .ok_or(anyhow!("transaction {} not found", outpoint.txid))?;
+    assert_eq!(funding_tx.output[outpoint.vout as usize], prevout);
+    assert_eq!(scenario.initializer.find_utxo(HTLC_AMOUNT)?, None);
+    assert_eq!(scenario.initializer.chain().get_block_height()?, height);
+    assert_eq!(scenario.initializer.get_balance()?, balance);
+    Ok(())
+}
+
+#[test]
+fn regtest_wrong_preimage_is_rejected() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, &"00".repeat(32))?;
+
+    let redeem_tx = scenario.htlc.create_redeem_tx_with_fee(FEE)?;
+    let err = scenario
+        .redeem
+        .chain()
+        .broadcast(&redeem_tx)
+        .err()
+        .ok_or(anyhow!("redeem with a wrong preimage was accepted"))?;
+    assert!(err.to_string().contains("script-verify-flag-failed"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn regtest_batch_funding() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+
+    let payments: Vec<_> = (0..3u64)
+        .map(|i| Ok((miner.get_new_address()?, Amount::from_sat(10_000 * (i + 1)))))
+        .collect::<Result<_>>()?;
+    let outpoints: Vec<OutPoint> = miner.send_many(&payments)?;
+
+    let tx = miner
+        .chain()
+        .get_tx(&outpoints[0].txid)?
+        .ok_or(anyhow!("funding transaction not found"))?;
+    for ((address, amount), outpoint) in payments.iter().zip(outpoints.iter()) {
+        let output = &tx.output[outpoint.vout as usize];
+        assert_eq!(output.script_pubkey, address.script_pubkey());
+        assert_eq!(output.value, *amount);
+    }
+    Ok(())
+}
+
+#[test]
+fn regtest_psbt_funding() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+    let redeem = node.wallet("redeem");
+    let mut htlc = HTLC {
+        htlc_funded_utxo: None,
+        redeem_address: Some(redeem.get_new_address()?),
+        redeem_config: Some(RedeemConfig::new_unchecked(
+            PAYMENT_HASH.parse().unwrap(),
+            Some(PREIMAGE.parse().unwrap()),
+        )),
+        refund_config: Some(RefundConfig {
+            refund_address: miner.get_new_address()?,
+            refund_lock: REFUND_LOCK,
+        }),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
+    };
+
+    // the funder's wallet holds coins the tool has no keys for
+    let funder = node.wallet("funder");
+    let funder_outpoint = miner.send(&funder.get_new_address()?, HTLC_AMOUNT * 2)?;
+    miner.mine_blocks(Some(1))?;
+    let psbt = funding_psbt_from_chain(
+        funder.chain(),
+        &[funder_outpoint],
+        htlc.address_with_fee(Network::Regtest)?.script_pubkey(),
+        HTLC_AMOUNT,
+        FEE,
+        Some(&funder.get_new_address()?),
+    )?;
+    let funding_tx = finalize_spend_psbt(funder.process_psbt(&psbt)?)?;
+    funder.chain().broadcast(&funding_tx)?;
+    miner.mine_blocks(Some(1))?;
+    htlc.record_funding(&funding_tx)?;
+
+    let redeem_tx = htlc.create_redeem_tx_with_fee(FEE)?;
+    redeem.chain().broadcast(&redeem_tx)?;
+    miner.mine_blocks(Some(1))?;
+    assert_eq!(redeem.get_balance()?, HTLC_AMOUNT - FEE);
+    Ok(())
+}
+
+#[test]
+fn regtest_redeem_and_refund_commands() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let scenario = funded_htlc(&node, PREIMAGE)?;
+    let contract_file = node.datadir.join("htlc_contract.json");
+    scenario.htlc.to_file(&contract_file)?;
+
+    let report = crate::refund(Some(FEE), true, false, &contract_file, &node.settings)?;
+    assert_eq!(report.status, "rejected");
+    let report = crate::redeem(&[], Some(FEE), true, false, &contract_file, &node.settings)?;
+    assert_eq!(report.status, "accepted");
+    assert_eq!(report.fee, Some(FEE));
+
+    let report = crate::redeem(&[], Some(FEE), false, false, &contract_file, &node.settings)?;
+    assert_eq!(report.status, "redeemed");
+    scenario.miner.mine_blocks(Some(1))?;
+    assert_eq!(scenario.redeem.get_balance()?, HTLC_AMOUNT - FEE);
+    Ok(())
+}
+
+/// an HTLC on the plain tree `deposit` funds, paid by the miner and stored in a contract file
+/// without its preimage
+fn plain_htlc_contract(node: &Bitcoind) -> Result<(Wallet, Wallet, PathBuf)> {
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+    let redeem = node.wallet("redeem");
+    let mut htlc = HTLC {
+        htlc_funded_utxo: None,
+        redeem_address: Some(redeem.get_new_address()?),
+        redeem_config: Some(RedeemConfig::new(PAYMENT_HASH.parse().unwrap(), None)?),
+        refund_config: Some(RefundConfig {
+            refund_address: miner.get_new_address()?,
+            refund_lock: REFUND_LOCK,
+        }),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
+    };
+    let outpoint = miner.send(&htlc.address(Network::Regtest)?, HTLC_AMOUNT)?;
+    miner.mine_blocks(Some(1))?;
+    htlc.set_funded_htlc(outpoint, HTLC_AMOUNT);
+    let contract_file = node.datadir.join("htlc_contract.json");
+    htlc.to_file(&contract_file)?;
+    Ok((miner, redeem, contract_file))
+}
+
+#[test]
+fn regtest_redeem_command_pays_the_plain_tree_fee_from_the_miner() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let (miner, redeem, contract_file) = plain_htlc_contract(&node)?;
+
+    let preimages = [PREIMAGE.parse().unwrap()];
+    let report = crate::redeem(
+        &preimages,
+        Some(FEE),
+        false,
+        false,
+        &contract_file,
+        &node.settings,
+    )?;
+    assert_eq!(report.status, "redeemed");
+    miner.mine_blocks(Some(1))?;
+    // the plain leaves pay the whole HTLC out, the fee came from the miner's input
+    assert_eq!(redeem.get_balance()?, HTLC_AMOUNT);
+    let redeem_config = HTLC::from_file(&contract_file)?.redeem_config.unwrap();
+    assert!(redeem_config.preimages().is_some());
+    Ok(())
+}
+
+#[test]
+fn regtest_spend_psbt_round_trip() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let (miner, redeem, contract_file) = plain_htlc_contract(&node)?;
+
+    let preimages = [PREIMAGE.parse().unwrap()];
+    let report = crate::redeem(&preimages, None, false, true, &contract_file, &node.settings)?;
+    assert_eq!(report.status, "awaiting_signature");
+    assert_eq!(report.fee, None);
+    let mut psbt = Psbt::from_str(report.extra["psbt"].as_str().unwrap())?;
+
+    // the fee wallet adds its input and change, then signs them
+    let (fee_outpoint, fee_prevout) = miner
+        .find_utxo(FEE * 2)?
+        .ok_or(anyhow!("the miner has no coin for the fee"))?;
+    psbt.unsigned_tx.input.push(TxIn {
+        previous_output: fee_outpoint,
+        ..Default::default()
+    });
+    psbt.unsigned_tx.output.push(TxOut {
+        value: fee_prevout.value - FEE,
+        script_pubkey: miner.get_new_address()?.script_pubkey(),
+    });
+    psbt.inputs.push(psbt::Input {
+        witness_utxo: Some(fee_prevout),
+        ..Default::default()
+    });
+    psbt.outputs.push(psbt::Output::default());
+    let signed = miner.process_psbt(&psbt)?;
+
+    let report = crate::finalize_psbt(&signed.to_string(), true, &node.settings)?;
+    assert_eq!(report.status, "accepted");
+    assert_eq!(report.fee, Some(FEE));
+    let report = crate::finalize_psbt(&signed.to_string(), false, &node.settings)?;
+    assert_eq!(report.status, "broadcast");
+    miner.mine_blocks(Some(1))?;
+    assert_eq!(redeem.get_balance()?, HTLC_AMOUNT);
+    Ok(())
+}
+
+#[test]
+fn regtest_ad_hoc_dry_run_broadcasts_nothing() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+    let height = miner.chain().get_block_height()?;
+    let balance = miner.get_balance()?;
+
+    let report = crate::ad_hoc_testing(true, &node.settings)?;
+    assert_eq!(report.status, "accepted");
+    assert_eq!(miner.chain().get_block_height()?, height);
+    assert_eq!(miner.get_balance()?, balance);
+    let client = Wallet::create_rpc_client(&node.settings, None)?;
+    assert!(client.get_raw_mempool()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn regtest_swap_commands() -> Result<()> {
+    let Some(node) = Bitcoind::start() else {
+        return Ok(());
+    };
+    let miner = node.wallet("miner");
+    miner.mine_blocks(Some(101))?;
+    let initiator = node.wallet("initiator");
+    let participant = node.wallet("participant");
+    let swap_file = node.datadir.join("swap.json");
+    crate::swap_new(
+        PAYMENT_HASH.parse().unwrap(),
+        SwapParty {
+            redeem_address: initiator.get_new_address()?,
+            refund_address: miner.get_new_address()?,
+        },
+        SwapParty {
+            redeem_address: participant.get_new_address()?,
+            refund_address: miner.get_new_address()?,
+        },
+        (HTLC_AMOUNT, HTLC_AMOUNT),
+        48,
+        24,
+        &swap_file,
+        &node.settings,
+    )?;
+    let swap = AtomicSwap::from_file(&swap_file)?;
+
+    let funding_a = miner.send(&swap.address_a(Network::Regtest)?, HTLC_AMOUNT)?;
+    miner.mine_blocks(Some(1))?;
+    crate::swap_fund_a(funding_a.txid, &swap_file, &node.settings)?;
+
+    let funding_b = miner.send(&swap.address_b(Network::Regtest)?, HTLC_AMOUNT)?;
+    let err = crate::swap_verify_b(funding_b.txid, &swap_file, &node.settings).unwrap_err();
+    assert!(err.to_string().contains("not confirmed"), "{}", err);
+    miner.mine_blocks(Some(1))?;
+    crate::swap_verify_b(funding_b.txid, &swap_file, &node.settings)?;
+
+    let report = crate::swap_redeem_b(PREIMAGE.parse().unwrap(), FEE, &swap_file, &node.settings)?;
+    assert_eq!(report.status, "b_redeemed");
+    miner.mine_blocks(Some(1))?;
+    let report = crate::swap_redeem_a(FEE, &swap_file, &node.settings)?;
+    assert_eq!(report.status, "a_redeemed");
+    miner.mine_blocks(Some(1))?;
+
+    assert_eq!(initiator.get_balance()?, HTLC_AMOUNT - FEE);
+    assert_eq!(participant.get_balance()?, HTLC_AMOUNT - FEE);
+    assert_eq!(crate::swap_status(&swap_file, &node.settings)?.status, "a_redeemed");
+    Ok(())
+}
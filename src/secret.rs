@@ -0,0 +1,368 @@
+//! Preimage generation and encrypted storage.
+//!
+//! Preimages are either derived from the store's seed and a contract index, so a seed backup
+//! recovers every one of them, or drawn from the OS CSPRNG and kept in the store. Everything
+//! secret is encrypted at rest with ChaCha20-Poly1305 under a key stretched from a passphrase
+//! with PBKDF2-HMAC-SHA256. Only payment hashes are stored in the clear.
+
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoincore_rpc::jsonrpc::serde_json;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::{OsRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::htlc::hashlock::{PaymentHash, Preimage};
+
+/// environment variable holding the passphrase, prompted for when unset
+pub(crate) const PASSPHRASE_ENV: &str = "CAT_HTLC_PASSPHRASE";
+/// environment variable holding the seed to recover a store from, prompted for when unset
+pub(crate) const SEED_ENV: &str = "CAT_HTLC_SEED";
+const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+const PREIMAGE_DERIVATION_TAG: &[u8] = b"cat-htlc/preimage";
+
+#[derive(Debug)]
+pub(crate) enum SecretError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    /// the passphrase is wrong or the store was tampered with
+    Decryption,
+    Random(std::io::Error),
+    UnknownSecret(String),
+    /// the recovery seed is not 32 bytes of hex
+    InvalidSeed,
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Io(e) => write!(f, "could not access the secret store: {}", e),
+            SecretError::Format(e) => write!(f, "malformed secret store: {}", e),
+            SecretError::Decryption => {
+                write!(f, "could not decrypt the secret store, wrong passphrase?")
+            }
+            SecretError::Random(e) => write!(f, "no randomness from the OS: {}", e),
+            SecretError::UnknownSecret(id) => write!(f, "no secret {} in the store", id),
+            SecretError::InvalidSeed => write!(f, "the recovery seed must be 32 bytes of hex"),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SecretError::Io(e) | SecretError::Random(e) => Some(e),
+            SecretError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SecretError {
+    fn from(e: std::io::Error) -> Self {
+        SecretError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SecretError {
+    fn from(e: serde_json::Error) -> Self {
+        SecretError::Format(e)
+    }
+}
+
+/// 32 bytes from the OS CSPRNG
+pub(crate) fn random_bytes() -> Result<[u8; 32], SecretError> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()
+        .map_err(SecretError::Random)?
+        .fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+/// a preimage from the OS CSPRNG
+pub(crate) fn random_preimage() -> Result<Preimage, SecretError> {
+    Ok(Preimage::from_byte_array(random_bytes()?))
+}
+
+/// the preimage of contract `index`: HMAC-SHA256(seed, tag || index)
+pub(crate) fn derive_preimage(seed: &[u8; 32], index: u32) -> Preimage {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(seed);
+    engine.input(PREIMAGE_DERIVATION_TAG);
+    engine.input(&index.to_be_bytes());
+    Preimage::from_byte_array(hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Encrypted {
+    #[serde(with = "hex::serde")]
+    nonce: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SecretEntry {
+    pub index: u32,
+    pub payment_hash: PaymentHash,
+    /// a CSPRNG preimage, `None` for preimages derived from the seed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    random: Option<Encrypted>,
+}
+
+/// the on disk secret store
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SecretStore {
+    #[serde(with = "hex::serde")]
+    salt: Vec<u8>,
+    iterations: u32,
+    seed: Encrypted,
+    pub secrets: Vec<SecretEntry>,
+}
+
+/// an opened store, holding the stretched key
+pub(crate) struct UnlockedStore<'a> {
+    store: &'a mut SecretStore,
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretStore {
+    /// a new store around `seed`, or a fresh random seed
+    pub(crate) fn create(passphrase: &str, seed: Option<[u8; 32]>) -> Result<Self, SecretError> {
+        Self::create_with_iterations(passphrase, seed, DEFAULT_KDF_ITERATIONS)
+    }
+
+    fn create_with_iterations(
+        passphrase: &str,
+        seed: Option<[u8; 32]>,
+        iterations: u32,
+    ) -> Result<Self, SecretError> {
+        let salt = random_bytes()?.to_vec();
+        let seed = match seed {
+            Some(seed) => seed,
+            None => random_bytes()?,
+        };
+        let cipher = stretch(passphrase, &salt, iterations);
+        Ok(SecretStore {
+            seed: encrypt(&cipher, &seed)?,
+            salt,
+            iterations,
+            secrets: Vec::new(),
+        })
+    }
+
+    pub(crate) fn from_file(path: &PathBuf) -> Result<Self, SecretError> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// write the store readable by its owner only
+    pub(crate) fn to_file(&self, path: &PathBuf) -> Result<(), SecretError> {
+        let json = serde_json::to_string_pretty(self)?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        // the mode only applies to new files, an existing one keeps its own
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    /// derive the key from `passphrase` and check it against the stored seed
+    pub(crate) fn unlock(&mut self, passphrase: &str) -> Result<UnlockedStore<'_>, SecretError> {
+        let cipher = stretch(passphrase, &self.salt, self.iterations);
+        decrypt(&cipher, &self.seed)?;
+        Ok(UnlockedStore {
+            store: self,
+            cipher,
+        })
+    }
+
+    /// the entry for a contract index or payment hash
+    pub(crate) fn find(&self, id: &str) -> Result<&SecretEntry, SecretError> {
+        self.secrets
+            .iter()
+            .find(|entry| entry.index.to_string() == id || entry.payment_hash.to_string() == id)
+            .ok_or(SecretError::UnknownSecret(id.to_string()))
+    }
+}
+
+impl UnlockedStore<'_> {
+    /// add a preimage for the next contract index, derived from the seed unless `random`
+    pub(crate) fn new_secret(&mut self, random: bool) -> Result<SecretEntry, SecretError> {
+        let index = self
+            .store
+            .secrets
+            .iter()
+            .map(|entry| entry.index + 1)
+            .max()
+            .unwrap_or(0);
+        let (preimage, random) = if random {
+            let preimage = random_preimage()?;
+            (preimage, Some(encrypt(&self.cipher, &preimage.to_byte_array())?))
+        } else {
+            (derive_preimage(&self.seed()?, index), None)
+        };
+        let entry = SecretEntry {
+            index,
+            payment_hash: preimage.payment_hash(),
+            random,
+        };
+        self.store.secrets.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// the preimage of a contract index or payment hash
+    pub(crate) fn reveal(&self, id: &str) -> Result<Preimage, SecretError> {
+        let entry = self.store.find(id)?;
+        match &entry.random {
+            Some(encrypted) => Ok(Preimage::from_byte_array(decrypt(&self.cipher, encrypted)?)),
+            None => Ok(derive_preimage(&self.seed()?, entry.index)),
+        }
+    }
+
+    /// the seed, for backups
+    pub(crate) fn seed(&self) -> Result<[u8; 32], SecretError> {
+        decrypt(&self.cipher, &self.store.seed)
+    }
+}
+
+/// the passphrase from `PASSPHRASE_ENV`, or prompted for on the terminal
+pub(crate) fn read_passphrase() -> Result<String, SecretError> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => Ok(rpassword::prompt_password("passphrase: ")?),
+    }
+}
+
+/// the seed of a store to recover, from `SEED_ENV` or prompted for on the terminal so it stays
+/// out of the shell history and process list
+pub(crate) fn read_seed() -> Result<[u8; 32], SecretError> {
+    let seed = match std::env::var(SEED_ENV) {
+        Ok(seed) => seed,
+        Err(_) => rpassword::prompt_password("recovery seed (hex): ")?,
+    };
+    hex::decode(seed.trim())
+        .ok()
+        .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+        .ok_or(SecretError::InvalidSeed)
+}
+
+fn stretch(passphrase: &str, salt: &[u8], iterations: u32) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn encrypt(cipher: &ChaCha20Poly1305, plaintext: &[u8; 32]) -> Result<Encrypted, SecretError> {
+    let nonce = random_bytes()?[..12].to_vec();
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| SecretError::Decryption)?;
+    Ok(Encrypted { nonce, ciphertext })
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, encrypted: &Encrypted) -> Result<[u8; 32], SecretError> {
+    if encrypted.nonce.len() != 12 {
+        return Err(SecretError::Decryption);
+    }
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&encrypted.nonce),
+            encrypted.ciphertext.as_slice(),
+        )
+        .map_err(|_| SecretError::Decryption)?;
+    plaintext.try_into().map_err(|_| SecretError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn store(seed: Option<[u8; 32]>) -> SecretStore {
+        SecretStore::create_with_iterations(PASSPHRASE, seed, 1).unwrap()
+    }
+
+    #[test]
+    fn derived_secrets_are_recovered_from_the_seed() {
+        let mut store = store(Some([7; 32]));
+        let mut unlocked = store.unlock(PASSPHRASE).unwrap();
+        let first = unlocked.new_secret(false).unwrap();
+        let second = unlocked.new_secret(false).unwrap();
+        assert_eq!((first.index, second.index), (0, 1));
+        assert_ne!(first.payment_hash, second.payment_hash);
+
+        // a store restored from the same seed derives the same preimages
+        let mut restored = self::store(Some([7; 32]));
+        let mut restored = restored.unlock(PASSPHRASE).unwrap();
+        restored.new_secret(false).unwrap();
+        let restored_second = restored.new_secret(false).unwrap();
+        assert_eq!(restored_second.payment_hash, second.payment_hash);
+        assert_eq!(
+            restored.reveal("1").unwrap().payment_hash(),
+            second.payment_hash
+        );
+    }
+
+    #[test]
+    fn random_secrets_round_trip_through_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("cat-htlc-secrets-{}.json", std::process::id()));
+        let mut store = store(None);
+        let entry = store.unlock(PASSPHRASE).unwrap().new_secret(true).unwrap();
+        store.to_file(&path).unwrap();
+
+        let json = std::fs::read_to_string(&path).unwrap();
+        let mut loaded = SecretStore::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let preimage = loaded
+            .unlock(PASSPHRASE)
+            .unwrap()
+            .reveal(&entry.payment_hash.to_string())
+            .unwrap();
+        assert_eq!(preimage.payment_hash(), entry.payment_hash);
+        assert!(!json.contains(&preimage.to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn store_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("cat-htlc-private-{}.json", std::process::id()));
+        // overwriting a world readable file tightens it as well
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        store(None).to_file(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let mut store = store(None);
+        assert!(matches!(
+            store.unlock("wrong"),
+            Err(SecretError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let mut store = store(None);
+        store.unlock(PASSPHRASE).unwrap().new_secret(true).unwrap();
+        store.secrets[0].random.as_mut().unwrap().ciphertext[0] ^= 1;
+        let unlocked = store.unlock(PASSPHRASE).unwrap();
+        assert!(matches!(unlocked.reveal("0"), Err(SecretError::Decryption)));
+    }
+}
@@ -9,17 +9,49 @@ pub(crate) struct Settings {
     pub network: Network,
     pub bitcoin_rpc_username: String,
     pub bitcoin_rpc_password: String,
+    /// host (or IP) of the bitcoind RPC server
+    #[serde(default = "default_rpc_host")]
+    pub rpc_host: String,
+    /// RPC port, defaults to the standard port of `network` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_port: Option<u16>,
+    /// explicit path to a bitcoind `.cookie` file. Takes precedence over user/pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_cookie_file: Option<PathBuf>,
+    /// bitcoind datadir, used to discover the cookie file when `rpc_cookie_file` is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitcoin_datadir: Option<PathBuf>,
+    /// base url of an Esplora REST API. When set, chain queries go there instead of bitcoind
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub esplora_url: Option<String>,
     pub create_wallets: bool,
     pub miner_wallet_name: String,
     pub fee_wallet_name: String,
 }
 
+fn default_rpc_host() -> String {
+    "127.0.0.1".to_string()
+}
+
 impl Settings {
     pub(crate) fn to_toml_file(&self, path: &PathBuf) -> Result<()> {
         let toml = toml::to_string(self)?;
         std::fs::write(path, toml)?;
         Ok(())
     }
+
+    /// the cookie file bitcoind writes into `datadir` for the configured network
+    pub(crate) fn datadir_cookie_file(&self) -> Option<PathBuf> {
+        let datadir = self.bitcoin_datadir.as_ref()?;
+        let cookie = match self.network {
+            Network::Bitcoin => datadir.join(".cookie"),
+            Network::Testnet => datadir.join("testnet3").join(".cookie"),
+            Network::Regtest => datadir.join("regtest").join(".cookie"),
+            Network::Signet => datadir.join("signet").join(".cookie"),
+            _ => return None,
+        };
+        Some(cookie)
+    }
 }
 
 impl Default for Settings {
@@ -28,6 +60,11 @@ impl Default for Settings {
             network: Network::Regtest,
             bitcoin_rpc_username: "user".to_string(),
             bitcoin_rpc_password: "password".to_string(),
+            rpc_host: default_rpc_host(),
+            rpc_port: None,
+            rpc_cookie_file: None,
+            bitcoin_datadir: None,
+            esplora_url: None,
             create_wallets: true,
             miner_wallet_name: "miner".to_string(),
             fee_wallet_name: "fee_payment".to_string(),
@@ -40,4 +77,4 @@ impl Settings {
         let toml = std::fs::read_to_string(path)?;
         Ok(toml::from_str(&toml)?)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,340 @@
+//! Submarine swaps: the HTLC as the on-chain leg of a Lightning payment.
+//!
+//! The user funds an HTLC redeemable by the swap service with the payment hash of a BOLT11
+//! invoice. The service pays the invoice, learns the preimage and redeems the HTLC. If it never
+//! pays, the user refunds after the lock. The invoice is parsed offline, only the fields the swap
+//! depends on are decoded and its signature is not checked.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bitcoin::bech32::primitives::decode::UncheckedHrpstring;
+use bitcoin::bech32::{Bech32, Fe32};
+use bitcoin::{Address, Amount, Network};
+
+use crate::htlc::contract::{RedeemConfig, RefundConfig, HTLC};
+use crate::htlc::hashlock::PaymentHash;
+
+/// seconds per block used to convert the invoice expiry into blocks
+const BLOCK_INTERVAL_SECS: u64 = 600;
+/// BOLT11 default for a missing `x` field
+const DEFAULT_EXPIRY_SECS: u64 = 3600;
+/// BOLT11 default for a missing `c` field
+const DEFAULT_MIN_FINAL_CLTV_EXPIRY: u64 = 18;
+/// the signature and recovery id at the end of the data part, in 5 bit words
+const SIGNATURE_WORDS: usize = 104;
+const TIMESTAMP_WORDS: usize = 7;
+
+const TAG_PAYMENT_HASH: u8 = 1;
+const TAG_EXPIRY: u8 = 6;
+const TAG_MIN_FINAL_CLTV_EXPIRY: u8 = 24;
+
+#[derive(Debug)]
+pub(crate) enum InvoiceError {
+    /// not a bech32 string with a valid checksum
+    Bech32(String),
+    /// the human readable part does not start with `ln` and a known currency
+    UnknownCurrency(String),
+    InvalidAmount(String),
+    /// the data part ends before the timestamp, a field or the signature
+    Truncated,
+    MissingPaymentHash,
+}
+
+impl fmt::Display for InvoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvoiceError::Bech32(e) => write!(f, "invalid invoice encoding: {}", e),
+            InvoiceError::UnknownCurrency(hrp) => write!(f, "unknown invoice currency in {}", hrp),
+            InvoiceError::InvalidAmount(amount) => write!(f, "invalid invoice amount {}", amount),
+            InvoiceError::Truncated => write!(f, "invoice data is truncated"),
+            InvoiceError::MissingPaymentHash => write!(f, "invoice has no payment hash"),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceError {}
+
+/// the parts of a BOLT11 invoice a swap depends on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bolt11Invoice {
+    pub network: Network,
+    pub amount_msat: Option<u64>,
+    /// unix time the invoice was created at
+    pub timestamp: u64,
+    /// seconds after `timestamp` the invoice expires
+    pub expiry: u64,
+    pub min_final_cltv_expiry: u64,
+    pub payment_hash: PaymentHash,
+}
+
+impl FromStr for Bolt11Invoice {
+    type Err = InvoiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("lightning:").unwrap_or(s);
+        let unchecked =
+            UncheckedHrpstring::new(s).map_err(|e| InvoiceError::Bech32(e.to_string()))?;
+        unchecked
+            .validate_checksum::<Bech32>()
+            .map_err(|e| InvoiceError::Bech32(e.to_string()))?;
+        let hrp = unchecked.hrp().to_lowercase();
+        let (network, amount_msat) = parse_hrp(&hrp)?;
+
+        // the data part without the separator and checksum, as 5 bit words
+        let data = &s[hrp.len() + 1..s.len() - 6];
+        let words = data
+            .chars()
+            .map(|c| Fe32::from_char(c).map(|fe| fe.to_u8()))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| InvoiceError::Bech32(e.to_string()))?;
+        if words.len() < TIMESTAMP_WORDS + SIGNATURE_WORDS {
+            return Err(InvoiceError::Truncated);
+        }
+        let (fields, _signature) = words.split_at(words.len() - SIGNATURE_WORDS);
+
+        let timestamp = words_to_u64(&fields[..TIMESTAMP_WORDS]);
+        let mut expiry = DEFAULT_EXPIRY_SECS;
+        let mut min_final_cltv_expiry = DEFAULT_MIN_FINAL_CLTV_EXPIRY;
+        let mut payment_hash = None;
+        let mut fields = &fields[TIMESTAMP_WORDS..];
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(InvoiceError::Truncated);
+            }
+            let tag = fields[0];
+            let len = words_to_u64(&fields[1..3]) as usize;
+            let value = fields.get(3..3 + len).ok_or(InvoiceError::Truncated)?;
+            match tag {
+                // readers skip a payment hash of the wrong length
+                TAG_PAYMENT_HASH if len == 52 && payment_hash.is_none() => {
+                    let bytes = words_to_bytes(value)[..32].try_into().unwrap();
+                    payment_hash = Some(PaymentHash::from_byte_array(bytes));
+                }
+                TAG_EXPIRY => expiry = words_to_u64(value),
+                TAG_MIN_FINAL_CLTV_EXPIRY => min_final_cltv_expiry = words_to_u64(value),
+                _ => {}
+            }
+            fields = &fields[3 + len..];
+        }
+
+        Ok(Bolt11Invoice {
+            network,
+            amount_msat,
+            timestamp,
+            expiry,
+            min_final_cltv_expiry,
+            payment_hash: payment_hash.ok_or(InvoiceError::MissingPaymentHash)?,
+        })
+    }
+}
+
+impl Bolt11Invoice {
+    /// the amount in sats, rounded up
+    pub(crate) fn amount(&self) -> Option<Amount> {
+        self.amount_msat
+            .map(|msat| Amount::from_sat(msat.div_ceil(1000)))
+    }
+
+    pub(crate) fn expires_at(&self) -> u64 {
+        self.timestamp.saturating_add(self.expiry)
+    }
+}
+
+/// `ln` + currency + optional amount with multiplier
+fn parse_hrp(hrp: &str) -> Result<(Network, Option<u64>), InvoiceError> {
+    let rest = hrp
+        .strip_prefix("ln")
+        .ok_or(InvoiceError::UnknownCurrency(hrp.to_string()))?;
+    let currency_len = rest
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (currency, amount) = rest.split_at(currency_len);
+    let network = match currency {
+        "bc" => Network::Bitcoin,
+        "tb" => Network::Testnet,
+        "tbs" => Network::Signet,
+        "bcrt" => Network::Regtest,
+        _ => return Err(InvoiceError::UnknownCurrency(hrp.to_string())),
+    };
+    if amount.is_empty() {
+        return Ok((network, None));
+    }
+
+    let invalid = || InvoiceError::InvalidAmount(amount.to_string());
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&amount[..i], Some(c)),
+        _ => (amount, None),
+    };
+    if digits.starts_with('0') {
+        return Err(invalid());
+    }
+    let value: u64 = digits.parse().map_err(|_| invalid())?;
+    // amounts are in bitcoin, the multipliers scale them down
+    let amount_msat = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value.is_multiple_of(10) => Some(value / 10),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+    Ok((network, Some(amount_msat)))
+}
+
+fn words_to_u64(words: &[u8]) -> u64 {
+    words
+        .iter()
+        .fold(0u64, |acc, word| (acc << 5) | *word as u64)
+}
+
+/// regroup 5 bit words into bytes, dropping the incomplete trailing group
+fn words_to_bytes(words: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(words.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for word in words {
+        buffer = (buffer << 5) | *word as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    bytes
+}
+
+/// an HTLC paying `htlc_amount` to the swap service at `redeem_address` once it has paid
+/// `invoice`, refundable to `refund_address` after `refund_lock` blocks.
+///
+/// the invoice has to be for `network`, ask for no more than `htlc_amount` and still be payable
+/// at `now`. The refund lock has to outlast the invoice expiry plus its final CLTV delta, so the
+/// service cannot take the preimage from a late payment after the user refunded
+pub(crate) fn submarine_htlc(
+    invoice: &Bolt11Invoice,
+    network: Network,
+    htlc_amount: Amount,
+    redeem_address: Address,
+    refund_address: Address,
+    refund_lock: i64,
+    now: u64,
+) -> Result<HTLC> {
+    if invoice.network != network {
+        return Err(anyhow!(
+            "invoice is for {} but the HTLC is on {}",
+            invoice.network,
+            network
+        ));
+    }
+
+    let amount = invoice
+        .amount()
+        .ok_or(anyhow!("invoice has no amount, the swap cannot check it"))?;
+    if amount > htlc_amount {
+        return Err(anyhow!(
+            "invoice asks for {} which is more than the HTLC's {}",
+            amount,
+            htlc_amount
+        ));
+    }
+
+    if now >= invoice.expires_at() {
+        return Err(anyhow!("invoice expired at {}", invoice.expires_at()));
+    }
+    let expiry_blocks = (invoice.expires_at() - now).div_ceil(BLOCK_INTERVAL_SECS);
+    let required_lock = expiry_blocks + invoice.min_final_cltv_expiry;
+    if refund_lock <= required_lock as i64 {
+        return Err(anyhow!(
+            "refund lock of {} blocks does not outlast the invoice, needs more than {}",
+            refund_lock,
+            required_lock
+        ));
+    }
+
+    Ok(HTLC {
+        htlc_funded_utxo: None,
+        redeem_address: Some(redeem_address),
+        redeem_config: Some(RedeemConfig::new(invoice.payment_hash.clone(), None)?),
+        refund_config: Some(RefundConfig {
+            refund_address,
+            refund_lock,
+        }),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::address;
+
+    /// "Please send $5 for a cup of coffee to the same peer, within one minute" from BOLT11
+    const INVOICE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+    const CREATED_AT: u64 = 1496314658;
+
+    #[test]
+    fn parses_the_bolt11_example() {
+        let invoice = Bolt11Invoice::from_str(INVOICE).unwrap();
+        assert_eq!(invoice.network, Network::Bitcoin);
+        assert_eq!(invoice.amount(), Some(Amount::from_sat(250_000)));
+        assert_eq!(invoice.timestamp, CREATED_AT);
+        assert_eq!(invoice.expiry, 60);
+        assert_eq!(
+            invoice.payment_hash.to_string(),
+            "0001020304050607080900010203040506070809000102030405060708090102"
+        );
+    }
+
+    #[test]
+    fn corrupted_invoice_is_rejected() {
+        let corrupted = INVOICE.replace("lnbc2500u", "lnbc2600u");
+        assert!(matches!(
+            Bolt11Invoice::from_str(&corrupted),
+            Err(InvoiceError::Bech32(_))
+        ));
+    }
+
+    #[test]
+    fn amounts_use_the_multiplier() {
+        assert_eq!(parse_hrp("lnbcrt").unwrap(), (Network::Regtest, None));
+        assert_eq!(
+            parse_hrp("lntbs20m").unwrap(),
+            (Network::Signet, Some(2_000_000_000))
+        );
+        assert_eq!(parse_hrp("lntb10p").unwrap(), (Network::Testnet, Some(1)));
+        assert!(parse_hrp("lntb11p").is_err());
+        assert!(parse_hrp("lnxy1m").is_err());
+    }
+
+    #[test]
+    fn swap_htlc_checks_the_invoice() {
+        let invoice = Bolt11Invoice::from_str(INVOICE).unwrap();
+        let swap = |network, amount, lock, now| {
+            submarine_htlc(&invoice, network, amount, address(2), address(3), lock, now)
+        };
+        let amount = Amount::from_sat(250_000);
+
+        let htlc = swap(Network::Bitcoin, amount, 20, CREATED_AT).unwrap();
+        assert_eq!(
+            htlc.redeem_config.unwrap().payment_hash(),
+            &invoice.payment_hash
+        );
+
+        assert!(swap(Network::Regtest, amount, 20, CREATED_AT).is_err());
+        assert!(swap(
+            Network::Bitcoin,
+            amount - Amount::from_sat(1),
+            20,
+            CREATED_AT
+        )
+        .is_err());
+        assert!(swap(Network::Bitcoin, amount, 20, CREATED_AT + 60).is_err());
+        // one block of expiry plus the default final CLTV delta of 18
+        assert!(swap(Network::Bitcoin, amount, 19, CREATED_AT).is_err());
+    }
+}
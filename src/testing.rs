@@ -0,0 +1,117 @@
+//! Fixtures shared by the unit tests.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash;
+use bitcoin::key::{Keypair, Secp256k1, TapTweak};
+use bitcoin::secp256k1::Message;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn,
+    TxOut, Witness,
+};
+
+use crate::chain::mock::MockChain;
+use crate::chain::ChainBackend;
+use crate::htlc::contract::{RedeemConfig, RefundConfig, HTLC};
+
+pub(crate) const PREIMAGE: &str =
+    "6644fd23b8327a04d86bdadbeba6903c1e9bfef68f9c9ee7c00cc8f59529430c";
+pub(crate) const PAYMENT_HASH: &str =
+    "7d71c056feba9afeb8ee135b8c83695b1ecf948a96d24494592a5743c6779a57";
+pub(crate) const REFUND_LOCK: i64 = 20;
+pub(crate) const HTLC_AMOUNT: Amount = Amount::from_sat(100_000_000);
+pub(crate) const FEE: Amount = Amount::from_sat(1000);
+
+pub(crate) fn keypair(secret: u8) -> Keypair {
+    Keypair::from_seckey_slice(&Secp256k1::new(), &[secret; 32]).unwrap()
+}
+
+/// key path address of `keypair(secret)`
+pub(crate) fn address(secret: u8) -> Address {
+    let (xonly, _) = keypair(secret).x_only_public_key();
+    Address::p2tr(&Secp256k1::new(), xonly, None, Network::Regtest)
+}
+
+/// an HTLC redeemable by `address(2)` with `preimage`, refundable to `address(3)`
+pub(crate) fn htlc(preimage: &str) -> HTLC {
+    HTLC {
+        htlc_funded_utxo: None,
+        redeem_address: Some(address(2)),
+        redeem_config: Some(RedeemConfig::new_unchecked(
+            PAYMENT_HASH.parse().unwrap(),
+            Some(preimage.parse().unwrap()),
+        )),
+        refund_config: Some(RefundConfig {
+            refund_address: address(3),
+            refund_lock: REFUND_LOCK,
+        }),
+        nums_tweak: None,
+        extra_leaves: Vec::new(),
+    }
+}
+
+/// a mock chain past coinbase maturity, like a freshly bootstrapped regtest node
+pub(crate) fn new_chain() -> MockChain {
+    let chain = MockChain::new();
+    chain.mine_blocks(101);
+    chain
+}
+
+/// sign input `input_index` of `tx`, which spends the key path of `address(secret)`
+pub(crate) fn sign_key_spend(tx: &mut Transaction, input_index: usize, prevouts: &[TxOut], secret: u8) {
+    let secp = Secp256k1::new();
+    let sighash = SighashCache::new(&*tx)
+        .taproot_key_spend_signature_hash(
+            input_index,
+            &Prevouts::All(prevouts),
+            TapSighashType::Default,
+        )
+        .unwrap();
+    let tweaked = keypair(secret).tap_tweak(&secp, None).to_inner();
+    let signature =
+        secp.sign_schnorr_no_aux_rand(&Message::from_digest(sighash.to_byte_array()), &tweaked);
+    tx.input[input_index].witness = Witness::from_slice(&[signature.as_ref()]);
+}
+
+/// pay `amount` to `script_pubkey` from a fresh key path wallet output and confirm it
+pub(crate) fn pay_to(chain: &MockChain, script_pubkey: ScriptBuf, amount: Amount) -> Transaction {
+    let tx = pay_to_mempool(chain, script_pubkey, amount);
+    chain.mine_blocks(1);
+    tx
+}
+
+/// like `pay_to`, but leave the payment unconfirmed
+pub(crate) fn pay_to_mempool(
+    chain: &MockChain,
+    script_pubkey: ScriptBuf,
+    amount: Amount,
+) -> Transaction {
+    let wallet_utxo = chain.fund(address(1).script_pubkey(), amount + FEE);
+    let prevouts = [chain.get_utxo(&wallet_utxo).unwrap()];
+    let mut tx = Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: wallet_utxo,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: amount,
+            script_pubkey,
+        }],
+    };
+    sign_key_spend(&mut tx, 0, &prevouts, 1);
+
+    chain.broadcast(&tx).unwrap();
+    tx
+}
+
+/// pay the HTLC from a key path wallet output, like the initializer wallet would
+pub(crate) fn deposit(chain: &MockChain, htlc: &mut HTLC) {
+    let script_pubkey = htlc.address_with_fee(Network::Regtest).unwrap().script_pubkey();
+    let tx = pay_to(chain, script_pubkey, HTLC_AMOUNT);
+    htlc.set_funded_htlc(OutPoint::new(tx.txid(), 0), HTLC_AMOUNT);
+}
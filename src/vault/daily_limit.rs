@@ -0,0 +1,446 @@
+//! A vault releasing at most `daily_limit` sats per `period` blocks to its owner.
+//!
+//! The withdraw leaf is a CAT covenant like the HTLC leaves: the witness carries the sighash
+//! message in pieces, the script rebuilds it around the outputs it allows and checks it with the
+//! G signature trick. A withdrawal pays at most `daily_limit` to the owner and sends the rest
+//! back to the vault. The script can't contain its own address, so it takes the vault's script
+//! pubkey from the witness and pins it through sha_scriptpubkeys. Spending needs a relative lock
+//! of `period` blocks, so the change can only be withdrawn from again a period later.
+//!
+//! Amounts are script numbers, which limits a vault to less than 2^31 sats.
+
+use anyhow::{anyhow, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::key::Secp256k1;
+use bitcoin::opcodes::all::{
+    OP_2DUP, OP_ADD, OP_CAT, OP_DROP, OP_DUP, OP_ELSE, OP_ENDIF, OP_EQUAL, OP_EQUALVERIFY,
+    OP_GREATERTHAN, OP_GREATERTHANOREQUAL, OP_IF, OP_OVER, OP_PICK, OP_ROLL, OP_ROT, OP_SHA256,
+    OP_SIZE, OP_SWAP, OP_VERIFY, OP_WITHIN,
+};
+use bitcoin::script::{write_scriptint, Builder, PushBytesBuf};
+use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapLeafHash, TapSighashType,
+    Transaction, TxIn, TxOut, Witness,
+};
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::htlc::contract::serde_address;
+use crate::nums;
+use crate::htlc::scripts::CovenantBuilder;
+use crate::htlc::signature_building::{
+    self, compute_signature_from_components, get_sigmsg_components, TxCommitmentSpec,
+};
+
+/// amounts the script does arithmetic on are 4 byte script numbers
+pub(crate) const MAX_VAULT_AMOUNT: Amount = Amount::from_sat(i32::MAX as u64);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct VaultUtxo {
+    pub outpoint: OutPoint,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: Amount,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DailyLimitVault {
+    /// where withdrawals go
+    #[serde(with = "serde_address")]
+    pub owner_address: Address,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub daily_limit: Amount,
+    /// blocks between withdrawals
+    pub period: u16,
+    /// the most a withdrawal may leave to the miners, so nobody can burn the vault in fees
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub max_fee: Amount,
+    pub utxo: Option<VaultUtxo>,
+}
+
+impl DailyLimitVault {
+    pub(crate) fn new(
+        owner_address: Address,
+        daily_limit: Amount,
+        period: u16,
+        max_fee: Amount,
+    ) -> Result<Self> {
+        if daily_limit == Amount::ZERO || period == 0 {
+            return Err(anyhow!("the daily limit and the period must not be zero"));
+        }
+        if daily_limit + max_fee >= MAX_VAULT_AMOUNT {
+            return Err(anyhow!(
+                "the daily limit must be below {}",
+                MAX_VAULT_AMOUNT
+            ));
+        }
+        Ok(DailyLimitVault {
+            owner_address,
+            daily_limit,
+            period,
+            max_fee,
+            utxo: None,
+        })
+    }
+
+    pub(crate) fn from_file(path: &PathBuf) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub(crate) fn to_file(&self, path: &PathBuf) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// the witness, top first, is
+    /// `[b+1] [b] sig[0..63] remainder withdrawal amount vault_spk sigmsg[9..] sha_sequences sigmsg[..5]`
+    /// with the three amounts as script numbers
+    pub(crate) fn withdraw_script(&self) -> ScriptBuf {
+        let owner_txout_script = serialize(&self.owner_address.script_pubkey());
+        let mut builder = CovenantBuilder::new()
+            .csv_guard(self.period as i64)
+            .stash_signature()
+            .into_builder()
+            // 1 <= withdrawal <= daily_limit
+            .push_opcode(OP_OVER)
+            .push_int(1)
+            .push_int(self.daily_limit.to_sat() as i64 + 1)
+            .push_opcode(OP_WITHIN)
+            .push_opcode(OP_VERIFY)
+            // withdrawal + remainder + max_fee >= amount
+            .push_opcode(OP_2DUP)
+            .push_opcode(OP_ADD)
+            .push_int(self.max_fee.to_sat() as i64)
+            .push_opcode(OP_ADD)
+            .push_int(3)
+            .push_opcode(OP_PICK)
+            .push_opcode(OP_GREATERTHANOREQUAL)
+            .push_opcode(OP_VERIFY);
+        // sha_outputs: withdrawal to the owner, the remainder back to the vault
+        builder = push_u64_le(builder)
+            .push_int(3)
+            .push_opcode(OP_PICK)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP);
+        builder = push_u64_le(builder)
+            .push_slice(
+                PushBytesBuf::try_from(owner_txout_script).expect("script pubkeys are short"),
+            )
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SHA256)
+            .push_opcode(OP_SWAP);
+        // sha_amounts, then sha_scriptpubkeys from the vault script pubkey
+        builder = push_u64_le(builder)
+            .push_opcode(OP_SHA256)
+            .push_opcode(OP_ROT)
+            .push_opcode(OP_SHA256)
+            .push_opcode(OP_CAT)
+            // sigmsg[..5] || amounts || scriptpubkeys || sequences || outputs || sigmsg[9..]
+            .push_int(4)
+            .push_opcode(OP_ROLL)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT)
+            .push_int(3)
+            .push_opcode(OP_ROLL)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT);
+        CovenantBuilder::from(builder).cat_checksig().into_script()
+    }
+
+    pub(crate) fn taproot_spend_info(&self) -> Result<TaprootSpendInfo> {
+        let nums_key = nums::nums_point();
+        Ok(TaprootBuilder::new()
+            .add_leaf(0, self.withdraw_script())?
+            .finalize(&Secp256k1::new(), nums_key)
+            .expect("finalizing taproot spend info with a NUMS point should always work"))
+    }
+
+    pub(crate) fn script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(
+            self.taproot_spend_info()?.output_key(),
+        ))
+    }
+
+    pub(crate) fn address(&self, network: Network) -> Result<Address> {
+        Ok(Address::p2tr_tweaked(
+            self.taproot_spend_info()?.output_key(),
+            network,
+        ))
+    }
+
+    /// find the output of `tx` paying the vault, a deposit or the change of a withdrawal, and
+    /// track it as the vault's coin
+    pub(crate) fn record_funding(&mut self, tx: &Transaction) -> Result<OutPoint> {
+        let script_pubkey = self.script_pubkey()?;
+        let (vout, output) = tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == script_pubkey)
+            .ok_or(anyhow!("transaction {} does not pay the vault", tx.txid()))?;
+        if output.value > MAX_VAULT_AMOUNT {
+            return Err(anyhow!(
+                "the vault can't spend more than {}, {} is stuck",
+                MAX_VAULT_AMOUNT,
+                output.value
+            ));
+        }
+        let outpoint = OutPoint::new(tx.txid(), vout as u32);
+        self.utxo = Some(VaultUtxo {
+            outpoint,
+            amount: output.value,
+        });
+        Ok(outpoint)
+    }
+
+    /// withdraw `amount` to the owner, paying `fee` and returning the rest to the vault
+    pub(crate) fn create_withdrawal_tx(&self, amount: Amount, fee: Amount) -> Result<Transaction> {
+        let utxo = self.utxo.ok_or(anyhow!("the vault is not funded"))?;
+        if amount == Amount::ZERO || amount > self.daily_limit {
+            return Err(anyhow!(
+                "can withdraw up to {} per {} blocks, not {}",
+                self.daily_limit,
+                self.period,
+                amount
+            ));
+        }
+        if fee > self.max_fee {
+            return Err(anyhow!("the fee is capped at {}", self.max_fee));
+        }
+        let dust = self.script_pubkey()?.dust_value();
+        let remainder = utxo
+            .amount
+            .checked_sub(amount + fee)
+            .filter(|remainder| *remainder >= dust)
+            .ok_or(anyhow!(
+                "the vault holds {}, withdrawing {} would leave less than {} in it",
+                utxo.amount,
+                amount + fee,
+                dust
+            ))?;
+        self.build_withdrawal_tx(utxo, amount, remainder)
+    }
+
+    fn build_withdrawal_tx(
+        &self,
+        utxo: VaultUtxo,
+        amount: Amount,
+        remainder: Amount,
+    ) -> Result<Transaction> {
+        let spend_info = self.taproot_spend_info()?;
+        let withdraw_script = self.withdraw_script();
+        let leaf_hash = TapLeafHash::from_script(&withdraw_script, LeafVersion::TapScript);
+        let vault_script_pubkey = self.script_pubkey()?;
+        let prevout = TxOut {
+            script_pubkey: vault_script_pubkey.clone(),
+            value: utxo.amount,
+        };
+
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::from_height(self.period),
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    script_pubkey: self.owner_address.script_pubkey(),
+                    value: amount,
+                },
+                TxOut {
+                    script_pubkey: vault_script_pubkey.clone(),
+                    value: remainder,
+                },
+            ],
+        };
+        // the sequence carries the period, grind the lock time
+        let contract_components = signature_building::grind_transaction(
+            tx,
+            signature_building::GrindField::LockTime,
+            std::slice::from_ref(&prevout),
+            leaf_hash,
+            TapSighashType::Default,
+        )?;
+        let components = &contract_components.signature_components;
+        let mut tx = contract_components.transaction;
+        debug_assert_eq!(
+            components,
+            &get_sigmsg_components(
+                &TxCommitmentSpec::default(),
+                &tx,
+                0,
+                std::slice::from_ref(&prevout),
+                None,
+                leaf_hash,
+                TapSighashType::Default,
+            )?
+        );
+
+        let mut witness = Witness::new();
+        witness.push(components[..5].concat());
+        witness.push(&components[7]);
+        witness.push(components[9..].concat());
+        witness.push(serialize(&vault_script_pubkey));
+        for value in [utxo.amount, amount, remainder] {
+            witness.push(script_num(value));
+        }
+        let signature = compute_signature_from_components(components)?;
+        witness.push(&signature[0..63]);
+        witness.push([signature[63]]);
+        witness.push([signature[63] + 1]);
+        witness.push(withdraw_script.as_bytes());
+        let control_block = spend_info
+            .control_block(&(withdraw_script, LeafVersion::TapScript))
+            .expect("the withdraw leaf is in the tree");
+        witness.push(control_block.serialize());
+        tx.input[0].witness = witness;
+        Ok(tx)
+    }
+}
+
+/// replace the positive script number on top of the stack by its 8 byte little endian encoding
+fn push_u64_le(builder: Builder) -> Builder {
+    let zeros = |n: usize| PushBytesBuf::try_from(vec![0u8; n]).expect("short push");
+    builder
+        .push_opcode(OP_DUP)
+        .push_int(0)
+        .push_opcode(OP_GREATERTHAN)
+        .push_opcode(OP_VERIFY)
+        .push_opcode(OP_SIZE)
+        .push_opcode(OP_DUP)
+        .push_int(4)
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_DROP)
+        .push_slice(zeros(4))
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DUP)
+        .push_int(3)
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_DROP)
+        .push_slice(zeros(5))
+        .push_opcode(OP_ELSE)
+        .push_opcode(OP_DUP)
+        .push_int(2)
+        .push_opcode(OP_EQUAL)
+        .push_opcode(OP_IF)
+        .push_opcode(OP_DROP)
+        .push_slice(zeros(6))
+        .push_opcode(OP_ELSE)
+        .push_int(1)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_slice(zeros(7))
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_ENDIF)
+        .push_opcode(OP_CAT)
+}
+
+fn script_num(amount: Amount) -> Vec<u8> {
+    let mut buffer = [0u8; 8];
+    let len = write_scriptint(&mut buffer, amount.to_sat() as i64);
+    buffer[..len].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainBackend;
+    use crate::testing::{address, new_chain, pay_to, FEE};
+
+    const LIMIT: Amount = Amount::from_sat(1_000_000);
+    const PERIOD: u16 = 144;
+    const DEPOSIT: Amount = Amount::from_sat(10_000_000);
+
+    fn funded_vault(chain: &crate::chain::mock::MockChain) -> DailyLimitVault {
+        let mut vault = DailyLimitVault::new(address(2), LIMIT, PERIOD, FEE * 10).unwrap();
+        let tx = pay_to(chain, vault.script_pubkey().unwrap(), DEPOSIT);
+        vault.record_funding(&tx).unwrap();
+        vault
+    }
+
+    #[test]
+    fn withdrawals_are_limited_per_period() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+
+        let withdrawal = vault.create_withdrawal_tx(LIMIT, FEE).unwrap();
+        assert!(chain.broadcast(&withdrawal).is_err());
+        chain.mine_blocks(PERIOD as u64 - 1);
+        let txid = chain.broadcast(&withdrawal).unwrap();
+        chain.mine_blocks(1);
+        assert_eq!(
+            chain
+                .get_utxo(&OutPoint::new(txid, 0))
+                .unwrap()
+                .script_pubkey,
+            address(2).script_pubkey()
+        );
+
+        // the change is locked for another period
+        vault.record_funding(&withdrawal).unwrap();
+        assert_eq!(vault.utxo.unwrap().amount, DEPOSIT - LIMIT - FEE);
+        let next = vault.create_withdrawal_tx(LIMIT / 2, FEE).unwrap();
+        assert!(chain.broadcast(&next).is_err());
+        chain.mine_blocks(PERIOD as u64 - 1);
+        chain.broadcast(&next).unwrap();
+    }
+
+    #[test]
+    fn covenant_rejects_withdrawals_over_the_limit() {
+        let chain = new_chain();
+        let vault = funded_vault(&chain);
+        chain.mine_blocks(PERIOD as u64);
+
+        assert!(vault.create_withdrawal_tx(LIMIT + FEE, FEE).is_err());
+        let utxo = vault.utxo.unwrap();
+        let tx = vault
+            .build_withdrawal_tx(utxo, LIMIT + FEE, DEPOSIT - LIMIT - FEE * 2)
+            .unwrap();
+        let err = chain.broadcast(&tx).unwrap_err();
+        assert!(err.to_string().contains("OP_VERIFY"), "{}", err);
+    }
+
+    #[test]
+    fn covenant_rejects_excessive_fees() {
+        let chain = new_chain();
+        let vault = funded_vault(&chain);
+        chain.mine_blocks(PERIOD as u64);
+
+        let utxo = vault.utxo.unwrap();
+        let remainder = DEPOSIT - LIMIT - vault.max_fee - Amount::from_sat(1);
+        let tx = vault.build_withdrawal_tx(utxo, LIMIT, remainder).unwrap();
+        let err = chain.broadcast(&tx).unwrap_err();
+        assert!(err.to_string().contains("OP_VERIFY"), "{}", err);
+    }
+
+    #[test]
+    fn covenant_rejects_redirected_change() {
+        let chain = new_chain();
+        let vault = funded_vault(&chain);
+        chain.mine_blocks(PERIOD as u64);
+
+        let mut tx = vault.create_withdrawal_tx(LIMIT, FEE).unwrap();
+        tx.output[1].script_pubkey = address(4).script_pubkey();
+        let err = chain.broadcast(&tx).unwrap_err();
+        assert!(
+            err.to_string().contains("signature verification failed"),
+            "{}",
+            err
+        );
+    }
+}
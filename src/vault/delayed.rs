@@ -0,0 +1,660 @@
+//! A vault whose withdrawals wait `delay` blocks, during which they can be cancelled.
+//!
+//! Coins move through two covenant outputs. The vault output can be triggered, which moves the
+//! whole amount to the unvault output and announces the target in a dust output of the trigger
+//! transaction, or cancelled straight to the cold address. The unvault output completes to the
+//! announced target once `delay` blocks have passed, and can be cancelled to the cold address
+//! until then. Nobody holds a key: anyone can trigger, so a watchtower cancels every trigger it
+//! doesn't recognize.
+//!
+//! Every leaf signs with SIGHASH_SINGLE|ANYONECANPAY like the HTLC's plain leaves, the first
+//! output keeps the whole amount and fees come from an extra input added after grinding, see
+//! `add_fee_to_txn`. The complete leaf can't see the trigger transaction through the sighash, so
+//! it takes its serialization in pieces from the witness, hashes it into the txid of the outpoint
+//! it spends and rebuilds its own output from the target found there.
+
+use anyhow::{anyhow, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::key::Secp256k1;
+use bitcoin::opcodes::all::{
+    OP_CAT, OP_DROP, OP_EQUALVERIFY, OP_FROMALTSTACK, OP_HASH256, OP_PICK, OP_SHA256, OP_SIZE,
+    OP_SWAP, OP_TOALTSTACK,
+};
+use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Network, OutPoint, Script, ScriptBuf, Sequence, TapLeafHash, TapSighashType,
+    Transaction, TxIn, TxOut, Txid, VarInt, Witness,
+};
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::htlc::contract::serde_address;
+use crate::nums;
+use crate::htlc::scripts::{CovenantBuilder, SINGLE_ACP_SIGMSG_PREFIX};
+use crate::htlc::signature_building::{
+    self, compute_signature_from_components, get_sigmsg_components, GrindField, TxCommitmentSpec,
+};
+use crate::vault::daily_limit::VaultUtxo;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum VaultState {
+    /// nothing deposited yet
+    Empty,
+    Vaulted {
+        utxo: VaultUtxo,
+    },
+    /// a withdrawal is on its way, completing `delay` blocks after `trigger_tx` confirmed
+    Triggered {
+        trigger_tx: Transaction,
+    },
+    Completed {
+        txid: Txid,
+    },
+    Cancelled {
+        txid: Txid,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DelayedVault {
+    /// where a cancel sends the coins
+    #[serde(with = "serde_address")]
+    pub cold_address: Address,
+    /// blocks between a trigger and the withdrawal it announced
+    pub delay: u16,
+    pub state: VaultState,
+}
+
+impl DelayedVault {
+    pub(crate) fn new(cold_address: Address, delay: u16) -> Result<Self> {
+        if delay == 0 {
+            return Err(anyhow!("the delay must not be zero"));
+        }
+        Ok(DelayedVault {
+            cold_address,
+            delay,
+            state: VaultState::Empty,
+        })
+    }
+
+    pub(crate) fn from_file(path: &PathBuf) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub(crate) fn to_file(&self, path: &PathBuf) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// moves the vault output to the unvault output
+    pub(crate) fn trigger_script(&self) -> Result<ScriptBuf> {
+        Ok(pay_input_to(&self.unvault_script_pubkey()?))
+    }
+
+    /// moves the vault or the unvault output to the cold address
+    pub(crate) fn cancel_script(&self) -> ScriptBuf {
+        pay_input_to(&self.cold_address.script_pubkey())
+    }
+
+    /// the witness, top first, is
+    /// `[b+1] [b] sig[0..63] trigger_outputs[2..]||lock_time target_txout_script target_value
+    /// unvault_txout_script amount trigger_tx[..outputs] sequence leaf||keyver||codesep`,
+    /// the pieces between the signature and the sequence serializing the trigger transaction
+    pub(crate) fn complete_script(&self) -> ScriptBuf {
+        let builder = CovenantBuilder::new()
+            .csv_guard(self.delay as i64)
+            .stash_signature()
+            .into_builder()
+            // the target value is 8 bytes, a longer one would swallow the announcement and let
+            // the target script point at a later output of the trigger
+            .push_int(2)
+            .push_opcode(OP_PICK)
+            .push_opcode(OP_SIZE)
+            .push_int(8)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_DROP)
+            // sha_single_output: the whole amount to the target
+            .push_int(4)
+            .push_opcode(OP_PICK)
+            .push_int(2)
+            .push_opcode(OP_PICK)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SHA256)
+            .push_opcode(OP_TOALTSTACK)
+            // the spent output, amount and script pubkey
+            .push_int(4)
+            .push_opcode(OP_PICK)
+            .push_int(4)
+            .push_opcode(OP_PICK)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_TOALTSTACK)
+            // the trigger txid and the unvault output in it
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_HASH256)
+            .push_slice([0u8; 4])
+            .push_opcode(OP_CAT)
+            // outpoint || amount || script pubkey || sequence || sha_single_output
+            .push_opcode(OP_FROMALTSTACK)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_FROMALTSTACK)
+            .push_opcode(OP_CAT)
+            .push_slice(SINGLE_ACP_SIGMSG_PREFIX)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_CAT);
+        CovenantBuilder::from(builder).cat_checksig().into_script()
+    }
+
+    /// the output coins are deposited to, with a trigger and a cancel leaf
+    pub(crate) fn vault_spend_info(&self) -> Result<TaprootSpendInfo> {
+        spend_info(self.trigger_script()?, self.cancel_script())
+    }
+
+    /// the output a trigger moves the coins to, with a complete and a cancel leaf
+    pub(crate) fn unvault_spend_info(&self) -> Result<TaprootSpendInfo> {
+        spend_info(self.complete_script(), self.cancel_script())
+    }
+
+    pub(crate) fn vault_script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(
+            self.vault_spend_info()?.output_key(),
+        ))
+    }
+
+    pub(crate) fn unvault_script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr_tweaked(
+            self.unvault_spend_info()?.output_key(),
+        ))
+    }
+
+    /// the deposit address
+    pub(crate) fn address(&self, network: Network) -> Result<Address> {
+        Ok(Address::p2tr_tweaked(
+            self.vault_spend_info()?.output_key(),
+            network,
+        ))
+    }
+
+    /// find the output of `tx` paying the vault and track it as the vault's coin
+    pub(crate) fn record_deposit(&mut self, tx: &Transaction) -> Result<OutPoint> {
+        let script_pubkey = self.vault_script_pubkey()?;
+        let (vout, output) = tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == script_pubkey)
+            .ok_or(anyhow!("transaction {} does not pay the vault", tx.txid()))?;
+        let outpoint = OutPoint::new(tx.txid(), vout as u32);
+        self.state = VaultState::Vaulted {
+            utxo: VaultUtxo {
+                outpoint,
+                amount: output.value,
+            },
+        };
+        Ok(outpoint)
+    }
+
+    /// move the vault to the unvault output, announcing `target`. The transaction still needs a
+    /// fee input covering the fee and the dust value of the announcement output
+    pub(crate) fn create_trigger_tx(&self, target: &Address) -> Result<Transaction> {
+        let utxo = match &self.state {
+            VaultState::Vaulted { utxo } => *utxo,
+            state => return Err(anyhow!("can only trigger a funded vault, not {:?}", state)),
+        };
+        let target_script_pubkey = target.script_pubkey();
+        let announcement = TxOut {
+            value: target_script_pubkey.dust_value(),
+            script_pubkey: target_script_pubkey,
+        };
+        let tx = self.build_tx(
+            utxo,
+            &self.vault_spend_info()?,
+            self.trigger_script()?,
+            self.unvault_script_pubkey()?,
+            Some(announcement),
+        )?;
+        Ok(tx)
+    }
+
+    /// track the unvault output of a broadcast trigger transaction
+    pub(crate) fn record_trigger(&mut self, trigger_tx: &Transaction) -> Result<()> {
+        if trigger_tx.output.len() < 2
+            || trigger_tx.output[0].script_pubkey != self.unvault_script_pubkey()?
+        {
+            return Err(anyhow!(
+                "transaction {} does not trigger the vault",
+                trigger_tx.txid()
+            ));
+        }
+        self.state = VaultState::Triggered {
+            trigger_tx: trigger_tx.clone(),
+        };
+        Ok(())
+    }
+
+    /// where a triggered withdrawal goes
+    pub(crate) fn target(&self) -> Option<&Script> {
+        match &self.state {
+            VaultState::Triggered { trigger_tx } => Some(&trigger_tx.output[1].script_pubkey),
+            _ => None,
+        }
+    }
+
+    /// send the coins back to the cold address, from the vault or the unvault output. The
+    /// transaction still needs a fee input
+    pub(crate) fn create_cancel_tx(&self) -> Result<Transaction> {
+        let (utxo, spend_info) = match &self.state {
+            VaultState::Vaulted { utxo } => (*utxo, self.vault_spend_info()?),
+            VaultState::Triggered { trigger_tx } => {
+                (unvault_utxo(trigger_tx), self.unvault_spend_info()?)
+            }
+            state => return Err(anyhow!("nothing to cancel in {:?}", state)),
+        };
+        self.build_tx(
+            utxo,
+            &spend_info,
+            self.cancel_script(),
+            self.cold_address.script_pubkey(),
+            None,
+        )
+    }
+
+    /// pay a triggered withdrawal to its target. Valid `delay` blocks after the trigger
+    /// confirmed, and still needs a fee input
+    pub(crate) fn create_complete_tx(&self) -> Result<Transaction> {
+        let trigger_tx = match &self.state {
+            VaultState::Triggered { trigger_tx } => trigger_tx,
+            state => return Err(anyhow!("no withdrawal to complete in {:?}", state)),
+        };
+        let utxo = unvault_utxo(trigger_tx);
+        let complete_script = self.complete_script();
+        let leaf_hash = TapLeafHash::from_script(&complete_script, LeafVersion::TapScript);
+        let prevout = TxOut {
+            script_pubkey: self.unvault_script_pubkey()?,
+            value: utxo.amount,
+        };
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::from_height(self.delay),
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: trigger_tx.output[1].script_pubkey.clone(),
+                value: utxo.amount,
+            }],
+        };
+        // the CSV check needs the delay, so grind the relative lock upwards from it
+        let contract_components = signature_building::grind_transaction(
+            tx,
+            GrindField::RelativeHeight,
+            std::slice::from_ref(&prevout),
+            leaf_hash,
+            TapSighashType::SinglePlusAnyoneCanPay,
+        )?;
+        let components = &contract_components.signature_components;
+        let mut tx = contract_components.transaction;
+
+        // the trigger transaction without its witness, split around its first two outputs
+        let mut prefix = serialize(&trigger_tx.version);
+        prefix.extend(serialize(&trigger_tx.input));
+        prefix.extend(serialize(&VarInt(trigger_tx.output.len() as u64)));
+        let mut rest: Vec<u8> = trigger_tx.output[2..].iter().flat_map(serialize).collect();
+        rest.extend(serialize(&trigger_tx.lock_time));
+
+        let mut witness = Witness::new();
+        witness.push(components[10..13].concat());
+        witness.push(&components[8]);
+        witness.push(&prefix);
+        witness.push(serialize(&utxo.amount));
+        witness.push(serialize(&prevout.script_pubkey));
+        witness.push(serialize(&trigger_tx.output[1].value));
+        witness.push(serialize(&trigger_tx.output[1].script_pubkey));
+        witness.push(rest);
+        push_signature(&mut witness, components)?;
+        witness.push(complete_script.as_bytes());
+        let control_block = self
+            .unvault_spend_info()?
+            .control_block(&(complete_script, LeafVersion::TapScript))
+            .expect("the complete leaf is in the tree");
+        witness.push(control_block.serialize());
+        tx.input[0].witness = witness;
+        Ok(tx)
+    }
+
+    /// track the end of the vault once a cancel or complete transaction is broadcast
+    pub(crate) fn record_spend(&mut self, tx: &Transaction) -> Result<()> {
+        self.state = if tx.output[0].script_pubkey == self.cold_address.script_pubkey() {
+            VaultState::Cancelled { txid: tx.txid() }
+        } else if Some(tx.output[0].script_pubkey.as_script()) == self.target() {
+            VaultState::Completed { txid: tx.txid() }
+        } else {
+            return Err(anyhow!(
+                "transaction {} does not spend the vault",
+                tx.txid()
+            ));
+        };
+        Ok(())
+    }
+
+    /// spend `utxo` with a leaf of `pay_input_to`, the whole amount going to `script_pubkey`
+    fn build_tx(
+        &self,
+        utxo: VaultUtxo,
+        spend_info: &TaprootSpendInfo,
+        script: ScriptBuf,
+        script_pubkey: ScriptBuf,
+        extra_output: Option<TxOut>,
+    ) -> Result<Transaction> {
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let prevout = TxOut {
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+            value: utxo.amount,
+        };
+        let mut output = vec![TxOut {
+            script_pubkey,
+            value: utxo.amount,
+        }];
+        output.extend(extra_output);
+        let tx = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output,
+        };
+        let contract_components = signature_building::grind_transaction(
+            tx,
+            GrindField::Sequence,
+            std::slice::from_ref(&prevout),
+            leaf_hash,
+            TapSighashType::SinglePlusAnyoneCanPay,
+        )?;
+        let components = &contract_components.signature_components;
+        let mut tx = contract_components.transaction;
+        debug_assert_eq!(
+            components,
+            &get_sigmsg_components(
+                &TxCommitmentSpec::default(),
+                &tx,
+                0,
+                std::slice::from_ref(&prevout),
+                None,
+                leaf_hash,
+                TapSighashType::SinglePlusAnyoneCanPay,
+            )?
+        );
+
+        let mut witness = Witness::new();
+        witness.push(components[10..13].concat());
+        witness.push(components[7..9].concat());
+        witness.push(&components[6]);
+        witness.push(&components[5]);
+        push_signature(&mut witness, components)?;
+        witness.push(script.as_bytes());
+        let control_block = spend_info
+            .control_block(&(script, LeafVersion::TapScript))
+            .expect("the leaf is in the tree");
+        witness.push(control_block.serialize());
+        tx.input[0].witness = witness;
+        Ok(tx)
+    }
+}
+
+/// the unvault output is the first output of the trigger transaction
+fn unvault_utxo(trigger_tx: &Transaction) -> VaultUtxo {
+    VaultUtxo {
+        outpoint: OutPoint::new(trigger_tx.txid(), 0),
+        amount: trigger_tx.output[0].value,
+    }
+}
+
+/// a leaf paying the whole input to `script_pubkey`, the witness, top first, being
+/// `[b+1] [b] sig[0..63] prevout amount script_pubkey||sequence leaf||keyver||codesep`
+fn pay_input_to(script_pubkey: &ScriptBuf) -> ScriptBuf {
+    CovenantBuilder::new()
+        .stash_signature()
+        .single_acp_sigmsg(script_pubkey)
+        .cat_checksig()
+        .into_script()
+}
+
+/// the G signature in the three pieces the covenant leaves expect
+fn push_signature(witness: &mut Witness, components: &[Vec<u8>]) -> Result<()> {
+    let signature = compute_signature_from_components(components)?;
+    witness.push(&signature[0..63]);
+    witness.push([signature[63]]);
+    witness.push([
+        signature[63] + 1,
+        TapSighashType::SinglePlusAnyoneCanPay as u8,
+    ]);
+    Ok(())
+}
+
+fn spend_info(first: ScriptBuf, second: ScriptBuf) -> Result<TaprootSpendInfo> {
+    let nums_key = nums::nums_point();
+    Ok(TaprootBuilder::new()
+        .add_leaf(1, first)?
+        .add_leaf(1, second)?
+        .finalize(&Secp256k1::new(), nums_key)
+        .expect("finalizing taproot spend info with a NUMS point should always work"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::mock::MockChain;
+    use crate::chain::ChainBackend;
+    use crate::htlc::contract::add_fee_to_txn;
+    use crate::testing::{address, new_chain, pay_to, sign_key_spend, FEE};
+    use bitcoin::Amount;
+
+    const DELAY: u16 = 10;
+    const DEPOSIT: Amount = Amount::from_sat(10_000_000);
+
+    fn funded_vault(chain: &MockChain) -> DelayedVault {
+        let mut vault = DelayedVault::new(address(3), DELAY).unwrap();
+        let tx = pay_to(chain, vault.vault_script_pubkey().unwrap(), DEPOSIT);
+        vault.record_deposit(&tx).unwrap();
+        vault
+    }
+
+    /// pay `fee` plus whatever the covenant outputs don't take from a key path wallet output
+    fn with_fee(chain: &MockChain, mut tx: Transaction, fee: Amount) -> Transaction {
+        let covenant_prevout = chain.get_utxo(&tx.input[0].previous_output).unwrap();
+        let fee_outpoint = chain.fund(address(1).script_pubkey(), Amount::from_sat(100_000));
+        let fee_prevout = chain.get_utxo(&fee_outpoint).unwrap();
+        add_fee_to_txn(&mut tx, fee_outpoint, fee_prevout.value, fee, address(1)).unwrap();
+        sign_key_spend(&mut tx, 1, &[covenant_prevout, fee_prevout], 1);
+        tx
+    }
+
+    fn trigger(chain: &MockChain, vault: &mut DelayedVault, target: &Address) -> Transaction {
+        let tx = vault.create_trigger_tx(target).unwrap();
+        let tx = with_fee(chain, tx.clone(), FEE + tx.output[1].value);
+        chain.broadcast(&tx).unwrap();
+        chain.mine_blocks(1);
+        vault.record_trigger(&tx).unwrap();
+        tx
+    }
+
+    #[test]
+    fn withdrawals_complete_after_the_delay() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+        trigger(&chain, &mut vault, &address(2));
+
+        let complete = with_fee(&chain, vault.create_complete_tx().unwrap(), FEE);
+        assert!(chain.broadcast(&complete).is_err());
+        chain.mine_blocks(DELAY as u64 - 1);
+        let txid = chain.broadcast(&complete).unwrap();
+        vault.record_spend(&complete).unwrap();
+        assert!(matches!(vault.state, VaultState::Completed { .. }));
+        let paid = chain.get_utxo(&OutPoint::new(txid, 0)).unwrap();
+        assert_eq!(paid.script_pubkey, address(2).script_pubkey());
+        assert_eq!(paid.value, DEPOSIT);
+    }
+
+    #[test]
+    fn vaulted_coins_cancel_to_the_cold_address() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+
+        let cancel = with_fee(&chain, vault.create_cancel_tx().unwrap(), FEE);
+        let txid = chain.broadcast(&cancel).unwrap();
+        vault.record_spend(&cancel).unwrap();
+        assert!(matches!(vault.state, VaultState::Cancelled { .. }));
+        assert_eq!(
+            chain
+                .get_utxo(&OutPoint::new(txid, 0))
+                .unwrap()
+                .script_pubkey,
+            address(3).script_pubkey()
+        );
+    }
+
+    #[test]
+    fn triggered_withdrawals_cancel_before_the_delay() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+        // a thief triggers a withdrawal to their own address
+        trigger(&chain, &mut vault, &address(4));
+
+        let cancel = with_fee(&chain, vault.create_cancel_tx().unwrap(), FEE);
+        let txid = chain.broadcast(&cancel).unwrap();
+        assert_eq!(
+            chain.get_utxo(&OutPoint::new(txid, 0)).unwrap().value,
+            DEPOSIT
+        );
+    }
+
+    #[test]
+    fn covenant_rejects_redirected_withdrawals() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+        trigger(&chain, &mut vault, &address(2));
+        chain.mine_blocks(DELAY as u64);
+
+        let mut complete = vault.create_complete_tx().unwrap();
+        complete.output[0].script_pubkey = address(4).script_pubkey();
+        let err = chain
+            .broadcast(&with_fee(&chain, complete, FEE))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("signature verification failed"),
+            "{}",
+            err
+        );
+
+        // nor can a trigger skip the unvault output
+        let vault = funded_vault(&chain);
+        let mut trigger = vault.create_trigger_tx(&address(2)).unwrap();
+        trigger.output[0].script_pubkey = address(4).script_pubkey();
+        let err = chain
+            .broadcast(&with_fee(&chain, trigger, FEE + Amount::from_sat(330)))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("signature verification failed"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn complete_pays_only_the_announced_target() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+        let trigger_tx = trigger(&chain, &mut vault, &address(2));
+        chain.mine_blocks(DELAY as u64);
+
+        // a forged announcement doesn't hash to the trigger txid
+        let mut forged = trigger_tx.clone();
+        forged.output[1].script_pubkey = address(4).script_pubkey();
+        vault.state = VaultState::Triggered { trigger_tx: forged };
+        let mut complete = vault.create_complete_tx().unwrap();
+        complete.input[0].previous_output = OutPoint::new(trigger_tx.txid(), 0);
+        let err = chain
+            .broadcast(&with_fee(&chain, complete, FEE))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("signature verification failed"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn complete_rejects_a_resplit_announcement() {
+        let chain = new_chain();
+        let mut vault = funded_vault(&chain);
+        // the fee input's change is a third output of the trigger
+        let trigger_tx = trigger(&chain, &mut vault, &address(2));
+        assert_eq!(trigger_tx.output[2].script_pubkey, address(1).script_pubkey());
+        chain.mine_blocks(DELAY as u64);
+
+        // fold the announcement into the target value so the target script lands on output 2,
+        // the pieces still hash to the trigger txid
+        let mut complete = vault.create_complete_tx().unwrap();
+        complete.output[0].script_pubkey = trigger_tx.output[2].script_pubkey.clone();
+        complete.input[0].sequence = Sequence::from_height(DELAY);
+        complete.input[0].witness = Witness::new();
+        let complete_script = vault.complete_script();
+        let leaf_hash = TapLeafHash::from_script(&complete_script, LeafVersion::TapScript);
+        let prevout = chain.get_utxo(&complete.input[0].previous_output).unwrap();
+        let contract_components = signature_building::grind_transaction(
+            complete,
+            GrindField::RelativeHeight,
+            std::slice::from_ref(&prevout),
+            leaf_hash,
+            TapSighashType::SinglePlusAnyoneCanPay,
+        )
+        .unwrap();
+        let components = &contract_components.signature_components;
+        let mut complete = contract_components.transaction;
+
+        let honest = vault.create_complete_tx().unwrap().input[0].witness.to_vec();
+        let mut target_value = serialize(&trigger_tx.output[1]);
+        target_value.extend(serialize(&trigger_tx.output[2].value));
+        let mut rest: Vec<u8> = trigger_tx.output[3..].iter().flat_map(serialize).collect();
+        rest.extend(serialize(&trigger_tx.lock_time));
+        let mut witness = Witness::new();
+        witness.push(components[10..13].concat());
+        witness.push(&components[8]);
+        for piece in &honest[2..5] {
+            witness.push(piece);
+        }
+        witness.push(target_value);
+        witness.push(serialize(&trigger_tx.output[2].script_pubkey));
+        witness.push(rest);
+        push_signature(&mut witness, components).unwrap();
+        for piece in &honest[honest.len() - 2..] {
+            witness.push(piece);
+        }
+        complete.input[0].witness = witness;
+
+        let err = chain
+            .broadcast(&with_fee(&chain, complete, FEE))
+            .unwrap_err();
+        assert!(err.to_string().contains("OP_EQUALVERIFY failed"), "{}", err);
+    }
+}
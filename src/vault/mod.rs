@@ -0,0 +1,2 @@
+pub(crate) mod delayed;
+pub(crate) mod daily_limit;
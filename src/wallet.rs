@@ -1,99 +1,141 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
 use anyhow::{anyhow, Result};
-use bitcoin::{Address, Amount, Network, OutPoint, Transaction, Txid};
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, Network, OutPoint, Transaction, TxOut, Txid};
 use bitcoincore_rpc::jsonrpc::serde_json::{json, Value};
-use bitcoincore_rpc::{Auth, Client, RawTx, RpcApi};
+use bitcoincore_rpc::json::SignRawTransactionInput;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
 use log::{debug, info};
 use serde::Deserialize;
 
+use crate::chain::{self, ChainBackend};
 use crate::settings::Settings;
 
+#[derive(Debug)]
+pub(crate) enum WalletError {
+    /// the RPC call to bitcoind failed
+    Rpc(bitcoincore_rpc::Error),
+    /// there is no default RPC port for the network and none was configured
+    UnsupportedNetwork(Network),
+    /// the configured cookie file does not exist
+    CookieFileNotFound(PathBuf),
+    /// the wallet does not exist and `create_wallets` is disabled
+    WalletNotFound(String),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::Rpc(e) => write!(f, "bitcoind rpc error: {}", e),
+            WalletError::UnsupportedNetwork(network) => {
+                write!(f, "no default rpc port for network {}, set rpc_port", network)
+            }
+            WalletError::CookieFileNotFound(path) => {
+                write!(f, "rpc cookie file {} does not exist", path.display())
+            }
+            WalletError::WalletNotFound(name) => write!(
+                f,
+                "wallet {} does not exist and the tool is configured to not create new wallets",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WalletError::Rpc(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for WalletError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        WalletError::Rpc(e)
+    }
+}
+
+/// a bitcoind wallet for keys and coin selection, next to the configured chain backend for
+/// everything that isn't wallet specific
 pub(crate) struct Wallet {
     client: Client,
+    chain: Box<dyn ChainBackend>,
     network: Network,
 }
 
 impl Wallet {
-    pub(crate) fn new(name: &str, settings: &Settings) -> Self {
+    pub(crate) fn new(name: &str, settings: &Settings) -> Result<Self, WalletError> {
         let name = name.to_string();
 
-        let client = Self::create_rpc_client(settings, None);
-        if client
-            .list_wallet_dir()
-            .expect("Could not list wallet dir")
-            .contains(&name)
-        {
-            if !client
-                .list_wallets()
-                .expect("Could not list wallets")
-                .contains(&name)
-            {
+        let client = Self::create_rpc_client(settings, None)?;
+        if client.list_wallet_dir()?.contains(&name) {
+            if !client.list_wallets()?.contains(&name) {
                 info!("loading wallet {}", name);
-                client.load_wallet(&name).unwrap();
+                client.load_wallet(&name)?;
             } else {
                 info!("wallet {} already loaded", name);
             }
         } else {
             if !settings.create_wallets {
-                panic!(
-                    "wallet {} does not exist and the tool is configured to not create new wallets",
-                    name
-                );
+                return Err(WalletError::WalletNotFound(name));
             }
             info!("creating wallet {}", name);
-            client
-                .create_wallet(&name, None, None, None, None)
-                .expect("Could not create wallet");
+            client.create_wallet(&name, None, None, None, None)?;
         }
 
-        Wallet {
-            client: Self::create_rpc_client(settings, Some(&name)),
+        Ok(Wallet {
+            client: Self::create_rpc_client(settings, Some(&name))?,
+            chain: chain::backend_from_settings(settings)?,
             network: settings.network,
-        }
+        })
     }
 
-    pub(crate) fn create_rpc_client(settings: &Settings, wallet_name: Option<&str>) -> Client {
-        let port = match settings.network {
-            Network::Bitcoin => 8332,
-            Network::Testnet => 18332,
-            Network::Regtest => 18443,
-            Network::Signet => 38332,
-            _ => {
-                unreachable!("unsupported network")
-            }
+    pub(crate) fn create_rpc_client(
+        settings: &Settings,
+        wallet_name: Option<&str>,
+    ) -> Result<Client, WalletError> {
+        let port = match (settings.rpc_port, settings.network) {
+            (Some(port), _) => port,
+            (None, Network::Bitcoin) => 8332,
+            (None, Network::Testnet) => 18332,
+            (None, Network::Regtest) => 18443,
+            (None, Network::Signet) => 38332,
+            (None, network) => return Err(WalletError::UnsupportedNetwork(network)),
         };
-        // TODO: allow for other authentication
-        let auth = Auth::UserPass(
-            settings.bitcoin_rpc_username.clone(),
-            settings.bitcoin_rpc_password.clone(),
-        );
 
-        //let auth = bitcoincore_rpc::Auth::CookieFile("/Users/alex/Library/Application Support/Bitcoin/regtest/.cookie".to_string().parse().unwrap());
+        // an explicit cookie file wins, then a cookie found in the datadir, then user/pass
+        let auth = match (&settings.rpc_cookie_file, settings.datadir_cookie_file()) {
+            (Some(cookie), _) if !cookie.exists() => {
+                return Err(WalletError::CookieFileNotFound(cookie.clone()))
+            }
+            (Some(cookie), _) => Auth::CookieFile(cookie.clone()),
+            (None, Some(cookie)) if cookie.exists() => {
+                debug!("using rpc cookie file {}", cookie.display());
+                Auth::CookieFile(cookie)
+            }
+            _ => Auth::UserPass(
+                settings.bitcoin_rpc_username.clone(),
+                settings.bitcoin_rpc_password.clone(),
+            ),
+        };
 
+        let host = &settings.rpc_host;
         let url = match wallet_name {
-            None => format!("http://127.0.0.1:{port}"),
-            Some(name) => format!("http://127.0.0.1:{}/wallet/{name}", port),
+            None => format!("http://{host}:{port}"),
+            Some(name) => format!("http://{host}:{port}/wallet/{name}"),
         };
 
-        Client::new(&url, auth.clone()).unwrap()
+        Ok(Client::new(&url, auth)?)
     }
 
-    /// broadcast a raw bitcoin transaction (needs to already be network serialized)
-    /// optionally specify a max fee rate in sat/vB. This function will automatically convert it to BTC/kB that bitcoin core expects
-    /// returns the txid of the broadcast transaction
-    pub(crate) fn broadcast_tx(&self, tx: &Vec<u8>, max_fee_rate: Option<u64>) -> Result<Txid> {
-        // convert fee rate from sat/vb to btc/kb
-        let max_fee_rate = match max_fee_rate {
-            Some(fee_rate) => {
-                let fee_rate = fee_rate as f64 / 100_000_000.0 * 1000.0;
-                format!("{:.8}", fee_rate).parse::<f64>().unwrap()
-            }
-            None => 0.1, // the default fee rate is 0.1 BTC/kB
-        };
-        let txid = self.client.call(
-            "sendrawtransaction",
-            &[json!(tx.raw_hex()), json!(max_fee_rate)],
-        )?;
-        Ok(txid)
+    /// the chain backend configured next to this wallet, bitcoind or esplora
+    pub(crate) fn chain(&self) -> &dyn ChainBackend {
+        self.chain.as_ref()
     }
 
     pub(crate) fn get_new_address(&self) -> Result<Address> {
@@ -116,30 +158,87 @@ impl Wallet {
         Ok(balance)
     }
 
+    /// a confirmed, spendable coin of this wallet worth at least `amount`
+    pub(crate) fn find_utxo(&self, amount: Amount) -> Result<Option<(OutPoint, TxOut)>> {
+        let unspent = self.client.list_unspent(Some(1), None, None, Some(false), None)?;
+        Ok(unspent
+            .into_iter()
+            .find(|entry| entry.spendable && entry.amount >= amount)
+            .map(|entry| {
+                (
+                    OutPoint::new(entry.txid, entry.vout),
+                    TxOut {
+                        value: entry.amount,
+                        script_pubkey: entry.script_pub_key,
+                    },
+                )
+            }))
+    }
+
     pub(crate) fn send(&self, address: &Address, amount: Amount) -> Result<OutPoint> {
-        let output = json!([{
-            address.to_string(): amount.to_float_in(bitcoin::Denomination::Bitcoin)
-        }]);
-        let send_result: SendResult = self
-            .client
-            .call("send", &[output, Value::Null, "unset".into(), 1.into()])?;
-        let txid = send_result.txid;
+        let outpoints = self.send_many(&[(address.clone(), amount)])?;
+        Ok(outpoints[0])
+    }
 
+    /// pay several addresses (e.g. a batch of HTLCs) in a single transaction.
+    /// returns the outpoint created for each payment, in the order of `payments`
+    pub(crate) fn send_many(&self, payments: &[(Address, Amount)]) -> Result<Vec<OutPoint>> {
+        let outputs: Vec<Value> = payments
+            .iter()
+            .map(|(address, amount)| {
+                json!({ address.to_string(): amount.to_float_in(bitcoin::Denomination::Bitcoin) })
+            })
+            .collect();
+        let send_result: SendResult = self.client.call(
+            "send",
+            &[json!(outputs), Value::Null, "unset".into(), 1.into()],
+        )?;
+        let txid = send_result.txid;
         debug!("sent txid: {}", txid);
-        let transaction_info = self.client.get_transaction(&txid, None)?;
-        let mut target_vout = 0;
-        for (_, details) in transaction_info.details.iter().enumerate() {
-            if &details.address.clone().unwrap().assume_checked() == address {
-                target_vout = details.vout;
-                break;
-            }
+
+        // locate the outputs in the decoded transaction, the wallet details only cover our own addresses
+        let tx = self.client.get_transaction(&txid, None)?.transaction()?;
+        let mut claimed = vec![false; tx.output.len()];
+        payments
+            .iter()
+            .map(|(address, amount)| {
+                let script_pubkey = address.script_pubkey();
+                let vout = tx
+                    .output
+                    .iter()
+                    .enumerate()
+                    .position(|(vout, output)| {
+                        !claimed[vout]
+                            && output.script_pubkey == script_pubkey
+                            && output.value == *amount
+                    })
+                    .ok_or(anyhow!(
+                        "transaction {} has no output paying {} to {}",
+                        txid,
+                        amount,
+                        address
+                    ))?;
+                claimed[vout] = true;
+                Ok(OutPoint {
+                    txid,
+                    vout: vout as u32,
+                })
+            })
+            .collect()
+    }
+
+    /// sign the inputs of `psbt` this wallet owns and finalize them (`walletprocesspsbt`)
+    pub(crate) fn process_psbt(&self, psbt: &Psbt) -> Result<Psbt> {
+        let result: ProcessedPsbtResult = self
+            .client
+            .call("walletprocesspsbt", &[json!(psbt.to_string())])?;
+        if !result.complete {
+            debug!("wallet could not sign every input");
         }
-        Ok(OutPoint {
-            txid,
-            vout: target_vout,
-        })
+        Ok(Psbt::from_str(&result.psbt)?)
     }
 
+    #[allow(dead_code)]
     pub(crate) fn sign_tx(&self, tx: &Transaction) -> Result<Transaction> {
         let signed = self
             .client
@@ -148,9 +247,83 @@ impl Wallet {
             .transaction()
             .map_err(|e| anyhow!("signing failed: {}", e))
     }
+
+    /// ask the node whether it would accept `tx` into its mempool, without broadcasting it
+    pub(crate) fn test_accept(&self, tx: &Transaction) -> Result<AcceptResult> {
+        let result = self
+            .client
+            .test_mempool_accept(&[tx])?
+            .pop()
+            .ok_or(anyhow!("testmempoolaccept returned no result"))?;
+        Ok(AcceptResult {
+            txid: result.txid,
+            allowed: result.allowed,
+            reject_reason: result.reject_reason,
+            vsize: result.vsize,
+            fee: result.fees.map(|fees| fees.base),
+        })
+    }
+
+    /// sign the inputs of `tx` this wallet owns, next to inputs it doesn't know like a covenant
+    /// spend. Taproot sighashes commit to every spent output, so those are passed in
+    pub(crate) fn sign_tx_with_prevouts(
+        &self,
+        tx: &Transaction,
+        prevouts: &[(OutPoint, TxOut)],
+    ) -> Result<Transaction> {
+        let prevouts: Vec<SignRawTransactionInput> = prevouts
+            .iter()
+            .map(|(outpoint, txout)| SignRawTransactionInput {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                script_pub_key: txout.script_pubkey.clone(),
+                redeem_script: None,
+                amount: Some(txout.value),
+            })
+            .collect();
+        let signed = self
+            .client
+            .sign_raw_transaction_with_wallet(tx, Some(&prevouts), None)?;
+        signed
+            .transaction()
+            .map_err(|e| anyhow!("signing failed: {}", e))
+    }
+}
+
+/// the verdict of `testmempoolaccept` on a transaction
+#[derive(Debug, Clone)]
+pub(crate) struct AcceptResult {
+    pub txid: Txid,
+    pub allowed: bool,
+    pub reject_reason: Option<String>,
+    /// only known for accepted transactions
+    pub vsize: Option<u64>,
+    /// only known for accepted transactions
+    pub fee: Option<Amount>,
+}
+
+impl fmt::Display for AcceptResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.reject_reason, self.vsize, self.fee) {
+            (_, Some(vsize), Some(fee)) if self.allowed => {
+                write!(f, "{} would be accepted, {} vB paying {}", self.txid, vsize, fee)
+            }
+            (Some(reason), _, _) if !self.allowed => {
+                write!(f, "{} would be rejected: {}", self.txid, reason)
+            }
+            _ if self.allowed => write!(f, "{} would be accepted", self.txid),
+            _ => write!(f, "{} would be rejected", self.txid),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct SendResult {
     txid: Txid,
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+struct ProcessedPsbtResult {
+    psbt: String,
+    complete: bool,
+}